@@ -0,0 +1,103 @@
+//! Reads and writes of the shared state done by the keyboard hook on every
+//! key press, alone and while other threads change the state, and the
+//! decision the hook makes per event with the system calls left out
+
+use capswitch::{decide, AppState, HookKey};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use windows::Win32::UI::{
+    Input::KeyboardAndMouse::{VK_A, VK_CAPITAL},
+    TextServices::HKL,
+};
+
+const INSTALLED: [HKL; 4] = [
+    HKL(0x0409_0409),
+    HKL(0x0419_0419),
+    HKL(0x0407_0407),
+    HKL(0x040C_040C),
+];
+
+fn app_state() -> AppState {
+    AppState::new(vec![String::from("CapsWitch")])
+}
+
+fn hook_reads(state: &AppState) {
+    black_box(state.is_paused());
+    black_box(state.mode());
+    black_box(state.excluded_layouts());
+    black_box(state.prev_layout());
+    black_box(state.hold_origin());
+}
+
+/// Typing and a CapsLock press, as the hook receives them
+fn key_events() -> Vec<HookKey> {
+    [VK_A, VK_A, VK_CAPITAL, VK_CAPITAL]
+        .iter()
+        .zip([true, false, true, false])
+        .map(|(vk, is_down)| HookKey {
+            vk_code: u32::from(vk.0),
+            is_down,
+            extra_info: 0,
+        })
+        .collect()
+}
+
+/// Decides on every event, with a pause hotkey that is never held
+fn hook_decisions(state: &AppState, events: &[HookKey]) {
+    for event in events {
+        black_box(decide(black_box(event), state, |_| false));
+    }
+}
+
+fn bench_uncontended(c: &mut Criterion) {
+    let state = app_state();
+
+    let events = key_events();
+
+    c.bench_function("hook_reads", |b| b.iter(|| hook_reads(&state)));
+    c.bench_function("hook_decisions", |b| {
+        b.iter(|| hook_decisions(&state, &events))
+    });
+    c.bench_function("set_prev_layout", |b| {
+        b.iter(|| state.set_prev_layout(black_box(Some(INSTALLED[1]))))
+    });
+    c.bench_function("toggle_pause", |b| b.iter(|| state.toggle_pause()));
+    c.bench_function("next_recent_layout", |b| {
+        b.iter(|| state.next_recent_layout(black_box(INSTALLED[0]), &INSTALLED))
+    });
+}
+
+fn bench_contended(c: &mut Criterion) {
+    let state = Arc::new(app_state());
+    let is_done = Arc::new(AtomicBool::new(false));
+
+    let writers: Vec<_> = (0..2)
+        .map(|i| {
+            let state = Arc::clone(&state);
+            let is_done = Arc::clone(&is_done);
+            thread::spawn(move || {
+                while !is_done.load(Ordering::Relaxed) {
+                    state.toggle_pause();
+                    state.set_prev_layout(Some(INSTALLED[i]));
+                    state.set_hold_origin(Some(INSTALLED[i + 1]));
+                }
+            })
+        })
+        .collect();
+
+    let events = key_events();
+    c.bench_function("hook_reads_contended", |b| b.iter(|| hook_reads(&state)));
+    c.bench_function("hook_decisions_contended", |b| {
+        b.iter(|| hook_decisions(&state, &events))
+    });
+
+    is_done.store(true, Ordering::Relaxed);
+    for writer in writers {
+        writer.join().unwrap();
+    }
+}
+
+criterion_group!(benches, bench_uncontended, bench_contended);
+criterion_main!(benches);
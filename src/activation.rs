@@ -0,0 +1,402 @@
+use std::time::Duration;
+use windows::Win32::{Foundation::HWND, UI::TextServices::HKL};
+#[cfg(windows)]
+use {
+    crate::layout_id::format_layout,
+    crate::switch::{get_window_layout, on_layout_switched},
+    crate::APP_STATE,
+    log::{debug, warn},
+    std::{
+        mem,
+        sync::{
+            mpsc::{self, Sender},
+            OnceLock,
+        },
+        thread,
+    },
+    windows::Win32::{
+        Foundation::{LPARAM, WPARAM},
+        UI::WindowsAndMessaging::{
+            GetGUIThreadInfo, GetWindowThreadProcessId, PostMessageW, GUITHREADINFO,
+            WM_INPUTLANGCHANGEREQUEST,
+        },
+    },
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+/// Time a window gets to report the new layout after every request
+const SETTLE_POLLS: u32 = 20;
+
+/// Ways to ask a window for a layout again after the first request didn't
+/// take effect, in the order they are tried. `ActivateKeyboardLayout` isn't
+/// one of them, as it only changes the layout of the calling thread or
+/// process, never of another app's window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Retry {
+    /// Posts the request instead of sending it, for windows that are busy
+    /// when the hook runs
+    Post,
+    /// Posts the request to the focused child, which some apps only listen to
+    PostToFocus,
+}
+
+const RETRIES: [Retry; 2] = [Retry::Post, Retry::PostToFocus];
+
+pub trait ActivationBackend {
+    fn layout(&self, target: HWND) -> HKL;
+    /// Returns `false` if the request couldn't be made at all
+    fn request(&self, target: HWND, layout: HKL, retry: Retry) -> bool;
+    fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Confirmed {
+        retries: usize,
+    },
+    /// The window kept its layout, e.g. because it's elevated or hung
+    Rejected,
+}
+
+/// What the window should report once the activation took effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expected {
+    Layout(HKL),
+    /// Any layout but this one, after a keystroke that cycles layouts
+    AnyBut(HKL),
+}
+
+impl Expected {
+    fn is_met(&self, layout: HKL) -> bool {
+        match *self {
+            Self::Layout(expected) => layout == expected,
+            Self::AnyBut(old) => layout != old,
+        }
+    }
+}
+
+fn wait_for<B: ActivationBackend>(backend: &B, target: HWND, expected: Expected) -> bool {
+    for _ in 0..SETTLE_POLLS {
+        if expected.is_met(backend.layout(target)) {
+            return true;
+        }
+        backend.sleep(POLL_INTERVAL);
+    }
+
+    expected.is_met(backend.layout(target))
+}
+
+/// Waits for the window to report the expected layout after the first
+/// request, repeating the request in other ways if it doesn't. Only a
+/// specific layout can be requested again.
+pub fn verify_activation<B: ActivationBackend>(
+    backend: &B,
+    target: HWND,
+    expected: Expected,
+) -> Outcome {
+    if wait_for(backend, target, expected) {
+        return Outcome::Confirmed { retries: 0 };
+    }
+    let Expected::Layout(layout) = expected else {
+        return Outcome::Rejected;
+    };
+
+    for (i, &retry) in RETRIES.iter().enumerate() {
+        if backend.request(target, layout, retry) && wait_for(backend, target, expected) {
+            return Outcome::Confirmed { retries: i + 1 };
+        }
+    }
+
+    Outcome::Rejected
+}
+
+#[cfg(windows)]
+pub struct SystemActivationBackend;
+
+#[cfg(windows)]
+impl ActivationBackend for SystemActivationBackend {
+    fn layout(&self, target: HWND) -> HKL {
+        get_window_layout(target)
+    }
+
+    fn request(&self, target: HWND, layout: HKL, retry: Retry) -> bool {
+        let window = match retry {
+            Retry::Post => target,
+            Retry::PostToFocus => {
+                let mut info = GUITHREADINFO {
+                    cbSize: mem::size_of::<GUITHREADINFO>() as u32,
+                    ..Default::default()
+                };
+                let thread_id = unsafe { GetWindowThreadProcessId(target, None) };
+                if unsafe { GetGUIThreadInfo(thread_id, &mut info) }.is_err()
+                    || info.hwndFocus.0 == 0
+                {
+                    return false;
+                }
+                info.hwndFocus
+            }
+        };
+
+        unsafe {
+            PostMessageW(
+                window,
+                WM_INPUTLANGCHANGEREQUEST,
+                WPARAM(0),
+                LPARAM(layout.0),
+            )
+        }
+        .is_ok()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[cfg(windows)]
+struct Activation {
+    target: HWND,
+    old: HKL,
+    expected: Expected,
+    /// Whether the switch was already reported when the first request was
+    /// answered
+    is_announced: bool,
+}
+
+#[cfg(windows)]
+static SENDER: OnceLock<Sender<Activation>> = OnceLock::new();
+
+/// Confirms a Previous mode switch of `target` away from `old` off the hook
+/// thread. The previous layout is only updated once the window reports the
+/// new one, and a switch not yet announced is announced then.
+#[cfg(windows)]
+pub fn verify(target: HWND, old: HKL, expected: Expected, is_announced: bool) {
+    let activation = Activation {
+        target,
+        old,
+        expected,
+        is_announced,
+    };
+    match SENDER.get() {
+        Some(sender) => {
+            let _ = sender.send(activation);
+        }
+        None => APP_STATE.set_prev_layout(Some(old)),
+    }
+}
+
+/// Starts the worker verifying layout activations
+#[cfg(windows)]
+pub fn start() {
+    let (tx, rx) = mpsc::channel::<Activation>();
+    if SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        for activation in rx {
+            match verify_activation(
+                &SystemActivationBackend,
+                activation.target,
+                activation.expected,
+            ) {
+                Outcome::Confirmed { retries } => {
+                    if retries > 0 {
+                        debug!("Layout activated after {} retries", retries);
+                    }
+                    APP_STATE.set_prev_layout(Some(activation.old));
+                    if !activation.is_announced {
+                        let new = match activation.expected {
+                            Expected::Layout(layout) => layout,
+                            Expected::AnyBut(_) => get_window_layout(activation.target),
+                        };
+                        on_layout_switched(activation.old, Some(new));
+                    }
+                }
+                Outcome::Rejected => warn!(
+                    "Window kept layout {} after the switch, it may be elevated or not responding",
+                    format_layout(&get_window_layout(activation.target))
+                ),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+
+    const TARGET: HWND = HWND(0x1234);
+    const US: HKL = HKL(0x0409_0409);
+    const RU: HKL = HKL(0x0419_0419);
+
+    /// Window that reports `layout`, takes requests made the `accepted` way
+    /// and switches on its own after `switch_after` polls
+    struct FakeWindow {
+        layout: Cell<HKL>,
+        accepted: Option<Retry>,
+        has_focus: bool,
+        switch_after: Option<(u32, HKL)>,
+        polls: Cell<u32>,
+        requests: RefCell<Vec<Retry>>,
+    }
+
+    impl FakeWindow {
+        fn new(layout: HKL) -> Self {
+            Self {
+                layout: Cell::new(layout),
+                accepted: None,
+                has_focus: true,
+                switch_after: None,
+                polls: Cell::new(0),
+                requests: RefCell::new(Vec::new()),
+            }
+        }
+
+        fn requests(&self) -> Vec<Retry> {
+            self.requests.borrow().clone()
+        }
+    }
+
+    impl ActivationBackend for FakeWindow {
+        fn layout(&self, target: HWND) -> HKL {
+            assert_eq!(target, TARGET);
+            if let Some((polls, layout)) = self.switch_after {
+                if self.polls.get() >= polls {
+                    self.layout.set(layout);
+                }
+            }
+            self.polls.set(self.polls.get() + 1);
+
+            self.layout.get()
+        }
+
+        fn request(&self, target: HWND, layout: HKL, retry: Retry) -> bool {
+            assert_eq!(target, TARGET);
+            self.requests.borrow_mut().push(retry);
+            if retry == Retry::PostToFocus && !self.has_focus {
+                return false;
+            }
+            if self.accepted == Some(retry) {
+                self.layout.set(layout);
+            }
+
+            true
+        }
+
+        fn sleep(&self, duration: Duration) {
+            assert_eq!(duration, POLL_INTERVAL);
+        }
+    }
+
+    #[test]
+    fn confirms_on_first_try() {
+        let window = FakeWindow::new(RU);
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Confirmed { retries: 0 }
+        );
+        assert_eq!(window.polls.get(), 1);
+        assert!(window.requests().is_empty());
+    }
+
+    #[test]
+    fn confirms_within_settle_time() {
+        let window = FakeWindow {
+            switch_after: Some((SETTLE_POLLS, RU)),
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Confirmed { retries: 0 }
+        );
+        assert!(window.requests().is_empty());
+    }
+
+    #[test]
+    fn confirms_after_post() {
+        let window = FakeWindow {
+            accepted: Some(Retry::Post),
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Confirmed { retries: 1 }
+        );
+        assert_eq!(window.requests(), [Retry::Post]);
+    }
+
+    #[test]
+    fn confirms_after_post_to_focus() {
+        let window = FakeWindow {
+            accepted: Some(Retry::PostToFocus),
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Confirmed { retries: 2 }
+        );
+        assert_eq!(window.requests(), [Retry::Post, Retry::PostToFocus]);
+    }
+
+    #[test]
+    fn rejects_after_all_retries() {
+        let window = FakeWindow::new(US);
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Rejected
+        );
+        assert_eq!(window.requests(), [Retry::Post, Retry::PostToFocus]);
+        // Every request was given the full settle time
+        assert_eq!(window.polls.get(), (SETTLE_POLLS + 1) * 3);
+    }
+
+    #[test]
+    fn rejects_without_focused_child() {
+        let window = FakeWindow {
+            accepted: Some(Retry::PostToFocus),
+            has_focus: false,
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::Layout(RU)),
+            Outcome::Rejected
+        );
+        assert_eq!(window.requests(), [Retry::Post, Retry::PostToFocus]);
+    }
+
+    #[test]
+    fn confirms_any_other_layout() {
+        let window = FakeWindow {
+            switch_after: Some((3, RU)),
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::AnyBut(US)),
+            Outcome::Confirmed { retries: 0 }
+        );
+    }
+
+    #[test]
+    fn rejects_any_other_layout_without_retries() {
+        let window = FakeWindow {
+            accepted: Some(Retry::Post),
+            ..FakeWindow::new(US)
+        };
+
+        assert_eq!(
+            verify_activation(&window, TARGET, Expected::AnyBut(US)),
+            Outcome::Rejected
+        );
+        assert!(window.requests().is_empty());
+        assert_eq!(window.polls.get(), SETTLE_POLLS + 1);
+    }
+}
@@ -1,67 +1,153 @@
-use crate::constants::{HKCU, REG_RUN_PATH};
-use std::env;
-use winreg::enums::KEY_SET_VALUE;
+mod cmdline;
+mod registry;
+// File-based mechanisms of Linux desktops
+#[cfg(not(windows))]
+mod systemd;
+#[cfg(not(windows))]
+mod xdg;
 
-pub fn is_autoload_enabled() -> bool {
-    let run = HKCU
-        .open_subkey(REG_RUN_PATH)
-        .expect("Failed to open registry key");
+use crate::error::{CapsWitchError, Result};
+use log::{error, info};
+use std::{
+    env, io,
+    path::{Path, PathBuf},
+};
 
-    let record_value: std::result::Result<String, std::io::Error> =
-        run.get_value(env!("CARGO_PKG_NAME"));
+#[cfg(windows)]
+pub use registry::RegistryRun;
+#[cfg(not(windows))]
+use {
+    crate::config::{AutostartMethod, CONFIG},
+    systemd::SystemdUserUnit,
+    xdg::XdgAutostart,
+};
 
-    let record_value = match record_value {
-        Ok(path) => path,
-        Err(_) => return false,
-    };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AutostartStatus {
+    Disabled,
+    /// `exe` is started on login with `args`
+    Enabled {
+        exe: PathBuf,
+        args: Vec<String>,
+    },
+}
 
-    let splitted_value: Vec<&str> = record_value.split(' ').collect();
-    let app_path = splitted_value[0];
+/// A way to start the application on user login
+pub trait Autostart {
+    fn enable(&self, exe: &Path, args: &[String]) -> io::Result<()>;
+    fn disable(&self) -> io::Result<()>;
+    fn status(&self) -> io::Result<AutostartStatus>;
+}
 
-    let curr_exe = env::current_exe().expect("Failed to get current executable path");
+/// `$XDG_CONFIG_HOME` falling back to `~/.config`
+#[cfg(not(windows))]
+fn get_xdg_config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
 
-    if app_path != curr_exe.to_str().unwrap() {
-        return false;
-    }
+/// Autostart mechanism of the current platform
+#[cfg(windows)]
+pub fn get_autostart() -> Option<Box<dyn Autostart>> {
+    Some(Box::new(RegistryRun::new(env!("CARGO_PKG_NAME"))))
+}
+
+/// Autostart mechanism of the current platform, chosen in the config
+#[cfg(not(windows))]
+pub fn get_autostart() -> Option<Box<dyn Autostart>> {
+    let config_home = get_xdg_config_home()?;
+    let (name, description) = (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_DESCRIPTION"));
 
-    true
+    Some(match CONFIG.autoload.method {
+        AutostartMethod::Xdg => Box::new(XdgAutostart::new(&config_home, name, description)),
+        AutostartMethod::Systemd => Box::new(SystemdUserUnit::new(&config_home, name, description)),
+    })
 }
 
-pub fn remove_autoload() -> bool {
-    let run = HKCU
-        .open_subkey_with_flags(REG_RUN_PATH, KEY_SET_VALUE)
-        .expect("Failed to open registry key");
+/// Paths on Windows are case-insensitive
+pub fn is_same_path(a: &Path, b: &Path) -> bool {
+    if cfg!(windows) {
+        a.to_string_lossy().to_lowercase() == b.to_string_lossy().to_lowercase()
+    } else {
+        a == b
+    }
+}
+
+pub fn is_autoload_enabled() -> bool {
+    let Some(autostart) = get_autostart() else {
+        return false;
+    };
+
+    let curr_exe = match env::current_exe() {
+        Ok(curr_exe) => curr_exe,
+        Err(err) => {
+            error!("Failed to get current executable path: {}", err);
+            return false;
+        }
+    };
 
-    let remove_result = run.delete_value(env!("CARGO_PKG_NAME"));
-    match remove_result {
-        Ok(_) => true,
-        Err(_) => false,
+    match autostart.status() {
+        Ok(AutostartStatus::Enabled { exe, .. }) => is_same_path(&exe, &curr_exe),
+        Ok(AutostartStatus::Disabled) => false,
+        Err(err) => {
+            error!("Failed to get autoload status: {}", err);
+            false
+        }
     }
 }
 
-pub fn set_autoload(flag: Option<String>) -> bool {
-    let run = HKCU
-        .open_subkey_with_flags(REG_RUN_PATH, KEY_SET_VALUE)
-        .expect("Failed to open registry key");
+pub fn remove_autoload() -> Result<()> {
+    let Some(autostart) = get_autostart() else {
+        return Ok(());
+    };
 
-    let curr_exe = env::current_exe().expect("Failed to get current executable path");
+    autostart.disable().map_err(CapsWitchError::Autoload)
+}
 
-    let curr_exe_str = curr_exe
-        .to_str()
-        .expect("Failed to get current executable path");
+pub fn set_autoload(flag: Option<String>) -> Result<()> {
+    let Some(autostart) = get_autostart() else {
+        return Err(CapsWitchError::Autoload(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No autostart mechanism found",
+        )));
+    };
 
-    let value = if flag.is_none() {
-        curr_exe_str.to_string() // Convert &str to String
-    } else {
-        format!("{} {}", curr_exe_str, flag.unwrap()) // format! already returns a String
+    let curr_exe = env::current_exe().map_err(CapsWitchError::Autoload)?;
+    let args: Vec<String> = flag.into_iter().collect();
+
+    autostart
+        .enable(&curr_exe, &args)
+        .map_err(CapsWitchError::Autoload)
+}
+
+/// Points an autoload entry left by a moved or reinstalled executable to the
+/// current one, keeping its arguments. Entries of executables that still
+/// exist are left alone, as they may belong to another copy of the app.
+pub fn repair_stale_autoload() {
+    let Some(autostart) = get_autostart() else {
+        return;
+    };
+
+    let Ok(curr_exe) = env::current_exe() else {
+        return;
     };
 
-    let set_result = run.set_value(env!("CARGO_PKG_NAME"), &value);
-    match set_result {
-        Ok(_) => true,
+    let (exe, args) = match autostart.status() {
+        Ok(AutostartStatus::Enabled { exe, args }) => (exe, args),
+        Ok(AutostartStatus::Disabled) => return,
         Err(err) => {
-            eprintln!("Failed to set registry value: {:?}", err);
-            return false;
+            error!("Failed to get autoload status: {}", err);
+            return;
         }
+    };
+    if is_same_path(&exe, &curr_exe) || exe.exists() {
+        return;
+    }
+
+    match autostart.enable(&curr_exe, &args) {
+        Ok(_) => info!("Repaired autoload entry pointing to {}", exe.display()),
+        Err(err) => error!("Failed to repair autoload entry: {}", err),
     }
 }
@@ -0,0 +1,257 @@
+//! Windows command line quoting and parsing compatible with
+//! `CommandLineToArgvW`, written in Rust so that it doesn't need Windows.
+
+fn needs_quotes(arg: &str) -> bool {
+    arg.is_empty() || arg.contains([' ', '\t', '\n', '\x0B', '"'])
+}
+
+/// Quotes an argument so that `CommandLineToArgvW` reads it back unchanged
+pub fn quote_arg(arg: &str) -> String {
+    if !needs_quotes(arg) {
+        return arg.to_string();
+    }
+
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Backslashes before a quote are escaped, and so is the quote
+                quoted.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    // Backslashes before the closing quote would escape it
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+
+    quoted
+}
+
+/// Builds a command line starting `exe` with `args`. The program name is
+/// parsed without escapes, so it is always quoted and can't contain quotes.
+pub fn join_command_line(exe: &str, args: &[String]) -> String {
+    let mut command_line = format!("\"{}\"", exe);
+    for arg in args {
+        command_line.push(' ');
+        command_line.push_str(&quote_arg(arg));
+    }
+
+    command_line
+}
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+/// Splits a command line the way `CommandLineToArgvW` does: the program name
+/// ends at the closing quote or the first blank, the other arguments follow
+/// the backslash and quote rules of the Microsoft C runtime.
+pub fn parse_command_line(command_line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut chars = command_line.chars().peekable();
+
+    let mut program = String::new();
+    if chars.peek() == Some(&'"') {
+        chars.next();
+        for c in chars.by_ref() {
+            if c == '"' {
+                break;
+            }
+            program.push(c);
+        }
+    } else {
+        while let Some(&c) = chars.peek() {
+            if is_blank(c) {
+                break;
+            }
+            program.push(c);
+            chars.next();
+        }
+    }
+    args.push(program);
+
+    while chars.peek().is_some_and(|&c| is_blank(c)) {
+        chars.next();
+    }
+    if chars.peek().is_none() {
+        return args;
+    }
+
+    let mut arg = String::new();
+    // 0 outside quotes, 1 inside, counts consecutive quotes in between
+    let mut quotes = 0;
+    let mut backslashes = 0;
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if is_blank(c) && quotes == 0 => {
+                arg.extend(std::iter::repeat_n('\\', backslashes));
+                backslashes = 0;
+                args.push(std::mem::take(&mut arg));
+                while chars.peek().is_some_and(|&c| is_blank(c)) {
+                    chars.next();
+                }
+                if chars.peek().is_none() {
+                    return args;
+                }
+            }
+            '\\' => backslashes += 1,
+            '"' => {
+                arg.extend(std::iter::repeat_n('\\', backslashes / 2));
+                if backslashes % 2 == 0 {
+                    quotes += 1;
+                } else {
+                    arg.push('"');
+                }
+                backslashes = 0;
+
+                // Every third quote in a row is a literal one
+                while chars.peek() == Some(&'"') {
+                    chars.next();
+                    quotes += 1;
+                    if quotes == 3 {
+                        arg.push('"');
+                        quotes = 0;
+                    }
+                }
+                if quotes == 2 {
+                    quotes = 0;
+                }
+            }
+            _ => {
+                arg.extend(std::iter::repeat_n('\\', backslashes));
+                backslashes = 0;
+                arg.push(c);
+            }
+        }
+    }
+    arg.extend(std::iter::repeat_n('\\', backslashes));
+    args.push(arg);
+
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EXE: &str = r"C:\Program Files\CapsWitch\CapsWitch.exe";
+
+    /// Arguments after the program name
+    fn parse_args(args: &str) -> Vec<String> {
+        let mut parsed = parse_command_line(&format!("CapsWitch.exe {}", args));
+        assert_eq!(parsed.remove(0), "CapsWitch.exe");
+        parsed
+    }
+
+    #[test]
+    fn quotes_only_when_needed() {
+        assert_eq!(quote_arg("--previous"), "--previous");
+        assert_eq!(quote_arg(r"C:\dir\"), r"C:\dir\");
+        assert_eq!(quote_arg(""), r#""""#);
+        assert_eq!(quote_arg("a b"), r#""a b""#);
+        assert_eq!(quote_arg(r#"a"b"#), r#""a\"b""#);
+        assert_eq!(quote_arg(r"C:\my dir\"), r#""C:\my dir\\""#);
+        assert_eq!(quote_arg(r#"a\"b"#), r#""a\\\"b""#);
+    }
+
+    #[test]
+    fn round_trips_args() {
+        let args: Vec<String> = [
+            "--mode=mru",
+            "",
+            " ",
+            "a b",
+            "\ttab",
+            "line\nbreak",
+            r#"""#,
+            r#""""#,
+            r#"say "hi""#,
+            r"C:\dir\",
+            r"C:\my dir\",
+            r"C:\my dir\\",
+            r#"a\"b"#,
+            r#"a\\"b"#,
+            r"\\server\share",
+            "%APPDATA%",
+            "ключ",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+
+        let parsed = parse_command_line(&join_command_line(EXE, &args));
+
+        assert_eq!(parsed[0], EXE);
+        assert_eq!(parsed[1..], args);
+    }
+
+    #[test]
+    fn round_trips_each_arg_alone() {
+        for arg in ["", "a b", r"C:\my dir\", r#"""#, r#"\""#, r"\"] {
+            let args = vec![arg.to_string()];
+
+            assert_eq!(
+                parse_command_line(&join_command_line(EXE, &args)),
+                [EXE, arg]
+            );
+        }
+    }
+
+    #[test]
+    fn parses_program_name_without_escapes() {
+        assert_eq!(
+            parse_command_line(r#""C:\dir\"CapsWitch.exe"#),
+            [r"C:\dir\", "CapsWitch.exe"]
+        );
+        assert_eq!(
+            parse_command_line(r"C:\dir\CapsWitch.exe"),
+            [r"C:\dir\CapsWitch.exe"]
+        );
+        assert_eq!(
+            parse_command_line(r#""C:\my dir\a.exe"   "#),
+            [r"C:\my dir\a.exe"]
+        );
+        assert_eq!(parse_command_line(""), [""]);
+    }
+
+    #[test]
+    fn follows_backslash_rules() {
+        assert_eq!(parse_args(r#""a b c" d e"#), ["a b c", "d", "e"]);
+        assert_eq!(parse_args(r#""ab\"c" "\\" d"#), [r#"ab"c"#, r"\", "d"]);
+        assert_eq!(parse_args(r#"a\\\b d"e f"g h"#), [r"a\\\b", "de fg", "h"]);
+        assert_eq!(parse_args(r#"a\\\"b c d"#), [r#"a\"b"#, "c", "d"]);
+        assert_eq!(parse_args(r#"a\\\\"b c" d e"#), [r"a\\b c", "d", "e"]);
+        assert_eq!(parse_args(r"trailing\\"), [r"trailing\\"]);
+    }
+
+    #[test]
+    fn follows_double_quote_rules() {
+        // A pair inside quotes is a literal quote that also ends the quoting
+        assert_eq!(parse_args(r#"a"b"" c d"#), [r#"ab""#, "c", "d"]);
+        // Every third quote in a row is a literal one
+        assert_eq!(parse_args("\"\"\""), ["\""]);
+        assert_eq!(parse_args("\"\"\"\"\"\" x"), ["\"\"", "x"]);
+        // A pair outside quotes is an empty argument
+        assert_eq!(parse_args("\"\""), [""]);
+        assert_eq!(parse_args(r#""" """#), ["", ""]);
+        assert_eq!(parse_args(r#"a"" b"#), ["a", "b"]);
+    }
+
+    #[test]
+    fn skips_blanks_between_args() {
+        assert_eq!(parse_args(" \t a \t b \t "), ["a", "b"]);
+        assert_eq!(parse_args("   "), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,134 @@
+use super::cmdline::parse_command_line;
+#[cfg(windows)]
+use {
+    super::cmdline::join_command_line,
+    super::{Autostart, AutostartStatus},
+    crate::constants::{HKCU, REG_RUN_PATH},
+    std::{
+        io,
+        path::{Path, PathBuf},
+    },
+    winreg::enums::KEY_SET_VALUE,
+};
+
+/// Value in the current user's `Run` registry key
+#[cfg(windows)]
+pub struct RegistryRun {
+    name: String,
+}
+
+#[cfg(windows)]
+impl RegistryRun {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+        }
+    }
+}
+
+/// Values written by older versions have an unquoted executable path, which
+/// breaks on paths with spaces. Like `CreateProcess`, such a path is taken up
+/// to the `.exe` extension.
+fn parse_run_value(value: &str) -> Vec<String> {
+    let value = value.trim_start();
+    if value.starts_with('"') {
+        return parse_command_line(value);
+    }
+
+    let exe_end = value
+        .to_ascii_lowercase()
+        .match_indices(".exe")
+        .map(|(i, _)| i + ".exe".len())
+        .find(|&end| value[end..].is_empty() || value[end..].starts_with([' ', '\t']));
+    match exe_end {
+        Some(end) => parse_command_line(&format!("\"{}\"{}", &value[..end], &value[end..])),
+        None => parse_command_line(value),
+    }
+}
+
+#[cfg(windows)]
+impl Autostart for RegistryRun {
+    fn enable(&self, exe: &Path, args: &[String]) -> io::Result<()> {
+        let run = HKCU.open_subkey_with_flags(REG_RUN_PATH, KEY_SET_VALUE)?;
+
+        let exe = exe
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Non-unicode path"))?;
+
+        run.set_value(&self.name, &join_command_line(exe, args))
+    }
+
+    fn disable(&self) -> io::Result<()> {
+        let run = HKCU.open_subkey_with_flags(REG_RUN_PATH, KEY_SET_VALUE)?;
+
+        match run.delete_value(&self.name) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn status(&self) -> io::Result<AutostartStatus> {
+        let run = HKCU.open_subkey(REG_RUN_PATH)?;
+
+        let record_value: String = match run.get_value(&self.name) {
+            Ok(value) => value,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(AutostartStatus::Disabled)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut args = parse_run_value(&record_value).into_iter();
+        let exe = PathBuf::from(args.next().unwrap_or_default());
+
+        Ok(AutostartStatus::Enabled {
+            exe,
+            args: args.collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quoted_value() {
+        assert_eq!(
+            parse_run_value(r#""C:\Program Files\CapsWitch\CapsWitch.exe" --mode=mru"#),
+            [r"C:\Program Files\CapsWitch\CapsWitch.exe", "--mode=mru"]
+        );
+    }
+
+    #[test]
+    fn parses_legacy_unquoted_value() {
+        assert_eq!(
+            parse_run_value(r"C:\Program Files\CapsWitch\CapsWitch.exe --previous"),
+            [r"C:\Program Files\CapsWitch\CapsWitch.exe", "--previous"]
+        );
+        assert_eq!(
+            parse_run_value(r"  C:\Program Files\CapsWitch\CapsWitch.exe"),
+            [r"C:\Program Files\CapsWitch\CapsWitch.exe"]
+        );
+        assert_eq!(
+            parse_run_value("C:\\Program Files\\CapsWitch\\CAPSWITCH.EXE\t--previous"),
+            [r"C:\Program Files\CapsWitch\CAPSWITCH.EXE", "--previous"]
+        );
+    }
+
+    #[test]
+    fn takes_exe_up_to_extension_followed_by_blank() {
+        assert_eq!(
+            parse_run_value(r"C:\Tools.exec\Caps Witch\CapsWitch.exe --previous"),
+            [r"C:\Tools.exec\Caps Witch\CapsWitch.exe", "--previous"]
+        );
+    }
+
+    #[test]
+    fn parses_value_without_exe_extension() {
+        assert_eq!(
+            parse_run_value(r"C:\CapsWitch\CapsWitch --previous"),
+            [r"C:\CapsWitch\CapsWitch", "--previous"]
+        );
+    }
+}
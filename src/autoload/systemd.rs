@@ -0,0 +1,302 @@
+use super::{Autostart, AutostartStatus};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Target of the user session that starts graphical applications
+const WANTED_BY: &str = "graphical-session.target";
+
+/// systemd user service, enabled the way `systemctl --user enable` does it:
+/// with a link in the `.wants` directory of the target
+pub struct SystemdUserUnit {
+    unit_path: PathBuf,
+    wants_path: PathBuf,
+    description: String,
+}
+
+impl SystemdUserUnit {
+    /// `config_home` is `$XDG_CONFIG_HOME`, usually `~/.config`
+    pub fn new(config_home: &Path, name: &str, description: &str) -> Self {
+        let units_dir = config_home.join("systemd").join("user");
+        let unit_name = format!("{}.service", name.to_lowercase());
+
+        Self {
+            wants_path: units_dir
+                .join(format!("{}.wants", WANTED_BY))
+                .join(&unit_name),
+            unit_path: units_dir.join(unit_name),
+            description: description.to_string(),
+        }
+    }
+}
+
+fn quote_exec_arg(arg: &str) -> String {
+    // `%` starts specifiers and `$` variables, both are doubled to be literal
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '"', '\'', '\\', ';']) {
+        return arg;
+    }
+
+    let mut quoted = String::from('"');
+    for c in arg.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Splits an `ExecStart` value into arguments, the reverse of `quote_exec_arg`
+fn parse_exec(exec: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut has_arg = false;
+    let mut in_quotes = false;
+    let mut chars = exec.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            '\\' => match chars.next() {
+                Some('n') => arg.push('\n'),
+                Some('t') => arg.push('\t'),
+                Some(escaped) => arg.push(escaped),
+                None => {}
+            },
+            ' ' | '\t' if !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut arg));
+                    has_arg = false;
+                }
+            }
+            '%' | '$' if chars.peek() == Some(&c) => {
+                chars.next();
+                arg.push(c);
+                has_arg = true;
+            }
+            _ => {
+                arg.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(arg);
+    }
+
+    args
+}
+
+#[cfg(unix)]
+fn link_unit(unit_path: &Path, link_path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(unit_path, link_path)
+}
+
+#[cfg(not(unix))]
+fn link_unit(_unit_path: &Path, _link_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "systemd units can only be enabled on Unix",
+    ))
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl Autostart for SystemdUserUnit {
+    fn enable(&self, exe: &Path, args: &[String]) -> io::Result<()> {
+        let exec: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(args.iter().cloned())
+            .map(|arg| quote_exec_arg(&arg))
+            .collect();
+        let unit = format!(
+            "[Unit]\nDescription={}\nPartOf={wanted_by}\nAfter={wanted_by}\n\n\
+             [Service]\nExecStart={}\nRestart=on-failure\n\n\
+             [Install]\nWantedBy={wanted_by}\n",
+            self.description,
+            exec.join(" "),
+            wanted_by = WANTED_BY,
+        );
+
+        if let Some(dir) = self.unit_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.unit_path, unit)?;
+
+        if let Some(dir) = self.wants_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        remove_if_exists(&self.wants_path)?;
+        link_unit(&self.unit_path, &self.wants_path)
+    }
+
+    fn disable(&self) -> io::Result<()> {
+        remove_if_exists(&self.wants_path)?;
+        remove_if_exists(&self.unit_path)
+    }
+
+    fn status(&self) -> io::Result<AutostartStatus> {
+        if fs::symlink_metadata(&self.wants_path).is_err() {
+            return Ok(AutostartStatus::Disabled);
+        }
+
+        let unit = match fs::read_to_string(&self.unit_path) {
+            Ok(unit) => unit,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(AutostartStatus::Disabled)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let exec = unit
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(key, _)| key.trim() == "ExecStart")
+            .map(|(_, value)| parse_exec(value.trim()))
+            .unwrap_or_default();
+
+        let mut args = exec.into_iter();
+        match args.next() {
+            Some(exe) => Ok(AutostartStatus::Enabled {
+                exe: PathBuf::from(exe),
+                args: args.collect(),
+            }),
+            None => Ok(AutostartStatus::Disabled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit(config_home: &Path) -> SystemdUserUnit {
+        SystemdUserUnit::new(config_home, "CapsWitch", "Switch layouts with CapsLock")
+    }
+
+    fn enabled(exe: &str, args: &[&str]) -> AutostartStatus {
+        AutostartStatus::Enabled {
+            exe: PathBuf::from(exe),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    fn to_strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn status_is_disabled_without_unit() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            unit(dir.path()).status().unwrap(),
+            AutostartStatus::Disabled
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enable_round_trips_special_chars() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = unit(dir.path());
+        let exe = "/opt/Caps Witch/capswitch";
+        let args = [
+            "--previous",
+            "100%",
+            "%h",
+            "$HOME",
+            "${USER}",
+            "$$",
+            "say \"hi\"",
+            "it's",
+            "back\\slash",
+            "trailing\\",
+            "semi;colon",
+            "tab\there",
+            "new\nline",
+            "",
+        ];
+
+        unit.enable(Path::new(exe), &to_strings(&args)).unwrap();
+
+        assert_eq!(unit.status().unwrap(), enabled(exe, &args));
+        assert_eq!(fs::read_link(&unit.wants_path).unwrap(), unit.unit_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn enable_twice_replaces_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = unit(dir.path());
+        let exe = Path::new("/usr/bin/capswitch");
+
+        unit.enable(exe, &to_strings(&["--previous"])).unwrap();
+        unit.enable(exe, &[]).unwrap();
+
+        assert_eq!(unit.status().unwrap(), enabled("/usr/bin/capswitch", &[]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn disable_removes_unit_and_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = unit(dir.path());
+
+        unit.enable(Path::new("/usr/bin/capswitch"), &[]).unwrap();
+        unit.disable().unwrap();
+
+        assert_eq!(unit.status().unwrap(), AutostartStatus::Disabled);
+        assert!(fs::symlink_metadata(&unit.wants_path).is_err());
+        assert!(!unit.unit_path.exists());
+        // Disabling twice is fine
+        unit.disable().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn status_is_disabled_without_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let unit = unit(dir.path());
+
+        unit.enable(Path::new("/usr/bin/capswitch"), &[]).unwrap();
+        fs::remove_file(&unit.wants_path).unwrap();
+
+        assert_eq!(unit.status().unwrap(), AutostartStatus::Disabled);
+    }
+
+    #[cfg(not(unix))]
+    #[test]
+    fn enable_is_unsupported() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let err = unit(dir.path())
+            .enable(Path::new("capswitch"), &[])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn quote_exec_arg_round_trips() {
+        let args = ["plain", "with space", "%i", "$VAR", "\"", "\\", ""];
+        let exec: Vec<String> = args.iter().map(|arg| quote_exec_arg(arg)).collect();
+
+        assert_eq!(parse_exec(&exec.join(" ")), args);
+    }
+}
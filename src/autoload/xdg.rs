@@ -0,0 +1,315 @@
+use super::{Autostart, AutostartStatus};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Characters that have to be escaped inside a quoted `Exec` argument
+const EXEC_ESCAPED_CHARS: [char; 4] = ['"', '`', '$', '\\'];
+/// Characters that make an `Exec` argument require quoting
+const EXEC_RESERVED_CHARS: [char; 19] = [
+    ' ', '\t', '\n', '"', '\'', '\\', '>', '<', '~', '|', '&', ';', '$', '*', '?', '#', '(', ')',
+    '`',
+];
+
+/// `.desktop` entry in the XDG autostart directory
+pub struct XdgAutostart {
+    entry_path: PathBuf,
+    name: String,
+    comment: String,
+}
+
+impl XdgAutostart {
+    /// `config_home` is `$XDG_CONFIG_HOME`, usually `~/.config`
+    pub fn new(config_home: &Path, name: &str, comment: &str) -> Self {
+        Self {
+            entry_path: config_home
+                .join("autostart")
+                .join(format!("{}.desktop", name)),
+            name: name.to_string(),
+            comment: comment.to_string(),
+        }
+    }
+}
+
+fn quote_exec_arg(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if !arg.is_empty() && !arg.contains(EXEC_RESERVED_CHARS) {
+        return arg;
+    }
+
+    let mut quoted = String::from('"');
+    for c in arg.chars() {
+        if EXEC_ESCAPED_CHARS.contains(&c) {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Applies the escapes of desktop entry string values, which come on top of
+/// the `Exec` quoting, so a literal backslash in a quoted argument is `\\\\`
+fn escape_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t")
+        .replace('\r', "\\r")
+}
+
+fn unescape_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+/// Splits an `Exec` value into arguments as described in the Desktop Entry
+/// Specification. Field codes other than `%%` are dropped.
+fn parse_exec(exec: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut has_arg = false;
+    let mut in_quotes = false;
+    let mut chars = exec.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            '\\' if in_quotes => {
+                if let Some(escaped) = chars.next() {
+                    arg.push(escaped);
+                }
+            }
+            ' ' | '\t' if !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut arg));
+                    has_arg = false;
+                }
+            }
+            '%' => {
+                if let Some('%') = chars.next() {
+                    arg.push('%');
+                    has_arg = true;
+                }
+            }
+            _ => {
+                arg.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(arg);
+    }
+
+    args
+}
+
+impl Autostart for XdgAutostart {
+    fn enable(&self, exe: &Path, args: &[String]) -> io::Result<()> {
+        let exec: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+            .chain(args.iter().cloned())
+            .map(|arg| quote_exec_arg(&arg))
+            .collect();
+        let entry = format!(
+            "[Desktop Entry]\nType=Application\nName={}\nComment={}\nExec={}\nX-GNOME-Autostart-enabled=true\n",
+            self.name,
+            escape_value(&self.comment),
+            escape_value(&exec.join(" "))
+        );
+
+        if let Some(dir) = self.entry_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.entry_path, entry)
+    }
+
+    fn disable(&self) -> io::Result<()> {
+        match fs::remove_file(&self.entry_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn status(&self) -> io::Result<AutostartStatus> {
+        let entry = match fs::read_to_string(&self.entry_path) {
+            Ok(entry) => entry,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(AutostartStatus::Disabled)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut exec = None;
+        for line in entry.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match (key.trim(), value.trim()) {
+                ("Hidden", "true") | ("X-GNOME-Autostart-enabled", "false") => {
+                    return Ok(AutostartStatus::Disabled)
+                }
+                ("Exec", value) => exec = Some(parse_exec(&unescape_value(value))),
+                _ => {}
+            }
+        }
+
+        let mut args = exec.unwrap_or_default().into_iter();
+        match args.next() {
+            Some(exe) => Ok(AutostartStatus::Enabled {
+                exe: PathBuf::from(exe),
+                args: args.collect(),
+            }),
+            None => Ok(AutostartStatus::Disabled),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn autostart(config_home: &Path) -> XdgAutostart {
+        XdgAutostart::new(config_home, "CapsWitch", "Switch layouts\twith CapsLock")
+    }
+
+    fn round_trip(exe: &str, args: &[&str]) -> AutostartStatus {
+        let dir = tempfile::tempdir().unwrap();
+        let autostart = autostart(dir.path());
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+        autostart.enable(Path::new(exe), &args).unwrap();
+        autostart.status().unwrap()
+    }
+
+    fn enabled(exe: &str, args: &[&str]) -> AutostartStatus {
+        AutostartStatus::Enabled {
+            exe: PathBuf::from(exe),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn status_is_disabled_without_entry() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert_eq!(
+            autostart(dir.path()).status().unwrap(),
+            AutostartStatus::Disabled
+        );
+    }
+
+    #[test]
+    fn enable_round_trips_plain_args() {
+        let args = ["--previous", "-v"];
+
+        assert_eq!(
+            round_trip("/usr/bin/capswitch", &args),
+            enabled("/usr/bin/capswitch", &args)
+        );
+    }
+
+    #[test]
+    fn enable_round_trips_special_chars() {
+        let exe = "/opt/Caps Witch/capswitch";
+        let args = [
+            "100%",
+            "%u",
+            "$HOME",
+            "$$",
+            "say \"hi\"",
+            "it's",
+            "back\\slash",
+            "trailing\\",
+            "tab\there",
+            "`cmd`",
+            "",
+        ];
+
+        assert_eq!(round_trip(exe, &args), enabled(exe, &args));
+    }
+
+    #[test]
+    fn enable_overwrites_previous_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let autostart = autostart(dir.path());
+        let exe = Path::new("/usr/bin/capswitch");
+
+        autostart.enable(exe, &["--previous".to_string()]).unwrap();
+        autostart.enable(exe, &[]).unwrap();
+
+        assert_eq!(
+            autostart.status().unwrap(),
+            enabled("/usr/bin/capswitch", &[])
+        );
+    }
+
+    #[test]
+    fn disable_removes_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let autostart = autostart(dir.path());
+
+        autostart
+            .enable(Path::new("/usr/bin/capswitch"), &[])
+            .unwrap();
+        autostart.disable().unwrap();
+
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+        assert!(!autostart.entry_path.exists());
+        // Disabling twice is fine
+        autostart.disable().unwrap();
+    }
+
+    #[test]
+    fn status_respects_disabled_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let autostart = autostart(dir.path());
+        autostart
+            .enable(Path::new("/usr/bin/capswitch"), &[])
+            .unwrap();
+
+        let entry = fs::read_to_string(&autostart.entry_path).unwrap();
+        fs::write(
+            &autostart.entry_path,
+            entry.replace(
+                "X-GNOME-Autostart-enabled=true",
+                "X-GNOME-Autostart-enabled=false",
+            ),
+        )
+        .unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+
+        fs::write(&autostart.entry_path, format!("{}Hidden=true\n", entry)).unwrap();
+        assert_eq!(autostart.status().unwrap(), AutostartStatus::Disabled);
+    }
+
+    #[test]
+    fn parse_exec_drops_field_codes() {
+        assert_eq!(
+            parse_exec("capswitch %U --flag 100%%"),
+            ["capswitch", "--flag", "100%"]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::gesture::{Gesture, MAX_TAPS};
+use log::warn;
+use std::collections::HashMap;
+
+/// Modifiers held with CapsLock, as a set of bits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const CTRL: Modifiers = Modifiers(1);
+    pub const ALT: Modifiers = Modifiers(2);
+    pub const SHIFT: Modifiers = Modifiers(4);
+    pub const WIN: Modifiers = Modifiers(8);
+
+    const NAMES: [(Modifiers, &'static str); 4] = [
+        (Modifiers::CTRL, "ctrl"),
+        (Modifiers::ALT, "alt"),
+        (Modifiers::SHIFT, "shift"),
+        (Modifiers::WIN, "win"),
+    ];
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn with(self, other: Modifiers, is_held: bool) -> Self {
+        if is_held {
+            Modifiers(self.0 | other.0)
+        } else {
+            self
+        }
+    }
+
+    /// Parses a chord like `shift` or `ctrl+alt`
+    pub fn parse(value: &str) -> Option<Self> {
+        value
+            .split('+')
+            .try_fold(Modifiers::NONE, |modifiers, name| {
+                let (modifier, _) = Modifiers::NAMES
+                    .iter()
+                    .find(|(_, n)| n.eq_ignore_ascii_case(name.trim()))?;
+                Some(Modifiers(modifiers.0 | modifier.0))
+            })
+    }
+
+    /// Parses a CapsLock hotkey like `ctrl+alt` or `ctrl+alt+capslock`,
+    /// which needs at least one modifier
+    pub fn parse_hotkey(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let chord = value
+            .len()
+            .checked_sub("+capslock".len())
+            .filter(|&i| value.is_char_boundary(i))
+            .filter(|&i| value[i..].eq_ignore_ascii_case("+capslock"))
+            .map_or(value, |i| &value[..i]);
+
+        Modifiers::parse(chord).filter(|modifiers| !modifiers.is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyAction {
+    /// Switches the layout the way the current mode does
+    Switch,
+    /// Lets the system toggle caps, like CapsLock does without the app
+    Caps,
+    /// Switches to the previously used layout whatever the mode
+    Previous,
+    /// Switches to the layout before the current one in the rotation
+    Reverse,
+    /// Pauses the app, CapsLock then works as usual until resumed
+    TogglePause,
+    /// Types the word typed last again after switching the layout the way
+    /// the current mode does
+    ConvertWord,
+    /// Does nothing, the press is swallowed
+    Ignore,
+}
+
+impl KeyAction {
+    const ALL: [KeyAction; 7] = [
+        KeyAction::Switch,
+        KeyAction::Caps,
+        KeyAction::Previous,
+        KeyAction::Reverse,
+        KeyAction::TogglePause,
+        KeyAction::ConvertWord,
+        KeyAction::Ignore,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyAction::Switch => "switch",
+            KeyAction::Caps => "caps",
+            KeyAction::Previous => "previous",
+            KeyAction::Reverse => "reverse",
+            KeyAction::TogglePause => "toggle_pause",
+            KeyAction::ConvertWord => "convert_word",
+            KeyAction::Ignore => "ignore",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        KeyAction::ALL
+            .into_iter()
+            .find(|action| action.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+}
+
+/// Actions bound to CapsLock gestures. A chord without a binding does what
+/// a single tap does, and so do the taps of a multi-tap without one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bindings {
+    actions: HashMap<Gesture, KeyAction>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self {
+            actions: HashMap::from([
+                (Gesture::Chord(Modifiers::SHIFT), KeyAction::Caps),
+                (Gesture::Chord(Modifiers::CTRL), KeyAction::Reverse),
+            ]),
+        }
+    }
+}
+
+impl Bindings {
+    /// The default bindings with the configured ones on top. Invalid entries
+    /// are skipped.
+    pub fn from_config(entries: &HashMap<String, String>) -> Self {
+        let mut bindings = Self::default();
+        for (gesture, action) in entries {
+            match (Gesture::parse(gesture), KeyAction::parse(action)) {
+                (Some(gesture), Some(action)) => {
+                    bindings.actions.insert(gesture, action);
+                }
+                (None, _) => warn!("Unknown CapsLock gesture `{}`", gesture),
+                (_, None) => warn!("Unknown action `{}` for `{}`", action, gesture),
+            }
+        }
+
+        bindings
+    }
+
+    fn single_tap(&self) -> KeyAction {
+        self.actions
+            .get(&Gesture::Tap(1))
+            .copied()
+            .unwrap_or(KeyAction::Switch)
+    }
+
+    pub fn actions(&self, gesture: Gesture) -> Vec<KeyAction> {
+        match (self.actions.get(&gesture), gesture) {
+            (Some(&action), _) => vec![action],
+            (None, Gesture::Tap(taps)) => vec![self.single_tap(); usize::from(taps)],
+            (None, _) => vec![self.single_tap()],
+        }
+    }
+
+    /// Most taps in a row with a binding of their own
+    pub fn max_taps(&self) -> u8 {
+        (2..=MAX_TAPS)
+            .filter(|&taps| self.actions.contains_key(&Gesture::Tap(taps)))
+            .max()
+            .unwrap_or(1)
+    }
+
+    pub fn has_long_press(&self) -> bool {
+        self.actions.contains_key(&Gesture::LongPress)
+    }
+
+    /// Whether any gesture does `action`, including an unbound single tap
+    pub fn has_action(&self, action: KeyAction) -> bool {
+        self.single_tap() == action || self.actions.values().any(|&a| a == action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::capture;
+    use log::Level;
+
+    fn bindings(entries: &[(&str, &str)]) -> Bindings {
+        let entries = entries
+            .iter()
+            .map(|(gesture, action)| (gesture.to_string(), action.to_string()))
+            .collect();
+        Bindings::from_config(&entries)
+    }
+
+    #[test]
+    fn parses_actions() {
+        for action in KeyAction::ALL {
+            assert_eq!(KeyAction::parse(action.as_str()), Some(action));
+        }
+        assert_eq!(
+            KeyAction::parse(" Convert_Word "),
+            Some(KeyAction::ConvertWord)
+        );
+        assert_eq!(KeyAction::parse("convert"), None);
+    }
+
+    #[test]
+    fn has_action_includes_single_tap() {
+        assert!(bindings(&[]).has_action(KeyAction::Switch));
+        assert!(!bindings(&[]).has_action(KeyAction::ConvertWord));
+
+        let convert_tap = bindings(&[("tap", "convert_word")]);
+        assert!(convert_tap.has_action(KeyAction::ConvertWord));
+        assert!(!convert_tap.has_action(KeyAction::Switch));
+
+        let convert_double = bindings(&[("double_tap", "convert_word")]);
+        assert!(convert_double.has_action(KeyAction::ConvertWord));
+        assert_eq!(
+            convert_double.actions(Gesture::Tap(2)),
+            [KeyAction::ConvertWord]
+        );
+        assert_eq!(convert_double.max_taps(), 2);
+    }
+
+    #[test]
+    fn parses_modifier_chords() {
+        assert_eq!(Modifiers::parse("shift"), Some(Modifiers::SHIFT));
+        assert_eq!(
+            Modifiers::parse(" Ctrl + ALT "),
+            Some(Modifiers::CTRL.with(Modifiers::ALT, true))
+        );
+        assert_eq!(Modifiers::parse("alt+ctrl"), Modifiers::parse("ctrl+alt"));
+        assert_eq!(Modifiers::parse("ctrl+hyper"), None);
+        assert_eq!(Modifiers::parse("ctrl+"), None);
+    }
+
+    #[test]
+    fn parses_hotkeys() {
+        let ctrl_alt = Modifiers::CTRL.with(Modifiers::ALT, true);
+
+        assert_eq!(Modifiers::parse_hotkey("ctrl+alt"), Some(ctrl_alt));
+        assert_eq!(Modifiers::parse_hotkey("ctrl+alt+capslock"), Some(ctrl_alt));
+        assert_eq!(Modifiers::parse_hotkey("Ctrl+Alt+CapsLock"), Some(ctrl_alt));
+        assert_eq!(
+            Modifiers::parse_hotkey("  ctrl + alt +capslock  "),
+            Some(ctrl_alt)
+        );
+        assert_eq!(
+            Modifiers::parse_hotkey("win+CAPSLOCK"),
+            Some(Modifiers::WIN)
+        );
+    }
+
+    #[test]
+    fn rejects_hotkeys_without_modifiers() {
+        assert_eq!(Modifiers::parse_hotkey(""), None);
+        assert_eq!(Modifiers::parse_hotkey("   "), None);
+        assert_eq!(Modifiers::parse_hotkey("capslock"), None);
+        assert_eq!(Modifiers::parse_hotkey("+capslock"), None);
+        assert_eq!(Modifiers::parse_hotkey("hyper+capslock"), None);
+        assert_eq!(Modifiers::parse_hotkey("ctrl+capslock+alt"), None);
+    }
+
+    #[test]
+    fn binds_defaults() {
+        let defaults = bindings(&[]);
+
+        assert_eq!(defaults, Bindings::default());
+        assert_eq!(
+            defaults.actions(Gesture::Chord(Modifiers::SHIFT)),
+            [KeyAction::Caps]
+        );
+        assert_eq!(
+            defaults.actions(Gesture::Chord(Modifiers::CTRL)),
+            [KeyAction::Reverse]
+        );
+        assert_eq!(defaults.actions(Gesture::Tap(1)), [KeyAction::Switch]);
+        assert_eq!(defaults.max_taps(), 1);
+        assert!(!defaults.has_long_press());
+    }
+
+    #[test]
+    fn overrides_defaults_from_config() {
+        let custom = bindings(&[("shift", "previous"), ("long_press", "toggle_pause")]);
+
+        assert_eq!(
+            custom.actions(Gesture::Chord(Modifiers::SHIFT)),
+            [KeyAction::Previous]
+        );
+        assert_eq!(
+            custom.actions(Gesture::Chord(Modifiers::CTRL)),
+            [KeyAction::Reverse]
+        );
+        assert_eq!(custom.actions(Gesture::LongPress), [KeyAction::TogglePause]);
+        assert!(custom.has_long_press());
+    }
+
+    #[test]
+    fn ignores_and_logs_invalid_entries() {
+        capture::start();
+        let invalid = bindings(&[("hyper", "caps"), ("alt", "explode")]);
+
+        assert_eq!(invalid, Bindings::default());
+        let mut records = capture::take();
+        records.sort();
+        assert_eq!(
+            records,
+            [
+                (Level::Warn, "Unknown CapsLock gesture `hyper`".to_string()),
+                (
+                    Level::Warn,
+                    "Unknown action `explode` for `alt`".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_single_tap_without_binding() {
+        let defaults = bindings(&[]);
+        assert_eq!(
+            defaults.actions(Gesture::Chord(Modifiers::ALT)),
+            [KeyAction::Switch]
+        );
+        assert_eq!(defaults.actions(Gesture::LongPress), [KeyAction::Switch]);
+        assert_eq!(
+            defaults.actions(Gesture::Tap(3)),
+            [KeyAction::Switch, KeyAction::Switch, KeyAction::Switch]
+        );
+
+        let previous_tap = bindings(&[("tap", "previous")]);
+        assert_eq!(
+            previous_tap.actions(Gesture::Chord(Modifiers::WIN)),
+            [KeyAction::Previous]
+        );
+        assert_eq!(
+            previous_tap.actions(Gesture::Tap(2)),
+            [KeyAction::Previous, KeyAction::Previous]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::{fmt, str::FromStr};
+
+const CAPS_ARG_PREFIX: &str = "--caps=";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapsCommand {
+    On,
+    Off,
+    Toggle,
+}
+
+impl CapsCommand {
+    /// Reads the command from `--caps=<on|off|toggle>`, `Some(Err)` if the
+    /// argument is there but the command is unknown
+    pub fn from_args(args: &[String]) -> Option<Result<Self, ()>> {
+        args.iter()
+            .skip(1)
+            .find_map(|arg| arg.strip_prefix(CAPS_ARG_PREFIX))
+            .map(str::parse)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CapsCommand::On => "on",
+            CapsCommand::Off => "off",
+            CapsCommand::Toggle => "toggle",
+        }
+    }
+}
+
+impl fmt::Display for CapsCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CapsCommand {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [CapsCommand::On, CapsCommand::Off, CapsCommand::Toggle]
+            .into_iter()
+            .find(|command| command.as_str().eq_ignore_ascii_case(value.trim()))
+            .ok_or(())
+    }
+}
+
+/// CapsLock state as the keyboard hook sees it. Windows flips the lock on
+/// every CapsLock key down that reaches it, but not on autorepeats, and the
+/// hook swallows most of the real ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapsTracker {
+    is_on: bool,
+    is_down: bool,
+}
+
+impl CapsTracker {
+    pub fn new(is_on: bool) -> Self {
+        Self {
+            is_on,
+            is_down: false,
+        }
+    }
+
+    pub fn is_on(self) -> bool {
+        self.is_on
+    }
+
+    /// State after a CapsLock event the hook let through to the system
+    pub fn on_key(self, is_down: bool) -> Self {
+        Self {
+            is_on: self.is_on ^ (is_down && !self.is_down),
+            is_down,
+        }
+    }
+
+    /// Whether a CapsLock press is needed to carry out the command
+    pub fn needs_press(self, command: CapsCommand) -> bool {
+        match command {
+            CapsCommand::On => !self.is_on,
+            CapsCommand::Off => self.is_on,
+            CapsCommand::Toggle => true,
+        }
+    }
+
+    /// Packs the state to be stored in an atomic
+    pub fn bits(self) -> u8 {
+        u8::from(self.is_on) | u8::from(self.is_down) << 1
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self {
+            is_on: bits & 1 != 0,
+            is_down: bits & 2 != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMMANDS: [CapsCommand; 3] = [CapsCommand::On, CapsCommand::Off, CapsCommand::Toggle];
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_commands() {
+        for command in COMMANDS {
+            assert_eq!(command.as_str().parse(), Ok(command));
+            assert_eq!(command.to_string().to_uppercase().parse(), Ok(command));
+        }
+        assert_eq!(" off ".parse(), Ok(CapsCommand::Off));
+        assert_eq!("".parse::<CapsCommand>(), Err(()));
+        assert_eq!("maybe".parse::<CapsCommand>(), Err(()));
+    }
+
+    #[test]
+    fn reads_command_from_args() {
+        assert_eq!(
+            CapsCommand::from_args(&args(&["CapsWitch.exe", "--caps=off"])),
+            Some(Ok(CapsCommand::Off))
+        );
+        assert_eq!(
+            CapsCommand::from_args(&args(&["CapsWitch.exe", "--mode=mru", "--caps=toggle"])),
+            Some(Ok(CapsCommand::Toggle))
+        );
+        assert_eq!(
+            CapsCommand::from_args(&args(&["CapsWitch.exe", "--caps=maybe"])),
+            Some(Err(()))
+        );
+        assert_eq!(
+            CapsCommand::from_args(&args(&["CapsWitch.exe", "--caps"])),
+            None
+        );
+        assert_eq!(CapsCommand::from_args(&args(&["--caps=on"])), None);
+        assert_eq!(CapsCommand::from_args(&args(&["CapsWitch.exe"])), None);
+    }
+
+    #[test]
+    fn toggles_on_key_down() {
+        let tracker = CapsTracker::new(false).on_key(true);
+        assert!(tracker.is_on());
+
+        let tracker = tracker.on_key(false);
+        assert!(tracker.is_on());
+        assert!(!tracker.on_key(true).is_on());
+    }
+
+    #[test]
+    fn ignores_autorepeat() {
+        let mut tracker = CapsTracker::new(false);
+        for _ in 0..5 {
+            tracker = tracker.on_key(true);
+        }
+        assert!(tracker.is_on());
+
+        let tracker = tracker.on_key(false).on_key(true);
+        assert!(!tracker.is_on());
+    }
+
+    #[test]
+    fn ignores_key_up_without_down() {
+        let tracker = CapsTracker::new(true).on_key(false).on_key(false);
+
+        assert!(tracker.is_on());
+        assert_eq!(tracker, CapsTracker::new(true));
+    }
+
+    #[test]
+    fn needs_press_only_to_change_state() {
+        let off = CapsTracker::new(false);
+        let on = CapsTracker::new(true);
+
+        assert!(off.needs_press(CapsCommand::On));
+        assert!(!off.needs_press(CapsCommand::Off));
+        assert!(!on.needs_press(CapsCommand::On));
+        assert!(on.needs_press(CapsCommand::Off));
+        assert!(off.needs_press(CapsCommand::Toggle));
+        assert!(on.needs_press(CapsCommand::Toggle));
+    }
+
+    #[test]
+    fn round_trips_through_bits() {
+        for is_on in [false, true] {
+            for is_down in [false, true] {
+                let tracker = CapsTracker { is_on, is_down };
+                assert_eq!(CapsTracker::from_bits(tracker.bits()), tracker);
+            }
+        }
+        assert_eq!(CapsTracker::default().bits(), 0);
+        assert_eq!(CapsTracker::new(true).on_key(true).bits(), 0b10);
+    }
+
+    #[test]
+    fn keeps_autorepeat_across_bits() {
+        let tracker = CapsTracker::from_bits(CapsTracker::new(false).on_key(true).bits());
+
+        assert!(tracker.on_key(true).is_on());
+    }
+}
@@ -0,0 +1,237 @@
+use crate::constants::CONFIG_FILE_NAME;
+use log::warn;
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs, io::ErrorKind, path::PathBuf, sync::LazyLock};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub hooks: HooksConfig,
+    pub osd: OsdConfig,
+    pub led: LedConfig,
+    pub sound: SoundConfig,
+    pub diagnostics: DiagnosticsConfig,
+    pub switch: SwitchConfig,
+    pub elevated_helper: ElevatedHelperConfig,
+    pub caps: CapsConfig,
+    pub keys: KeysConfig,
+    #[cfg(not(windows))]
+    pub autoload: AutoloadConfig,
+}
+
+/// External commands run on application events. Every command is executed
+/// through `cmd /C`, so the value may contain arguments and redirections.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    pub on_layout_change: Option<String>,
+    pub on_pause: Option<String>,
+    pub on_resume: Option<String>,
+    pub on_mode_change: Option<String>,
+    pub timeout_ms: u64,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            on_layout_change: None,
+            on_pause: None,
+            on_resume: None,
+            on_mode_change: None,
+            timeout_ms: 5000,
+        }
+    }
+}
+
+/// On-screen indicator shown after a layout is switched with CapsLock
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct OsdConfig {
+    pub enabled: bool,
+    pub duration_ms: u32,
+    pub position: OsdPosition,
+    /// From 0.0 (transparent) to 1.0 (opaque)
+    pub opacity: f32,
+}
+
+impl Default for OsdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            duration_ms: 800,
+            position: OsdPosition::Center,
+            opacity: 0.85,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsdPosition {
+    /// Center of the screen with the active window
+    #[default]
+    Center,
+    /// Below the text cursor, falls back to the center if there is none
+    Caret,
+    Top,
+    Bottom,
+}
+
+/// CapsLock LED used as a layout indicator instead of the CapsLock state
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct LedConfig {
+    pub enabled: bool,
+    /// Layout with the LED off, e.g. "en-US:00000409", "00000409" or
+    /// "04090409". Defaults to the first installed layout.
+    pub primary_layout: Option<String>,
+}
+
+/// Start on login. Windows always uses the Run key of the registry.
+#[cfg(not(windows))]
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct AutoloadConfig {
+    pub method: AutostartMethod,
+}
+
+#[cfg(not(windows))]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutostartMethod {
+    /// `.desktop` file in the XDG autostart directory, which most desktop
+    /// environments start
+    #[default]
+    Xdg,
+    /// systemd user service started with the graphical session
+    Systemd,
+}
+
+/// Short tones played on layout switches, distinct for every layout
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct SoundConfig {
+    pub enabled: bool,
+    /// WAV files replacing the tones of particular layouts, e.g.
+    /// `"ru-RU:00000419" = "C:\\Sounds\\ru.wav"`
+    pub files: HashMap<String, PathBuf>,
+}
+
+/// How CapsLock activates layouts
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SwitchConfig {
+    /// Cycles layouts with a synthetic Win+Space if a layout can't be
+    /// activated directly. The keystroke may show the language flyout.
+    pub keystroke_fallback: bool,
+}
+
+impl Default for SwitchConfig {
+    fn default() -> Self {
+        Self {
+            keystroke_fallback: true,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CapsConfig {
+    /// Turns caps off after every switch with CapsLock
+    pub off_on_switch: bool,
+}
+
+/// What CapsLock does when pressed with modifiers, tapped repeatedly or
+/// held
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct KeysConfig {
+    /// Gestures and their actions on top of `shift = "caps"` and
+    /// `ctrl = "reverse"`, e.g. `alt = "caps"` or `double_tap = "caps"`
+    pub bindings: HashMap<String, String>,
+    /// Longest time from a release to the next press of a multi-tap
+    pub tap_interval_ms: u32,
+    /// Shortest hold of a long press
+    pub long_press_ms: u32,
+    /// Modifiers that pause and resume the app with CapsLock, e.g.
+    /// `ctrl+alt`. Works while paused, unlike the bindings.
+    pub pause_hotkey: Option<String>,
+}
+
+impl Default for KeysConfig {
+    fn default() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            tap_interval_ms: 300,
+            long_press_ms: 500,
+            pause_hotkey: None,
+        }
+    }
+}
+
+/// Companion process run as administrator, which switches layouts of
+/// elevated windows the app itself can't reach
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ElevatedHelperConfig {
+    pub enabled: bool,
+    pub launch: HelperLaunch,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HelperLaunch {
+    /// Registers a scheduled task on the first start, which starts the
+    /// helper at logon without a UAC prompt
+    #[default]
+    Task,
+    /// Asks with a UAC prompt every time the app starts
+    Prompt,
+}
+
+/// Contents of the diagnostics bundle saved from the tray
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct DiagnosticsConfig {
+    /// Window titles are redacted unless enabled
+    pub include_window_titles: bool,
+}
+
+impl Config {
+    fn load() -> Self {
+        let Some(path) = config_path() else {
+            return Self::default();
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Self::default(),
+            Err(err) => {
+                warn!("Failed to read config file {}: {}", path.display(), err);
+                return Self::default();
+            }
+        };
+
+        toml::from_str(&content).unwrap_or_else(|err| {
+            warn!("Failed to parse config file {}: {}", path.display(), err);
+            Self::default()
+        })
+    }
+}
+
+/// `%APPDATA%\CapsWitch`
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("APPDATA").map(|app_data| PathBuf::from(app_data).join(env!("CARGO_PKG_NAME")))
+}
+
+/// `%LOCALAPPDATA%\CapsWitch`, for data that is not worth roaming
+pub fn local_data_dir() -> Option<PathBuf> {
+    env::var_os("LOCALAPPDATA")
+        .map(|local_app_data| PathBuf::from(local_app_data).join(env!("CARGO_PKG_NAME")))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+pub static CONFIG: LazyLock<Config> = LazyLock::new(Config::load);
@@ -1,6 +1,36 @@
-use winreg::enums::HKEY_CURRENT_USER;
-use winreg::RegKey;
+#[cfg(windows)]
+use winreg::{
+    enums::{HKEY_CURRENT_USER, HKEY_LOCAL_MACHINE},
+    RegKey,
+};
 
+#[cfg(windows)]
 pub const HKCU: RegKey = RegKey::predef(HKEY_CURRENT_USER);
+#[cfg(windows)]
+pub const HKLM: RegKey = RegKey::predef(HKEY_LOCAL_MACHINE);
 
 pub const REG_RUN_PATH: &str = "SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Run";
+
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+pub const LAYOUT_NAMES_FILE_NAME: &str = "layouts.toml";
+
+pub const REG_SETTINGS_PATH: &str = "SOFTWARE\\CapsWitch";
+
+pub const REG_KEYBOARD_LAYOUTS_PATH: &str = "SYSTEM\\CurrentControlSet\\Control\\Keyboard Layouts";
+
+/// Time given to a window to apply a requested layout before it is queried
+pub const LAYOUT_SETTLE_DELAY_MS: u32 = 100;
+
+/// Longest wait for a window to answer a layout request. Requests are sent
+/// from the keyboard hook, which Windows removes if it takes too long.
+pub const LAYOUT_REQUEST_TIMEOUT_MS: u32 = 100;
+
+pub const LOG_FILE_NAME: &str = "CapsWitch.log";
+
+pub const HELPER_LOG_FILE_NAME: &str = "CapsWitch-helper.log";
+
+/// Argument that starts the elevated helper instead of the app
+pub const HELPER_ARG: &str = "--elevated-helper";
+
+pub const HELPER_TASK_NAME: &str = "CapsWitch Elevated Helper";
@@ -0,0 +1,145 @@
+use crate::AppState;
+use windows::Win32::UI::Input::KeyboardAndMouse::VK_CAPITAL;
+
+/// `dwExtraInfo` of the heartbeat key events injected by the watchdog
+pub const HEARTBEAT_MARKER: usize = 0x4357_4842;
+/// Marks CapsLock presses made by the app, which the hook lets through
+pub const CAPS_MARKER: usize = 0x4357_4350;
+
+/// Key event as the keyboard hook receives it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookKey {
+    pub vk_code: u32,
+    pub is_down: bool,
+    /// `dwExtraInfo` of the event, one of the markers for input of the app
+    pub extra_info: usize,
+}
+
+/// What the keyboard hook does with a key event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookDecision {
+    /// Heartbeat of the watchdog, which only the hook should see
+    SwallowHeartbeat,
+    /// CapsLock press of the pause hotkey, which was handled
+    SwallowHotkey,
+    /// CapsLock press that reaches the system and toggles caps
+    PassCaps,
+    TriggerDown,
+    TriggerUp,
+    Pass,
+}
+
+/// Decides what the hook does with a key event. `on_pause_hotkey` handles
+/// CapsLock presses the app didn't make and returns `true` if they were the
+/// pause hotkey.
+pub fn decide(
+    key: &HookKey,
+    state: &AppState,
+    on_pause_hotkey: impl FnOnce(bool) -> bool,
+) -> HookDecision {
+    if key.extra_info == HEARTBEAT_MARKER {
+        return HookDecision::SwallowHeartbeat;
+    }
+    if key.vk_code != u32::from(VK_CAPITAL.0) {
+        return HookDecision::Pass;
+    }
+
+    // Handled before the pause check, so the hotkey also resumes
+    if key.extra_info != CAPS_MARKER && on_pause_hotkey(key.is_down) {
+        return HookDecision::SwallowHotkey;
+    }
+    // CapsLock presses of the app, and all of them while paused, reach the
+    // system and toggle caps
+    if key.extra_info == CAPS_MARKER || state.is_paused() {
+        return HookDecision::PassCaps;
+    }
+
+    if key.is_down {
+        HookDecision::TriggerDown
+    } else {
+        HookDecision::TriggerUp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use windows::Win32::UI::Input::KeyboardAndMouse::VK_A;
+
+    fn key(vk_code: u16, is_down: bool, extra_info: usize) -> HookKey {
+        HookKey {
+            vk_code: u32::from(vk_code),
+            is_down,
+            extra_info,
+        }
+    }
+
+    fn state() -> AppState {
+        AppState::new(vec![String::from("CapsWitch")])
+    }
+
+    #[test]
+    fn swallows_heartbeats() {
+        let state = state();
+
+        for vk in [VK_A.0, VK_CAPITAL.0, 0xFC] {
+            let decision = decide(&key(vk, true, HEARTBEAT_MARKER), &state, |_| {
+                panic!("Heartbeats aren't hotkeys")
+            });
+            assert_eq!(decision, HookDecision::SwallowHeartbeat);
+        }
+    }
+
+    #[test]
+    fn passes_other_keys() {
+        let state = state();
+
+        let decision = decide(&key(VK_A.0, true, 0), &state, |_| panic!("Not CapsLock"));
+        assert_eq!(decision, HookDecision::Pass);
+        state.toggle_pause();
+        let decision = decide(&key(VK_A.0, false, 0), &state, |_| panic!("Not CapsLock"));
+        assert_eq!(decision, HookDecision::Pass);
+    }
+
+    #[test]
+    fn triggers_on_caps_lock() {
+        let state = state();
+
+        assert_eq!(
+            decide(&key(VK_CAPITAL.0, true, 0), &state, |_| false),
+            HookDecision::TriggerDown
+        );
+        assert_eq!(
+            decide(&key(VK_CAPITAL.0, false, 0), &state, |_| false),
+            HookDecision::TriggerUp
+        );
+    }
+
+    #[test]
+    fn passes_caps_lock_of_app_and_while_paused() {
+        let state = state();
+
+        let decision = decide(&key(VK_CAPITAL.0, true, CAPS_MARKER), &state, |_| {
+            panic!("Presses of the app aren't hotkeys")
+        });
+        assert_eq!(decision, HookDecision::PassCaps);
+
+        state.toggle_pause();
+        assert_eq!(
+            decide(&key(VK_CAPITAL.0, true, 0), &state, |_| false),
+            HookDecision::PassCaps
+        );
+    }
+
+    #[test]
+    fn swallows_pause_hotkey_while_paused() {
+        let state = state();
+        state.toggle_pause();
+
+        let decision = decide(&key(VK_CAPITAL.0, false, 0), &state, |is_down| {
+            assert!(!is_down);
+            true
+        });
+        assert_eq!(decision, HookDecision::SwallowHotkey);
+    }
+}
@@ -0,0 +1,137 @@
+mod bundle;
+
+use crate::autoload::{get_autostart, AutostartStatus};
+use crate::config::{config_path, local_data_dir, CONFIG};
+use crate::error::{CapsWitchError, Result};
+use crate::latency;
+use crate::layout_id::format_layout;
+use crate::logging::{format_timestamp, log_path};
+use crate::switch::{
+    get_hook_health, get_installed_layouts, get_layout_display_name, get_layout_id,
+    get_window_layout,
+};
+use crate::utils::get_process_path;
+use crate::APP_STATE;
+use bundle::{write_bundle, Diagnostics, LayoutInfo, WindowInfo};
+use std::{
+    fs::{self, File},
+    io,
+    path::PathBuf,
+    time::SystemTime,
+};
+use windows::Win32::{
+    Foundation::HWND,
+    UI::TextServices::HKL,
+    UI::WindowsAndMessaging::{GetClassNameW, GetWindowTextW, GetWindowThreadProcessId},
+};
+
+fn get_autostart_description() -> String {
+    let Some(autostart) = get_autostart() else {
+        return String::from("Unsupported");
+    };
+
+    match autostart.status() {
+        Ok(AutostartStatus::Enabled { exe, args }) => {
+            format!("Enabled: {} {}", exe.display(), args.join(" "))
+        }
+        Ok(AutostartStatus::Disabled) => String::from("Disabled"),
+        Err(err) => format!("Unknown: {}", err),
+    }
+}
+
+/// File name of the executable that owns the window
+fn get_window_process_name(hwnd: HWND) -> String {
+    let mut process_id: u32 = 0;
+    unsafe { GetWindowThreadProcessId(hwnd, Some(&mut process_id)) };
+
+    get_process_path(process_id)
+        .and_then(|path| {
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| format!("PID {}", process_id))
+}
+
+/// Handle and id of a layout, e.g. `04090409 en-US:00000409`
+fn format_layout_info(layout: &HKL) -> String {
+    format!("{} {}", format_layout(layout), get_layout_id(layout))
+}
+
+fn get_window_info(hwnd: HWND) -> Option<WindowInfo> {
+    if hwnd.0 == 0 {
+        return None;
+    }
+
+    let mut class = [0u16; 256];
+    let class_len = unsafe { GetClassNameW(hwnd, &mut class) };
+    let mut title = [0u16; 512];
+    let title_len = unsafe { GetWindowTextW(hwnd, &mut title) };
+    let layout = get_window_layout(hwnd);
+
+    Some(WindowInfo {
+        process: get_window_process_name(hwnd),
+        class: String::from_utf16_lossy(&class[..usize::try_from(class_len).unwrap_or(0)]),
+        title: String::from_utf16_lossy(&title[..usize::try_from(title_len).unwrap_or(0)]),
+        layout: format!(
+            "{} {}",
+            format_layout_info(&layout),
+            get_layout_display_name(&layout)
+        ),
+    })
+}
+
+/// Gathers the state of the app. `target` is the window that receives
+/// layout switches.
+fn collect(target: HWND) -> Diagnostics {
+    let excluded = APP_STATE.excluded_layouts();
+    let layouts = get_installed_layouts()
+        .iter()
+        .map(|layout| LayoutInfo {
+            id: format_layout_info(layout),
+            name: get_layout_display_name(layout),
+            is_excluded: excluded.contains(layout),
+        })
+        .collect();
+
+    Diagnostics {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created: format_timestamp(SystemTime::now()),
+        mode: APP_STATE.mode().as_str().to_string(),
+        is_paused: APP_STATE.is_paused(),
+        autostart: get_autostart_description(),
+        layouts,
+        foreground: get_window_info(target),
+        hook_health: get_hook_health(),
+        latency: latency::get_stats(),
+        config: config_path().and_then(|path| fs::read_to_string(path).ok()),
+        log: log_path().and_then(|path| fs::read_to_string(path).ok()),
+    }
+}
+
+/// Saves a diagnostics bundle to `%LOCALAPPDATA%\CapsWitch\Diagnostics` and
+/// returns its path
+pub fn save(target: HWND) -> Result<PathBuf> {
+    let diagnostics = collect(target);
+
+    let dir = local_data_dir()
+        .map(|dir| dir.join("Diagnostics"))
+        .ok_or_else(|| {
+            CapsWitchError::Diagnostics(io::Error::new(
+                io::ErrorKind::NotFound,
+                "No local application data folder",
+            ))
+        })?;
+    fs::create_dir_all(&dir).map_err(CapsWitchError::Diagnostics)?;
+
+    // Colons are not allowed in file names
+    let path = dir.join(format!(
+        "{}-diagnostics-{}Z.zip",
+        env!("CARGO_PKG_NAME"),
+        diagnostics.created[..19].replace(':', "-")
+    ));
+    let file = File::create(&path).map_err(CapsWitchError::Diagnostics)?;
+    write_bundle(&diagnostics, CONFIG.diagnostics.include_window_titles, file)
+        .map_err(CapsWitchError::Diagnostics)?;
+
+    Ok(path)
+}
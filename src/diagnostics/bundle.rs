@@ -0,0 +1,331 @@
+//! Diagnostics bundle built from already collected data, without touching
+//! the system, so what ends up in it is easy to review.
+
+use crate::watchdog::HookHealth;
+use std::{
+    fmt::Write as _,
+    io::{self, Seek, Write},
+};
+use zip::{write::SimpleFileOptions, ZipWriter};
+
+/// Number of the most recent log lines put in the bundle
+pub const LOG_TAIL_LINES: usize = 1000;
+
+pub struct LayoutInfo {
+    /// Formatted HKL, e.g. `04090409`
+    pub id: String,
+    pub name: String,
+    pub is_excluded: bool,
+}
+
+/// Window that received the last layout switch
+pub struct WindowInfo {
+    pub process: String,
+    pub class: String,
+    pub title: String,
+    pub layout: String,
+}
+
+pub struct Diagnostics {
+    pub version: String,
+    pub created: String,
+    pub mode: String,
+    pub is_paused: bool,
+    pub autostart: String,
+    pub layouts: Vec<LayoutInfo>,
+    pub foreground: Option<WindowInfo>,
+    pub hook_health: HookHealth,
+    /// Rendered latency histograms
+    pub latency: String,
+    /// Content of the config file, if there is one
+    pub config: Option<String>,
+    pub log: Option<String>,
+}
+
+/// Window titles may reveal document names or messages, so by default only
+/// their length is kept
+fn redact_title(title: &str, include_titles: bool) -> String {
+    if include_titles {
+        title.to_string()
+    } else {
+        format!("<redacted, {} chars>", title.chars().count())
+    }
+}
+
+/// Last `count` lines of `text`
+pub fn last_lines(text: &str, count: usize) -> &str {
+    if count == 0 {
+        return "";
+    }
+
+    match text
+        .trim_end_matches('\n')
+        .rmatch_indices('\n')
+        .nth(count - 1)
+    {
+        Some((i, _)) => &text[i + 1..],
+        None => text,
+    }
+}
+
+/// Human-readable overview, the `summary.txt` of the bundle
+pub fn render_summary(diagnostics: &Diagnostics, include_titles: bool) -> String {
+    let mut summary = String::new();
+    let health = &diagnostics.hook_health;
+
+    // Writing to a String never fails
+    let _ = writeln!(
+        summary,
+        "{} {}",
+        env!("CARGO_PKG_NAME"),
+        diagnostics.version
+    );
+    let _ = writeln!(summary, "Created: {}", diagnostics.created);
+    let _ = writeln!(summary, "Mode: {}", diagnostics.mode);
+    let _ = writeln!(summary, "Paused: {}", diagnostics.is_paused);
+    let _ = writeln!(summary, "Autostart: {}", diagnostics.autostart);
+
+    let _ = writeln!(summary, "\nKeyboard hook");
+    let _ = writeln!(summary, "  Installed: {}", health.is_installed);
+    let _ = writeln!(summary, "  Key events: {}", health.key_events);
+    let _ = writeln!(summary, "  Switches: {}", health.switches);
+    let _ = writeln!(summary, "  Errors: {}", health.errors);
+    let _ = writeln!(summary, "  Reinstalls: {}", health.reinstalls);
+
+    let _ = writeln!(summary, "\nInstalled layouts");
+    for layout in &diagnostics.layouts {
+        let excluded = if layout.is_excluded {
+            " (excluded from rotation)"
+        } else {
+            ""
+        };
+        let _ = writeln!(summary, "  {} {}{}", layout.id, layout.name, excluded);
+    }
+
+    let _ = writeln!(summary, "\nForeground window");
+    match &diagnostics.foreground {
+        Some(window) => {
+            let _ = writeln!(summary, "  Process: {}", window.process);
+            let _ = writeln!(summary, "  Class: {}", window.class);
+            let _ = writeln!(
+                summary,
+                "  Title: {}",
+                redact_title(&window.title, include_titles)
+            );
+            let _ = writeln!(summary, "  Layout: {}", window.layout);
+        }
+        None => {
+            let _ = writeln!(summary, "  None");
+        }
+    }
+
+    summary
+}
+
+/// Writes the bundle as a zip archive with the summary, latency stats, the
+/// config file and the end of the log
+pub fn write_bundle<W: Write + Seek>(
+    diagnostics: &Diagnostics,
+    include_titles: bool,
+    writer: W,
+) -> io::Result<W> {
+    let mut zip = ZipWriter::new(writer);
+    let options = SimpleFileOptions::default();
+
+    zip.start_file("summary.txt", options)?;
+    zip.write_all(render_summary(diagnostics, include_titles).as_bytes())?;
+
+    zip.start_file("latency.txt", options)?;
+    zip.write_all(diagnostics.latency.as_bytes())?;
+
+    if let Some(config) = &diagnostics.config {
+        zip.start_file("config.toml", options)?;
+        zip.write_all(config.as_bytes())?;
+    }
+
+    if let Some(log) = &diagnostics.log {
+        zip.start_file("log.txt", options)?;
+        zip.write_all(last_lines(log, LOG_TAIL_LINES).as_bytes())?;
+    }
+
+    Ok(zip.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use zip::ZipArchive;
+
+    const TITLE: &str = "Secret plans.docx — Word";
+
+    fn diagnostics() -> Diagnostics {
+        Diagnostics {
+            version: String::from("1.2.3"),
+            created: String::from("2024-05-01 12:00:00"),
+            mode: String::from("circular"),
+            is_paused: false,
+            autostart: String::from("Disabled"),
+            layouts: vec![
+                LayoutInfo {
+                    id: String::from("04090409"),
+                    name: String::from("English — US"),
+                    is_excluded: false,
+                },
+                LayoutInfo {
+                    id: String::from("04190419"),
+                    name: String::from("Russian"),
+                    is_excluded: true,
+                },
+            ],
+            foreground: Some(WindowInfo {
+                process: String::from("WINWORD.EXE"),
+                class: String::from("OpusApp"),
+                title: String::from(TITLE),
+                layout: String::from("04090409 en-US:00000409"),
+            }),
+            hook_health: HookHealth {
+                is_installed: true,
+                key_events: 42,
+                ..Default::default()
+            },
+            latency: String::from("switch: p50 1 ms"),
+            config: Some(String::from("[osd]\nenabled = true\n")),
+            log: Some(String::from("first\nsecond\nthird\n")),
+        }
+    }
+
+    fn read_bundle(diagnostics: &Diagnostics, include_titles: bool) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer =
+            write_bundle(diagnostics, include_titles, Cursor::new(Vec::new())).unwrap();
+        writer.set_position(0);
+
+        ZipArchive::new(writer).unwrap()
+    }
+
+    fn file_names(archive: &ZipArchive<Cursor<Vec<u8>>>) -> Vec<String> {
+        let mut names: Vec<String> = archive
+            .file_names()
+            .map(|name| name.unwrap().into_owned())
+            .collect();
+        names.sort_unstable();
+
+        names
+    }
+
+    fn read_file(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> String {
+        let mut content = String::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+
+        content
+    }
+
+    #[test]
+    fn takes_last_lines() {
+        assert_eq!(last_lines("a\nb\nc", 2), "b\nc");
+        assert_eq!(last_lines("a\nb\nc", 1), "c");
+        assert_eq!(last_lines("a\nb\nc", 3), "a\nb\nc");
+    }
+
+    #[test]
+    fn takes_no_lines_for_zero_count() {
+        assert_eq!(last_lines("a\nb\nc\n", 0), "");
+        assert_eq!(last_lines("", 0), "");
+    }
+
+    #[test]
+    fn keeps_trailing_newline() {
+        assert_eq!(last_lines("a\nb\nc\n", 2), "b\nc\n");
+        assert_eq!(last_lines("a\nb\nc\n", 1), "c\n");
+        assert_eq!(last_lines("a\nb\n\n\n", 1), "b\n\n\n");
+    }
+
+    #[test]
+    fn takes_whole_text_with_fewer_lines() {
+        assert_eq!(last_lines("a\nb\n", 5), "a\nb\n");
+        assert_eq!(last_lines("only", 5), "only");
+        assert_eq!(last_lines("", 5), "");
+    }
+
+    #[test]
+    fn redacts_titles() {
+        assert_eq!(redact_title(TITLE, true), TITLE);
+        assert_eq!(redact_title(TITLE, false), "<redacted, 24 chars>");
+        assert_eq!(redact_title("", false), "<redacted, 0 chars>");
+    }
+
+    #[test]
+    fn bundles_all_files() {
+        let mut archive = read_bundle(&diagnostics(), false);
+
+        assert_eq!(
+            file_names(&archive),
+            ["config.toml", "latency.txt", "log.txt", "summary.txt"]
+        );
+        assert_eq!(read_file(&mut archive, "latency.txt"), "switch: p50 1 ms");
+        assert_eq!(
+            read_file(&mut archive, "config.toml"),
+            "[osd]\nenabled = true\n"
+        );
+        assert_eq!(read_file(&mut archive, "log.txt"), "first\nsecond\nthird\n");
+    }
+
+    #[test]
+    fn leaves_out_missing_config_and_log() {
+        let diagnostics = Diagnostics {
+            config: None,
+            log: None,
+            ..diagnostics()
+        };
+        let archive = read_bundle(&diagnostics, false);
+
+        assert_eq!(file_names(&archive), ["latency.txt", "summary.txt"]);
+    }
+
+    #[test]
+    fn bundles_only_log_tail() {
+        let log: String = (0..LOG_TAIL_LINES + 10)
+            .map(|i| format!("line {}\n", i))
+            .collect();
+        let diagnostics = Diagnostics {
+            log: Some(log),
+            ..diagnostics()
+        };
+
+        let bundled = read_file(&mut read_bundle(&diagnostics, false), "log.txt");
+        assert_eq!(bundled.lines().count(), LOG_TAIL_LINES);
+        assert!(bundled.starts_with("line 10\n"));
+    }
+
+    #[test]
+    fn redacts_title_in_bundle() {
+        let summary = read_file(&mut read_bundle(&diagnostics(), false), "summary.txt");
+
+        assert!(!summary.contains("Secret"));
+        assert!(summary.contains("  Title: <redacted, 24 chars>\n"));
+        assert!(summary.contains("  Process: WINWORD.EXE\n"));
+        assert!(summary.contains("  04190419 Russian (excluded from rotation)\n"));
+        assert!(summary.contains("  Key events: 42\n"));
+    }
+
+    #[test]
+    fn includes_title_when_asked() {
+        let summary = read_file(&mut read_bundle(&diagnostics(), true), "summary.txt");
+
+        assert!(summary.contains(&format!("  Title: {}\n", TITLE)));
+    }
+
+    #[test]
+    fn summarizes_missing_foreground_window() {
+        let diagnostics = Diagnostics {
+            foreground: None,
+            ..diagnostics()
+        };
+
+        assert!(render_summary(&diagnostics, false).contains("Foreground window\n  None\n"));
+    }
+}
@@ -0,0 +1,102 @@
+use std::{fmt, io};
+#[cfg(windows)]
+use {
+    log::error,
+    windows::{
+        core::HSTRING,
+        Win32::UI::WindowsAndMessaging::{MessageBoxW, MB_ICONERROR, MB_ICONWARNING, MB_OK},
+    },
+};
+
+/// Errors of the application.
+///
+/// Recovery depends on where they happen: the keyboard hook logs them and
+/// lets the key through or swallows it, but never unwinds; actions started
+/// from the tray menu are logged and shown to the user; errors that leave
+/// the app without its hook or tray are shown to the user and end the process.
+#[derive(Debug)]
+pub enum CapsWitchError {
+    AnotherInstance,
+    // Win32 errors only happen on Windows, other builds stay free of Win32
+    // calls that can't be linked there
+    /// The named mutex that detects other instances couldn't be created
+    #[cfg(windows)]
+    InstanceMutex(windows::core::Error),
+    #[cfg(windows)]
+    HookInstall(windows::core::Error),
+    #[cfg(windows)]
+    HookRemove(windows::core::Error),
+    #[cfg(windows)]
+    SendInput(windows::core::Error),
+    Icon(String),
+    Tray(String),
+    Autoload(io::Error),
+    Settings(&'static str, io::Error),
+    Diagnostics(io::Error),
+    Helper(io::Error),
+    /// A command from the command line couldn't be carried out by the
+    /// running app
+    Command(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, CapsWitchError>;
+
+impl fmt::Display for CapsWitchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AnotherInstance => {
+                write!(f, "Another instance of the application is already running.")
+            }
+            #[cfg(windows)]
+            Self::InstanceMutex(err) => write!(f, "Failed to check for another instance: {}", err),
+            #[cfg(windows)]
+            Self::HookInstall(err) => write!(f, "Failed to install keyboard hook: {}", err),
+            #[cfg(windows)]
+            Self::HookRemove(err) => write!(f, "Failed to remove keyboard hook: {}", err),
+            #[cfg(windows)]
+            Self::SendInput(err) => write!(f, "Failed to send keyboard input: {}", err),
+            Self::Icon(err) => write!(f, "Failed to create tray icon: {}", err),
+            Self::Tray(err) => write!(f, "Failed to create tray menu: {}", err),
+            Self::Autoload(err) => write!(f, "Failed to change autoload: {}", err),
+            Self::Settings(name, err) => write!(f, "Failed to save `{}` setting: {}", name, err),
+            Self::Diagnostics(err) => write!(f, "Failed to save diagnostics: {}", err),
+            Self::Helper(err) => write!(f, "Elevated helper failed: {}", err),
+            Self::Command(err) => write!(f, "Command failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CapsWitchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(windows)]
+            Self::InstanceMutex(err)
+            | Self::HookInstall(err)
+            | Self::HookRemove(err)
+            | Self::SendInput(err) => Some(err),
+            Self::Autoload(err)
+            | Self::Settings(_, err)
+            | Self::Diagnostics(err)
+            | Self::Helper(err)
+            | Self::Command(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Logs the error and shows it in a message box. Blocks until the box is
+/// closed, so it is not to be called from the keyboard hook.
+#[cfg(windows)]
+pub fn notify(err: &CapsWitchError) {
+    error!("{}", err);
+
+    let style = match err {
+        CapsWitchError::AnotherInstance => MB_OK | MB_ICONWARNING,
+        _ => MB_OK | MB_ICONERROR,
+    };
+    let text = HSTRING::from(err.to_string());
+    let caption = HSTRING::from(env!("CARGO_PKG_NAME"));
+    unsafe {
+        MessageBoxW(None, &text, &caption, style);
+    }
+}
@@ -0,0 +1,515 @@
+use crate::bindings::Modifiers;
+use crate::watchdog::{is_later_by, Clock, Tick};
+
+/// Most taps in a row the recognizer tells apart
+pub const MAX_TAPS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Gesture {
+    /// CapsLock pressed with the modifiers held
+    Chord(Modifiers),
+    /// CapsLock pressed and released the number of times in a row
+    Tap(u8),
+    /// CapsLock held on its own
+    LongPress,
+}
+
+impl Gesture {
+    /// Parses a config key, e.g. `alt`, `ctrl+shift`, `double_tap` or
+    /// `long_press`
+    pub fn parse(value: &str) -> Option<Self> {
+        let gesture = match value.trim().to_ascii_lowercase().as_str() {
+            "tap" => Gesture::Tap(1),
+            "double_tap" => Gesture::Tap(2),
+            "triple_tap" => Gesture::Tap(3),
+            "long_press" => Gesture::LongPress,
+            chord => Modifiers::parse(chord)
+                .filter(|modifiers| !modifiers.is_empty())
+                .map(Gesture::Chord)?,
+        };
+
+        Some(gesture)
+    }
+}
+
+/// Which gestures the recognizer waits for, and for how long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GestureTimings {
+    /// Taps up to this count are told apart, from 1 to `MAX_TAPS`
+    pub max_taps: u8,
+    /// Longest time from a release to the next press of the same gesture
+    pub tap_interval_ms: u32,
+    /// Shortest hold of a long press, `None` if long presses aren't bound
+    pub long_press_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Pressed {
+        since: Tick,
+        taps: u8,
+    },
+    Released {
+        since: Tick,
+        taps: u8,
+    },
+    /// The gesture was recognized while the key is still down
+    Recognized,
+}
+
+/// Turns CapsLock presses and releases into gestures. Each press starts or
+/// extends a gesture that is only recognized once no longer one is
+/// possible, so a single tap waits for the tap interval if more taps are
+/// bound. Autorepeated presses are ignored.
+///
+/// `poll` has to be called at the `deadline` for gestures recognized by
+/// time alone.
+pub struct Recognizer<C: Clock> {
+    clock: C,
+    timings: GestureTimings,
+    state: State,
+}
+
+impl<C: Clock> Recognizer<C> {
+    pub fn new(clock: C, timings: GestureTimings) -> Self {
+        Self {
+            clock,
+            timings: GestureTimings {
+                max_taps: timings.max_taps.clamp(1, MAX_TAPS),
+                ..timings
+            },
+            state: State::Idle,
+        }
+    }
+
+    /// Whether any gesture but a single tap can be recognized. Otherwise
+    /// every press is a single tap as soon as it's made.
+    pub fn is_needed(timings: &GestureTimings) -> bool {
+        timings.max_taps > 1 || timings.long_press_ms.is_some()
+    }
+
+    fn can_long_press(&self, taps: u8) -> bool {
+        taps == 1 && self.timings.long_press_ms.is_some()
+    }
+
+    pub fn key_down(&mut self) -> Vec<Gesture> {
+        let mut gestures: Vec<Gesture> = self.poll().into_iter().collect();
+        let now = self.clock.now();
+
+        let taps = match self.state {
+            State::Idle => 1,
+            State::Released { taps, .. } => taps + 1,
+            State::Pressed { .. } | State::Recognized => return gestures,
+        };
+        if taps >= self.timings.max_taps && !self.can_long_press(taps) {
+            gestures.push(Gesture::Tap(taps));
+            self.state = State::Recognized;
+        } else {
+            self.state = State::Pressed { since: now, taps };
+        }
+
+        gestures
+    }
+
+    pub fn key_up(&mut self) -> Vec<Gesture> {
+        let mut gestures: Vec<Gesture> = self.poll().into_iter().collect();
+        let now = self.clock.now();
+
+        match self.state {
+            State::Pressed { taps, .. } if taps >= self.timings.max_taps => {
+                gestures.push(Gesture::Tap(taps));
+                self.state = State::Idle;
+            }
+            State::Pressed { taps, .. } => self.state = State::Released { since: now, taps },
+            State::Recognized => self.state = State::Idle,
+            State::Idle | State::Released { .. } => {}
+        }
+
+        gestures
+    }
+
+    /// Recognizes a gesture whose time ran out
+    pub fn poll(&mut self) -> Option<Gesture> {
+        let now = self.clock.now();
+
+        match self.state {
+            State::Pressed { since, taps } if self.can_long_press(taps) => {
+                let long_press_ms = self.timings.long_press_ms?;
+                if !is_later_by(now, since, long_press_ms) {
+                    return None;
+                }
+                self.state = State::Recognized;
+                Some(Gesture::LongPress)
+            }
+            State::Released { since, taps } => {
+                if !is_later_by(now, since, self.timings.tap_interval_ms) {
+                    return None;
+                }
+                self.state = State::Idle;
+                Some(Gesture::Tap(taps))
+            }
+            _ => None,
+        }
+    }
+
+    /// Tick at which `poll` may recognize a gesture
+    pub fn deadline(&self) -> Option<Tick> {
+        match self.state {
+            State::Pressed { since, taps } if self.can_long_press(taps) => self
+                .timings
+                .long_press_ms
+                .map(|ms| since.wrapping_add(ms).wrapping_add(1)),
+            State::Released { since, .. } => Some(
+                since
+                    .wrapping_add(self.timings.tap_interval_ms)
+                    .wrapping_add(1),
+            ),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    const TAP_INTERVAL_MS: u32 = 300;
+    const LONG_PRESS_MS: u32 = 500;
+
+    struct FakeClock(Cell<Tick>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Tick {
+            self.0.get()
+        }
+    }
+
+    fn recognizer_at(start: Tick, max_taps: u8, long_press: bool) -> Recognizer<FakeClock> {
+        Recognizer::new(
+            FakeClock(Cell::new(start)),
+            GestureTimings {
+                max_taps,
+                tap_interval_ms: TAP_INTERVAL_MS,
+                long_press_ms: long_press.then_some(LONG_PRESS_MS),
+            },
+        )
+    }
+
+    fn recognizer(max_taps: u8, long_press: bool) -> Recognizer<FakeClock> {
+        recognizer_at(1000, max_taps, long_press)
+    }
+
+    fn advance(recognizer: &Recognizer<FakeClock>, ms: u32) {
+        let clock = &recognizer.clock.0;
+        clock.set(clock.get().wrapping_add(ms));
+    }
+
+    /// Releases the key and presses it again `ms` later
+    fn tap_again(recognizer: &mut Recognizer<FakeClock>, ms: u32) -> Vec<Gesture> {
+        let mut gestures = recognizer.key_up();
+        advance(recognizer, ms);
+        gestures.extend(recognizer.key_down());
+        gestures
+    }
+
+    #[test]
+    fn parses_gestures() {
+        assert_eq!(Gesture::parse("tap"), Some(Gesture::Tap(1)));
+        assert_eq!(Gesture::parse(" Double_Tap "), Some(Gesture::Tap(2)));
+        assert_eq!(Gesture::parse("triple_tap"), Some(Gesture::Tap(3)));
+        assert_eq!(Gesture::parse("long_press"), Some(Gesture::LongPress));
+        assert_eq!(
+            Gesture::parse("ctrl+shift"),
+            Some(Gesture::Chord(Modifiers::CTRL.with(Modifiers::SHIFT, true)))
+        );
+        assert_eq!(Gesture::parse("quadruple_tap"), None);
+        assert_eq!(Gesture::parse(""), None);
+        assert_eq!(Gesture::parse("ctrl+"), None);
+    }
+
+    #[test]
+    fn is_needed_only_for_multi_taps_and_long_presses() {
+        let timings = |max_taps, long_press_ms| GestureTimings {
+            max_taps,
+            tap_interval_ms: TAP_INTERVAL_MS,
+            long_press_ms,
+        };
+
+        assert!(!Recognizer::<FakeClock>::is_needed(&timings(1, None)));
+        assert!(Recognizer::<FakeClock>::is_needed(&timings(2, None)));
+        assert!(Recognizer::<FakeClock>::is_needed(&timings(1, Some(500))));
+    }
+
+    #[test]
+    fn max_taps_are_clamped() {
+        assert_eq!(recognizer(0, false).timings.max_taps, 1);
+        assert_eq!(recognizer(5, false).timings.max_taps, MAX_TAPS);
+    }
+
+    #[test]
+    fn single_tap_is_immediate_without_other_gestures() {
+        let mut recognizer = recognizer(1, false);
+
+        assert_eq!(recognizer.key_down(), [Gesture::Tap(1)]);
+        assert_eq!(recognizer.deadline(), None);
+        assert_eq!(recognizer.key_up(), []);
+        assert_eq!(recognizer.key_down(), [Gesture::Tap(1)]);
+    }
+
+    #[test]
+    fn single_tap_waits_for_tap_interval() {
+        let mut recognizer = recognizer(2, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(recognizer.deadline(), None);
+        assert_eq!(recognizer.key_up(), []);
+        assert_eq!(recognizer.deadline(), Some(1000 + TAP_INTERVAL_MS + 1));
+
+        advance(&recognizer, TAP_INTERVAL_MS);
+        assert_eq!(recognizer.poll(), None);
+        advance(&recognizer, 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::Tap(1)));
+        assert_eq!(recognizer.poll(), None);
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn double_tap_at_edge_of_tap_interval() {
+        let mut recognizer = recognizer(2, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(
+            tap_again(&mut recognizer, TAP_INTERVAL_MS),
+            [Gesture::Tap(2)]
+        );
+        // Released after being recognized
+        assert_eq!(recognizer.key_up(), []);
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn two_taps_just_past_tap_interval() {
+        let mut recognizer = recognizer(2, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(
+            tap_again(&mut recognizer, TAP_INTERVAL_MS + 1),
+            [Gesture::Tap(1)]
+        );
+        assert_eq!(recognizer.key_up(), []);
+        advance(&recognizer, TAP_INTERVAL_MS + 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::Tap(1)));
+    }
+
+    #[test]
+    fn double_tap_waits_when_triple_taps_are_bound() {
+        let mut recognizer = recognizer(3, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, TAP_INTERVAL_MS), []);
+        assert_eq!(recognizer.key_up(), []);
+
+        advance(&recognizer, TAP_INTERVAL_MS);
+        assert_eq!(recognizer.poll(), None);
+        advance(&recognizer, 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::Tap(2)));
+    }
+
+    #[test]
+    fn triple_tap_at_edge_of_tap_interval() {
+        let mut recognizer = recognizer(3, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, TAP_INTERVAL_MS), []);
+        assert_eq!(
+            tap_again(&mut recognizer, TAP_INTERVAL_MS),
+            [Gesture::Tap(3)]
+        );
+        assert_eq!(recognizer.key_up(), []);
+    }
+
+    #[test]
+    fn third_tap_past_tap_interval_starts_over() {
+        let mut recognizer = recognizer(3, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, TAP_INTERVAL_MS), []);
+        assert_eq!(
+            tap_again(&mut recognizer, TAP_INTERVAL_MS + 1),
+            [Gesture::Tap(2)]
+        );
+        assert_eq!(tap_again(&mut recognizer, 10), []);
+        assert_eq!(tap_again(&mut recognizer, 10), [Gesture::Tap(3)]);
+    }
+
+    #[test]
+    fn tap_interval_counts_from_release() {
+        let mut recognizer = recognizer(2, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        // A slow press is still a tap without long presses bound
+        advance(&recognizer, LONG_PRESS_MS * 2);
+        assert_eq!(recognizer.poll(), None);
+        assert_eq!(
+            tap_again(&mut recognizer, TAP_INTERVAL_MS),
+            [Gesture::Tap(2)]
+        );
+    }
+
+    #[test]
+    fn long_press_without_multi_taps() {
+        let mut recognizer = recognizer(1, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(recognizer.deadline(), Some(1000 + LONG_PRESS_MS + 1));
+        advance(&recognizer, LONG_PRESS_MS);
+        assert_eq!(recognizer.poll(), None);
+        advance(&recognizer, 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::LongPress));
+        assert_eq!(recognizer.deadline(), None);
+        assert_eq!(recognizer.key_up(), []);
+    }
+
+    #[test]
+    fn short_press_is_a_tap_on_release_without_multi_taps() {
+        let mut recognizer = recognizer(1, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        advance(&recognizer, LONG_PRESS_MS);
+        assert_eq!(recognizer.key_up(), [Gesture::Tap(1)]);
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn long_press_with_multi_taps() {
+        let mut recognizer = recognizer(2, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        advance(&recognizer, LONG_PRESS_MS + 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::LongPress));
+        assert_eq!(recognizer.key_up(), []);
+        // Nothing is pending after the release
+        advance(&recognizer, TAP_INTERVAL_MS + 1);
+        assert_eq!(recognizer.poll(), None);
+    }
+
+    #[test]
+    fn short_press_waits_for_taps_with_long_press_bound() {
+        let mut recognizer = recognizer(2, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        advance(&recognizer, LONG_PRESS_MS);
+        assert_eq!(recognizer.key_up(), []);
+        advance(&recognizer, TAP_INTERVAL_MS + 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::Tap(1)));
+    }
+
+    #[test]
+    fn only_the_first_press_can_be_long() {
+        let mut recognizer = recognizer(3, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, 10), []);
+        // The second press is held, it's still counted as a tap
+        assert_eq!(recognizer.deadline(), None);
+        advance(&recognizer, LONG_PRESS_MS * 2);
+        assert_eq!(recognizer.poll(), None);
+        assert_eq!(tap_again(&mut recognizer, 10), [Gesture::Tap(3)]);
+    }
+
+    #[test]
+    fn key_down_recognizes_expired_gesture_first() {
+        let mut recognizer = recognizer(2, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        advance(&recognizer, LONG_PRESS_MS + 1);
+        // The poll was missed, the long press comes with the next event
+        assert_eq!(recognizer.key_up(), [Gesture::LongPress]);
+        assert_eq!(recognizer.key_down(), []);
+    }
+
+    #[test]
+    fn autorepeat_while_pressed_is_ignored() {
+        let mut recognizer = recognizer(2, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        for _ in 0..10 {
+            advance(&recognizer, 30);
+            assert_eq!(recognizer.key_down(), []);
+        }
+        // The press still counts from the first key down
+        assert_eq!(recognizer.deadline(), Some(1000 + LONG_PRESS_MS + 1));
+        advance(&recognizer, LONG_PRESS_MS + 1 - 300);
+        assert_eq!(recognizer.key_down(), [Gesture::LongPress]);
+        for _ in 0..10 {
+            advance(&recognizer, 30);
+            assert_eq!(recognizer.key_down(), []);
+        }
+        assert_eq!(recognizer.key_up(), []);
+    }
+
+    #[test]
+    fn autorepeat_after_immediate_tap_is_ignored() {
+        let mut recognizer = recognizer(1, false);
+
+        assert_eq!(recognizer.key_down(), [Gesture::Tap(1)]);
+        for _ in 0..10 {
+            advance(&recognizer, 30);
+            assert_eq!(recognizer.key_down(), []);
+        }
+        assert_eq!(recognizer.key_up(), []);
+    }
+
+    #[test]
+    fn autorepeat_of_last_tap_is_ignored() {
+        let mut recognizer = recognizer(2, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, 10), [Gesture::Tap(2)]);
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(recognizer.key_up(), []);
+    }
+
+    #[test]
+    fn release_without_press_is_ignored() {
+        let mut recognizer = recognizer(2, true);
+
+        assert_eq!(recognizer.key_up(), []);
+        assert_eq!(recognizer.poll(), None);
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn taps_across_tick_wrap_around() {
+        let mut recognizer = recognizer_at(Tick::MAX - 100, 3, false);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(tap_again(&mut recognizer, TAP_INTERVAL_MS), []);
+        assert_eq!(recognizer.key_up(), []);
+        assert_eq!(
+            recognizer.deadline(),
+            Some((Tick::MAX - 100).wrapping_add(2 * TAP_INTERVAL_MS + 1))
+        );
+        advance(&recognizer, TAP_INTERVAL_MS);
+        assert_eq!(recognizer.poll(), None);
+        advance(&recognizer, 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::Tap(2)));
+    }
+
+    #[test]
+    fn long_press_across_tick_wrap_around() {
+        let mut recognizer = recognizer_at(Tick::MAX - 10, 1, true);
+
+        assert_eq!(recognizer.key_down(), []);
+        assert_eq!(
+            recognizer.deadline(),
+            Some((Tick::MAX - 10).wrapping_add(LONG_PRESS_MS + 1))
+        );
+        advance(&recognizer, LONG_PRESS_MS);
+        assert_eq!(recognizer.poll(), None);
+        advance(&recognizer, 1);
+        assert_eq!(recognizer.poll(), Some(Gesture::LongPress));
+    }
+}
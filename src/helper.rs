@@ -0,0 +1,707 @@
+mod protocol;
+
+use crate::caps::{CapsCommand, CapsTracker};
+use crate::config::{HelperLaunch, CONFIG};
+use crate::constants::{HELPER_ARG, HELPER_TASK_NAME};
+use crate::error::{CapsWitchError, Result};
+use crate::latency;
+use crate::layout_id::LayoutId;
+use crate::switch::{
+    self, change_window_layout, get_installed_layouts, get_layout_id, press_caps_lock,
+};
+use crate::utils::get_process_path;
+use log::{debug, error, info, warn};
+use protocol::{
+    check_peer, is_in_protected_dir, Action, Peer, Request, Response, Session, MAX_LINE_LEN,
+    MAX_RESPONSE_LEN,
+};
+use std::{
+    env,
+    ffi::c_void,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    iter, mem,
+    os::windows::{
+        io::{AsRawHandle, FromRawHandle},
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process::{Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        LazyLock, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use windows::{
+    core::{GUID, HSTRING, PWSTR},
+    Win32::{
+        Foundation::{
+            CloseHandle, LocalFree, ERROR_ACCESS_DENIED, ERROR_PIPE_CONNECTED, HANDLE, HLOCAL,
+            HWND, INVALID_HANDLE_VALUE, LRESULT,
+        },
+        Security::{
+            Authorization::{
+                ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW,
+                SDDL_REVISION_1,
+            },
+            GetTokenInformation, TokenElevation, TokenUser, PSECURITY_DESCRIPTOR,
+            SECURITY_ATTRIBUTES, TOKEN_ELEVATION, TOKEN_QUERY, TOKEN_USER,
+        },
+        Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX},
+        System::{
+            Com::CoTaskMemFree,
+            Pipes::{
+                ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe,
+                GetNamedPipeClientProcessId, GetNamedPipeServerProcessId,
+                PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_WAIT,
+            },
+            RemoteDesktop::ProcessIdToSessionId,
+            Threading::{
+                GetCurrentProcess, GetCurrentProcessId, OpenProcess, OpenProcessToken,
+                CREATE_NO_WINDOW, PROCESS_QUERY_LIMITED_INFORMATION,
+            },
+        },
+        UI::{
+            Input::KeyboardAndMouse::{GetKeyState, VK_CAPITAL},
+            Shell::{
+                FOLDERID_ProgramFiles, FOLDERID_ProgramFilesX86, SHGetKnownFolderPath,
+                ShellExecuteW, KF_FLAG_DEFAULT,
+            },
+            TextServices::HKL,
+            WindowsAndMessaging::{GetForegroundWindow, GetWindowThreadProcessId, SW_HIDE},
+        },
+    },
+};
+
+const PIPE_BUFFER_SIZE: u32 = 4096;
+/// Time the helper has to come up after it's launched, which includes the
+/// user answering the UAC prompt
+const LAUNCH_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+enum Job {
+    Activate(HWND, HKL),
+    Caps(CapsCommand),
+}
+
+static SENDER: OnceLock<Sender<Job>> = OnceLock::new();
+
+static IS_ELEVATED: LazyLock<bool> =
+    LazyLock::new(|| is_process_elevated(unsafe { GetCurrentProcess() }).unwrap_or(false));
+
+pub fn is_helper_process(args: &[String]) -> bool {
+    args.iter().any(|arg| arg == HELPER_ARG)
+}
+
+fn get_session_id(process_id: u32) -> Option<u32> {
+    let mut session_id = 0;
+    unsafe { ProcessIdToSessionId(process_id, &mut session_id) }.ok()?;
+
+    Some(session_id)
+}
+
+fn get_peer(process_id: u32) -> Option<Peer> {
+    Some(Peer {
+        executable: get_process_path(process_id)?,
+        session_id: get_session_id(process_id)?,
+    })
+}
+
+fn get_own_peer() -> Option<Peer> {
+    get_peer(unsafe { GetCurrentProcessId() })
+}
+
+/// Pipe the helper listens on
+const HELPER_PIPE: &str = "elevated";
+/// Pipe the app itself listens on for commands from the command line
+const COMMAND_PIPE: &str = "commands";
+
+/// One pipe per session, as every user runs their own app and helper
+fn get_pipe_name(pipe: &str) -> Option<String> {
+    let session_id = get_session_id(unsafe { GetCurrentProcessId() })?;
+
+    Some(format!(
+        r"\\.\pipe\{}-{}-{}",
+        env!("CARGO_PKG_NAME"),
+        pipe,
+        session_id
+    ))
+}
+
+fn is_process_elevated(process: HANDLE) -> Option<bool> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(process, TOKEN_QUERY, &mut token) }.ok()?;
+
+    let mut elevation = TOKEN_ELEVATION::default();
+    let mut len = 0;
+    let result = unsafe {
+        GetTokenInformation(
+            token,
+            TokenElevation,
+            Some(&mut elevation as *mut _ as *mut c_void),
+            mem::size_of::<TOKEN_ELEVATION>() as u32,
+            &mut len,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(token);
+    }
+
+    result.ok().map(|_| elevation.TokenIsElevated != 0)
+}
+
+/// Whether the window belongs to a process the app can't send messages to.
+/// Processes that can't even be queried are assumed to be elevated.
+pub fn is_out_of_reach(window: HWND) -> bool {
+    if *IS_ELEVATED {
+        return false;
+    }
+
+    let mut process_id = 0;
+    unsafe { GetWindowThreadProcessId(window, Some(&mut process_id)) };
+    let Ok(process) =
+        (unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id) })
+    else {
+        return true;
+    };
+    let is_elevated = is_process_elevated(process);
+    unsafe {
+        let _ = CloseHandle(process);
+    }
+
+    is_elevated.unwrap_or(true)
+}
+
+// Helper side
+
+/// SDDL string of the current user, e.g. `S-1-5-21-...`
+fn get_user_sid() -> io::Result<String> {
+    let mut token = HANDLE::default();
+    unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }
+        .map_err(io::Error::other)?;
+
+    let mut len = 0;
+    let _ = unsafe { GetTokenInformation(token, TokenUser, None, 0, &mut len) };
+    // u64 keeps the buffer aligned for TOKEN_USER
+    let mut buffer = vec![0u64; (len as usize).div_ceil(mem::size_of::<u64>())];
+    let result = unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            Some(buffer.as_mut_ptr() as *mut c_void),
+            len,
+            &mut len,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(token);
+    }
+    result.map_err(io::Error::other)?;
+
+    let user = unsafe { &*(buffer.as_ptr() as *const TOKEN_USER) };
+    let mut sid = PWSTR::null();
+    unsafe { ConvertSidToStringSidW(user.User.Sid, &mut sid) }.map_err(io::Error::other)?;
+    let sid_string = unsafe { sid.to_string() }.map_err(io::Error::other);
+    unsafe {
+        let _ = LocalFree(HLOCAL(sid.0 as *mut c_void));
+    }
+
+    sid_string
+}
+
+/// Creates the only instance of the pipe. Only the current user may connect,
+/// and the medium integrity label lets the non-elevated app write to it.
+fn create_pipe(name: &str) -> io::Result<HANDLE> {
+    let sddl = format!(
+        "D:P(A;;GA;;;SY)(A;;GRGW;;;{})S:(ML;;NW;;;ME)",
+        get_user_sid()?
+    );
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            &HSTRING::from(sddl),
+            SDDL_REVISION_1,
+            &mut descriptor,
+            None,
+        )
+    }
+    .map_err(io::Error::other)?;
+
+    let attributes = SECURITY_ATTRIBUTES {
+        nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        lpSecurityDescriptor: descriptor.0,
+        bInheritHandle: false.into(),
+    };
+    let pipe = unsafe {
+        CreateNamedPipeW(
+            &HSTRING::from(name),
+            PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+            PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            1,
+            PIPE_BUFFER_SIZE,
+            PIPE_BUFFER_SIZE,
+            0,
+            Some(&attributes),
+        )
+    };
+    let last_error = io::Error::last_os_error();
+    unsafe {
+        let _ = LocalFree(HLOCAL(descriptor.0));
+    }
+
+    if pipe == INVALID_HANDLE_VALUE {
+        return Err(last_error);
+    }
+
+    Ok(pipe)
+}
+
+fn activate(window: u64, layout: &LayoutId) -> Response {
+    let Some(hkl) = get_installed_layouts()
+        .into_iter()
+        .find(|hkl| get_layout_id(hkl) == *layout)
+    else {
+        return Response::Error(format!("Layout {} is not installed", layout));
+    };
+
+    match change_window_layout(HWND(window as isize), &hkl) {
+        Some(LRESULT(0)) => Response::Ok,
+        Some(_) => Response::Error(String::from("Window rejected the layout")),
+        None => Response::Error(String::from("Window didn't answer")),
+    }
+}
+
+/// Presses CapsLock for the foreground window if the command needs it.
+/// Injected input reaches elevated windows only from elevated processes.
+fn set_caps(command: CapsCommand) -> Response {
+    let is_on = unsafe { GetKeyState(i32::from(VK_CAPITAL.0)) } & 1 != 0;
+    if !CapsTracker::new(is_on).needs_press(command) {
+        return Response::Ok;
+    }
+
+    match press_caps_lock() {
+        Ok(_) => Response::Ok,
+        Err(err) => Response::Error(err.to_string()),
+    }
+}
+
+/// Answers the requests of a connected client until it disconnects. `run`
+/// carries out the actions that aren't plain replies.
+fn serve(pipe: &File, own: &Peer, run: &impl Fn(Action) -> Response) -> io::Result<()> {
+    let mut client_id = 0;
+    unsafe { GetNamedPipeClientProcessId(HANDLE(pipe.as_raw_handle() as isize), &mut client_id) }
+        .map_err(io::Error::other)?;
+    let client =
+        get_peer(client_id).ok_or_else(|| io::Error::other("Client process can't be queried"))?;
+    let mut writer = pipe;
+    if let Err(denied) = check_peer(own, &client) {
+        warn!(
+            "Refused pipe client {}: {}",
+            client.executable.display(),
+            denied
+        );
+        return writeln!(writer, "{}", Response::Error(denied.to_string()).encode());
+    }
+
+    let mut session = Session::default();
+    let mut reader = BufReader::new(pipe);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if (&mut reader)
+            .take(MAX_LINE_LEN as u64 + 1)
+            .read_line(&mut line)?
+            == 0
+        {
+            return Ok(());
+        }
+
+        let response = match Request::decode(&line) {
+            Ok(request) => {
+                let foreground = unsafe { GetForegroundWindow() }.0 as u64;
+                match session.handle(request, foreground) {
+                    Action::Reply(response) => response,
+                    action => run(action),
+                }
+            }
+            Err(err) => Response::Error(err.to_string()),
+        };
+        if let Response::Error(message) = &response {
+            debug!("Request `{}` failed: {}", line.trim_end(), message);
+        }
+        writeln!(writer, "{}", response.encode())?;
+    }
+}
+
+fn get_known_folder(id: &GUID) -> Option<PathBuf> {
+    let path = unsafe { SHGetKnownFolderPath(id, KF_FLAG_DEFAULT, None) }.ok()?;
+    let result = unsafe { path.to_string() }.ok().map(PathBuf::from);
+    unsafe { CoTaskMemFree(Some(path.0 as *const c_void)) };
+
+    result
+}
+
+/// Folders only administrators can write to, which packaged apps in
+/// `WindowsApps` are inside of too
+fn get_protected_dirs() -> Vec<PathBuf> {
+    [FOLDERID_ProgramFiles, FOLDERID_ProgramFilesX86]
+        .iter()
+        .filter_map(get_known_folder)
+        .collect()
+}
+
+/// Registers the helper to start elevated at logon without a UAC prompt, or
+/// removes the registration if it's to be started with a prompt. The task
+/// is only registered for an executable users can't replace, otherwise any
+/// process of the user could get itself started elevated.
+fn sync_task(launch: HelperLaunch) {
+    let exe = env::current_exe().ok();
+    let launch = match (launch, &exe) {
+        (HelperLaunch::Task, Some(exe)) if !is_in_protected_dir(exe, &get_protected_dirs()) => {
+            warn!(
+                "Not registering the helper task, {} is not installed in Program Files",
+                exe.display()
+            );
+            HelperLaunch::Prompt
+        }
+        _ => launch,
+    };
+
+    let mut command = Command::new("schtasks");
+    match launch {
+        HelperLaunch::Task => {
+            let Some(exe) = exe else {
+                return;
+            };
+            command
+                .args(["/Create", "/F", "/SC", "ONLOGON", "/RL", "HIGHEST", "/TN"])
+                .arg(HELPER_TASK_NAME)
+                .raw_arg(format!("/TR \"\\\"{}\\\" {}\"", exe.display(), HELPER_ARG));
+        }
+        HelperLaunch::Prompt => {
+            command.args(["/Delete", "/F", "/TN"]).arg(HELPER_TASK_NAME);
+        }
+    }
+
+    let status = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .creation_flags(CREATE_NO_WINDOW.0)
+        .status();
+    match (launch, status) {
+        (HelperLaunch::Task, Ok(status)) if !status.success() => {
+            warn!("Failed to register the helper task: {}", status)
+        }
+        (_, Err(err)) => warn!("Failed to run schtasks: {}", err),
+        _ => {}
+    }
+}
+
+/// Serves clients of the pipe one connection at a time. Fails with
+/// `ERROR_ACCESS_DENIED` if another process already listens on it.
+fn listen(name: &str, own: &Peer, run: impl Fn(Action) -> Response) -> io::Result<()> {
+    loop {
+        let pipe = create_pipe(name)?;
+        match unsafe { ConnectNamedPipe(pipe, None) } {
+            Ok(_) => {}
+            Err(err) if err.code() == ERROR_PIPE_CONNECTED.to_hresult() => {}
+            Err(err) => {
+                unsafe {
+                    let _ = CloseHandle(pipe);
+                }
+                return Err(io::Error::other(err));
+            }
+        }
+
+        let file = unsafe { File::from_raw_handle(pipe.0 as _) };
+        if let Err(err) = serve(&file, own, &run) {
+            debug!("Pipe connection closed: {}", err);
+        }
+        unsafe {
+            let _ = DisconnectNamedPipe(pipe);
+        }
+    }
+}
+
+fn is_access_denied(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(ERROR_ACCESS_DENIED.0 as i32)
+}
+
+/// Runs the elevated helper, serving the app one connection at a time
+pub fn run_server() -> Result<()> {
+    if !*IS_ELEVATED {
+        return Err(CapsWitchError::Helper(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "The helper has to run as administrator",
+        )));
+    }
+    let (Some(own), Some(name)) = (get_own_peer(), get_pipe_name(HELPER_PIPE)) else {
+        return Err(CapsWitchError::Helper(io::Error::last_os_error()));
+    };
+    sync_task(CONFIG.elevated_helper.launch);
+
+    let result = listen(&name, &own, |action| match action {
+        Action::Reply(response) => response,
+        Action::Activate { window, layout } => activate(window, &layout),
+        Action::SetCaps(command) => set_caps(command),
+        Action::SendStats => Response::Error(String::from("Stats are kept by the app")),
+    });
+    match result {
+        Err(err) if is_access_denied(&err) => {
+            info!("Another helper is already running");
+            Ok(())
+        }
+        result => result.map_err(CapsWitchError::Helper),
+    }
+}
+
+// App side
+
+struct Connection {
+    reader: BufReader<File>,
+    writer: File,
+}
+
+impl Connection {
+    /// Connects to the pipe, making sure the process listening on it is the
+    /// app's own executable
+    fn open(pipe: &str) -> io::Result<Self> {
+        let name = get_pipe_name(pipe).ok_or_else(io::Error::last_os_error)?;
+        let writer = OpenOptions::new().read(true).write(true).open(name)?;
+
+        let mut server_id = 0;
+        unsafe {
+            GetNamedPipeServerProcessId(HANDLE(writer.as_raw_handle() as isize), &mut server_id)
+        }
+        .map_err(io::Error::other)?;
+        let (Some(own), Some(server)) = (get_own_peer(), get_peer(server_id)) else {
+            return Err(io::Error::other("Server process can't be queried"));
+        };
+        check_peer(&own, &server).map_err(|denied| io::Error::other(denied.to_string()))?;
+
+        let mut connection = Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        };
+        connection.request(&Request::Hello {
+            version: protocol::PROTOCOL_VERSION,
+        })?;
+
+        Ok(connection)
+    }
+
+    /// Sends the request, returning the text the server answered with, if
+    /// any
+    fn request(&mut self, request: &Request) -> io::Result<String> {
+        writeln!(self.writer, "{}", request.encode())?;
+
+        let mut line = String::new();
+        if (&mut self.reader)
+            .take(MAX_RESPONSE_LEN as u64 + 1)
+            .read_line(&mut line)?
+            == 0
+        {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        match Response::decode(&line).map_err(io::Error::other)? {
+            Response::Ok => Ok(String::new()),
+            Response::Text(text) => Ok(text),
+            Response::Error(message) => Err(io::Error::other(message)),
+        }
+    }
+}
+
+/// Starts the helper through its task, or with a UAC prompt if there is none
+fn launch(launch: HelperLaunch) -> bool {
+    if launch == HelperLaunch::Task {
+        let status = Command::new("schtasks")
+            .args(["/Run", "/TN", HELPER_TASK_NAME])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .creation_flags(CREATE_NO_WINDOW.0)
+            .status();
+        if status.is_ok_and(|status| status.success()) {
+            return true;
+        }
+    }
+
+    let Ok(exe) = env::current_exe() else {
+        return false;
+    };
+    let instance = unsafe {
+        ShellExecuteW(
+            HWND(0),
+            &HSTRING::from("runas"),
+            &HSTRING::from(exe.as_path()),
+            &HSTRING::from(HELPER_ARG),
+            None,
+            SW_HIDE,
+        )
+    };
+
+    // Values up to 32 are errors, e.g. the prompt was declined
+    instance.0 > 32
+}
+
+fn connect_or_launch() -> Option<Connection> {
+    match Connection::open(HELPER_PIPE) {
+        Ok(connection) => return Some(connection),
+        Err(err) => debug!("Elevated helper is not running: {}", err),
+    }
+
+    info!("Launching elevated helper");
+    if !launch(CONFIG.elevated_helper.launch) {
+        warn!("Failed to launch elevated helper");
+        return None;
+    }
+
+    let started = Instant::now();
+    while started.elapsed() < LAUNCH_TIMEOUT {
+        thread::sleep(CONNECT_INTERVAL);
+        if let Ok(connection) = Connection::open(HELPER_PIPE) {
+            return Some(connection);
+        }
+    }
+    warn!("Elevated helper didn't start");
+
+    None
+}
+
+/// Keeps the last request of every kind. Requests queued while the helper
+/// was launched are outdated but for those.
+fn latest_jobs(first: Job, rx: &Receiver<Job>) -> Vec<Job> {
+    let mut activate = None;
+    let mut caps = None;
+    for job in iter::once(first).chain(rx.try_iter()) {
+        match job {
+            Job::Activate(..) => activate = Some(job),
+            Job::Caps(_) => caps = Some(job),
+        }
+    }
+
+    activate.into_iter().chain(caps).collect()
+}
+
+fn run_client(rx: Receiver<Job>) {
+    // The helper is only launched once, so declining the prompt is final
+    // until the app is restarted
+    let mut connection = connect_or_launch();
+
+    while let Ok(first) = rx.recv() {
+        for job in latest_jobs(first, &rx) {
+            if connection.is_none() {
+                connection = Connection::open(HELPER_PIPE).ok();
+            }
+            let Some(current) = connection.as_mut() else {
+                warn!("Elevated helper is not available");
+                continue;
+            };
+
+            let request = match job {
+                Job::Activate(window, layout) => Request::Activate {
+                    window: window.0 as u64,
+                    layout: get_layout_id(&layout),
+                },
+                Job::Caps(command) => Request::Caps { command },
+            };
+            match current.request(&request) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::Other => {
+                    warn!("Elevated helper failed `{}`: {}", request.encode(), err)
+                }
+                Err(err) => {
+                    error!("Lost connection to elevated helper: {}", err);
+                    connection = None;
+                }
+            }
+        }
+    }
+}
+
+fn send_if_out_of_reach(window: HWND, job: Job) -> bool {
+    let Some(sender) = SENDER.get() else {
+        return false;
+    };
+
+    is_out_of_reach(window) && sender.send(job).is_ok()
+}
+
+/// Hands the layout request to the elevated helper if the window is out of
+/// reach of the app. Returns `false` if the app has to make it itself.
+pub fn activate_for(window: HWND, layout: HKL) -> bool {
+    send_if_out_of_reach(window, Job::Activate(window, layout))
+}
+
+/// Hands the caps command to the elevated helper if the window is out of
+/// reach of the app. Returns `false` if the app has to carry it out itself.
+pub fn set_caps_for(window: HWND, command: CapsCommand) -> bool {
+    send_if_out_of_reach(window, Job::Caps(command))
+}
+
+/// Connects to the elevated helper, launching it if needed, if it is enabled
+pub fn start() {
+    if !CONFIG.elevated_helper.enabled || *IS_ELEVATED {
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    if SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || run_client(rx));
+}
+
+// Commands
+
+/// Carries out a command sent with `--caps`, for the foreground window
+fn run_command(action: Action) -> Response {
+    match action {
+        Action::Reply(response) => response,
+        Action::SetCaps(command) => match switch::set_caps(command) {
+            Ok(_) => Response::Ok,
+            Err(err) => Response::Error(err.to_string()),
+        },
+        Action::SendStats => Response::Text(latency::get_stats()),
+        Action::Activate { .. } => {
+            Response::Error(String::from("Layouts can't be activated by commands"))
+        }
+    }
+}
+
+/// Listens for commands from other runs of the app, e.g. `--caps=off` from
+/// a script or a shortcut, or `--stats`
+pub fn start_commands() {
+    let (Some(own), Some(name)) = (get_own_peer(), get_pipe_name(COMMAND_PIPE)) else {
+        warn!(
+            "Failed to listen for commands: {}",
+            io::Error::last_os_error()
+        );
+        return;
+    };
+
+    thread::spawn(move || {
+        if let Err(err) = listen(&name, &own, run_command) {
+            warn!("Stopped listening for commands: {}", err);
+        }
+    });
+}
+
+/// Has the running app turn caps on or off in the foreground window
+pub fn send_caps_command(command: CapsCommand) -> Result<()> {
+    Connection::open(COMMAND_PIPE)
+        .and_then(|mut connection| connection.request(&Request::Caps { command }))
+        .map(|_| ())
+        .map_err(CapsWitchError::Command)
+}
+
+/// Latency stats of the running app, as `render_stats` shows them
+pub fn request_stats() -> Result<String> {
+    Connection::open(COMMAND_PIPE)
+        .and_then(|mut connection| connection.request(&Request::Stats))
+        .map_err(CapsWitchError::Command)
+}
@@ -0,0 +1,654 @@
+//! Line-based protocol between the app and its elevated helper, and the
+//! rules the helper applies before acting on a request. Platform-neutral,
+//! windows are passed around as plain numbers.
+
+use crate::autoload::is_same_path;
+use crate::caps::CapsCommand;
+use crate::layout_id::LayoutId;
+use std::{
+    fmt,
+    path::{Component, Path, PathBuf},
+};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Longer lines are rejected, so a client can't make the helper buffer
+/// unbounded input
+pub const MAX_LINE_LEN: usize = 1024;
+/// Responses come from an already checked server and may carry a whole
+/// table, e.g. the latency stats
+pub const MAX_RESPONSE_LEN: usize = 16 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Has to come first, with the version the client speaks
+    Hello {
+        version: u32,
+    },
+    Ping,
+    /// Activates the layout in the window, which has to be in the foreground
+    Activate {
+        window: u64,
+        layout: LayoutId,
+    },
+    /// Turns caps on or off for the foreground window
+    Caps {
+        command: CapsCommand,
+    },
+    /// Asks for the latency stats of the running app
+    Stats,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Error(String),
+    /// Output of a command, which may span several lines
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    Empty,
+    TooLong,
+    UnknownCommand(String),
+    MissingArgument(&'static str),
+    InvalidArgument(&'static str),
+    UnexpectedArgument,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "Empty message"),
+            Self::TooLong => write!(f, "Message is too long"),
+            Self::UnknownCommand(command) => write!(f, "Unknown command `{}`", command),
+            Self::MissingArgument(name) => write!(f, "Missing `{}`", name),
+            Self::InvalidArgument(name) => write!(f, "Invalid `{}`", name),
+            Self::UnexpectedArgument => write!(f, "Too many arguments"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+fn check_len(line: &str, max_len: usize) -> Result<&str, ProtocolError> {
+    if line.len() > max_len {
+        return Err(ProtocolError::TooLong);
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']))
+}
+
+/// Puts text on one line, escaping line breaks and backslashes
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn unescape(escaped: &str) -> Result<String, ProtocolError> {
+    let mut text = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => text.push('\\'),
+            Some('n') => text.push('\n'),
+            Some('r') => text.push('\r'),
+            _ => return Err(ProtocolError::InvalidArgument("text")),
+        }
+    }
+
+    Ok(text)
+}
+
+impl Request {
+    /// One line without the line break, e.g. `ACTIVATE 1a2b en-US:00000409`
+    pub fn encode(&self) -> String {
+        match self {
+            Self::Hello { version } => format!("HELLO {}", version),
+            Self::Ping => String::from("PING"),
+            Self::Activate { window, layout } => format!("ACTIVATE {:x} {}", window, layout),
+            Self::Caps { command } => format!("CAPS {}", command),
+            Self::Stats => String::from("STATS"),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Self, ProtocolError> {
+        let mut words = check_len(line, MAX_LINE_LEN)?.split_whitespace();
+        let command = words.next().ok_or(ProtocolError::Empty)?;
+
+        let request = match command {
+            "HELLO" => Self::Hello {
+                version: words
+                    .next()
+                    .ok_or(ProtocolError::MissingArgument("version"))?
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidArgument("version"))?,
+            },
+            "PING" => Self::Ping,
+            "ACTIVATE" => {
+                let window = words
+                    .next()
+                    .ok_or(ProtocolError::MissingArgument("window"))?;
+                let layout = words
+                    .next()
+                    .ok_or(ProtocolError::MissingArgument("layout"))?;
+                Self::Activate {
+                    window: u64::from_str_radix(window, 16)
+                        .map_err(|_| ProtocolError::InvalidArgument("window"))?,
+                    layout: layout
+                        .parse()
+                        .map_err(|_| ProtocolError::InvalidArgument("layout"))?,
+                }
+            }
+            "CAPS" => Self::Caps {
+                command: words
+                    .next()
+                    .ok_or(ProtocolError::MissingArgument("state"))?
+                    .parse()
+                    .map_err(|_| ProtocolError::InvalidArgument("state"))?,
+            },
+            "STATS" => Self::Stats,
+            _ => return Err(ProtocolError::UnknownCommand(command.to_string())),
+        };
+        if words.next().is_some() {
+            return Err(ProtocolError::UnexpectedArgument);
+        }
+
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> String {
+        match self {
+            Self::Ok => String::from("OK"),
+            // Messages are kept on one line
+            Self::Error(message) => format!("ERROR {}", message.replace(['\r', '\n'], " ")),
+            Self::Text(text) => format!("TEXT {}", escape(text)),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Self, ProtocolError> {
+        let line = check_len(line, MAX_RESPONSE_LEN)?;
+        match line.split_once(' ').unwrap_or((line, "")) {
+            ("OK", "") => Ok(Self::Ok),
+            ("OK", _) => Err(ProtocolError::UnexpectedArgument),
+            ("ERROR", message) => Ok(Self::Error(message.to_string())),
+            ("TEXT", text) => unescape(text).map(Self::Text),
+            ("", _) => Err(ProtocolError::Empty),
+            (command, _) => Err(ProtocolError::UnknownCommand(command.to_string())),
+        }
+    }
+}
+
+/// Process on either end of the pipe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub executable: PathBuf,
+    pub session_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Denied {
+    /// Only the app itself may use the helper, not any process of the user
+    ForeignExecutable,
+    /// Only the desktop session the helper runs in may use it
+    OtherSession,
+    NoHello,
+    UnsupportedVersion(u32),
+    /// Windows other than the foreground one are left alone, so the helper
+    /// can't be used to tamper with arbitrary elevated windows
+    NotForeground,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ForeignExecutable => write!(f, "Client is not {}", env!("CARGO_PKG_NAME")),
+            Self::OtherSession => write!(f, "Client is in another session"),
+            Self::NoHello => write!(f, "HELLO expected first"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            Self::NotForeground => write!(f, "Window is not in the foreground"),
+        }
+    }
+}
+
+/// Whether `exe` is inside one of `dirs`, which only administrators can
+/// write to. Relative paths and ones that climb out with `..` never are.
+pub fn is_in_protected_dir(exe: &Path, dirs: &[PathBuf]) -> bool {
+    exe.is_absolute()
+        && !exe.components().any(|c| c == Component::ParentDir)
+        && exe
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| dirs.iter().any(|dir| is_same_path(ancestor, dir)))
+}
+
+/// Checks that `other` is another process of the app in the same session
+pub fn check_peer(own: &Peer, other: &Peer) -> Result<(), Denied> {
+    if !is_same_path(&own.executable, &other.executable) {
+        return Err(Denied::ForeignExecutable);
+    }
+    if own.session_id != other.session_id {
+        return Err(Denied::OtherSession);
+    }
+
+    Ok(())
+}
+
+/// What the helper has to do for a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Reply(Response),
+    Activate { window: u64, layout: LayoutId },
+    SetCaps(CapsCommand),
+    SendStats,
+}
+
+impl From<Denied> for Action {
+    fn from(denied: Denied) -> Self {
+        Self::Reply(Response::Error(denied.to_string()))
+    }
+}
+
+/// Connection of a client the helper has accepted with `check_peer`
+#[derive(Debug, Default)]
+pub struct Session {
+    is_greeted: bool,
+}
+
+impl Session {
+    /// Decides on a request. `foreground` is the window in the foreground
+    /// when the request arrived.
+    pub fn handle(&mut self, request: Request, foreground: u64) -> Action {
+        match request {
+            Request::Hello { version } if version == PROTOCOL_VERSION => {
+                self.is_greeted = true;
+                Action::Reply(Response::Ok)
+            }
+            Request::Hello { version } => Denied::UnsupportedVersion(version).into(),
+            _ if !self.is_greeted => Denied::NoHello.into(),
+            Request::Ping => Action::Reply(Response::Ok),
+            Request::Activate { window, .. } if window != foreground => {
+                Denied::NotForeground.into()
+            }
+            Request::Activate { window, layout } => Action::Activate { window, layout },
+            Request::Caps { command } => Action::SetCaps(command),
+            Request::Stats => Action::SendStats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout_id::LayoutVariant;
+
+    const WINDOW: u64 = 0x1a2b;
+
+    #[cfg(windows)]
+    const PROGRAM_FILES: &str = r"C:\Program Files";
+    #[cfg(not(windows))]
+    const PROGRAM_FILES: &str = "/opt";
+
+    fn us() -> LayoutId {
+        LayoutId::new("en-US", LayoutVariant::Klid(0x0409))
+    }
+
+    fn peer(executable: &str, session_id: u32) -> Peer {
+        Peer {
+            executable: PathBuf::from(executable),
+            session_id,
+        }
+    }
+
+    fn greeted() -> Session {
+        let mut session = Session::default();
+        session.handle(
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+            },
+            0,
+        );
+        session
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Hello {
+                version: PROTOCOL_VERSION,
+            },
+            Request::Ping,
+            Request::Activate {
+                window: WINDOW,
+                layout: us(),
+            },
+            Request::Activate {
+                window: u64::MAX,
+                layout: LayoutId::new("und", LayoutVariant::from_xkb("us(dvorak)").unwrap()),
+            },
+            Request::Caps {
+                command: CapsCommand::On,
+            },
+            Request::Caps {
+                command: CapsCommand::Off,
+            },
+            Request::Caps {
+                command: CapsCommand::Toggle,
+            },
+            Request::Stats,
+        ];
+
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()), Ok(request.clone()));
+            assert_eq!(
+                Request::decode(&format!("{}\r\n", request.encode())),
+                Ok(request)
+            );
+        }
+        assert_eq!(
+            Request::Activate {
+                window: WINDOW,
+                layout: us(),
+            }
+            .encode(),
+            "ACTIVATE 1a2b en-US:00000409"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(Request::decode(""), Err(ProtocolError::Empty));
+        assert_eq!(Request::decode("\r\n"), Err(ProtocolError::Empty));
+        assert_eq!(
+            Request::decode("ping"),
+            Err(ProtocolError::UnknownCommand(String::from("ping")))
+        );
+        assert_eq!(
+            Request::decode("HELLO"),
+            Err(ProtocolError::MissingArgument("version"))
+        );
+        assert_eq!(
+            Request::decode("HELLO one"),
+            Err(ProtocolError::InvalidArgument("version"))
+        );
+        assert_eq!(
+            Request::decode("ACTIVATE 1a2b"),
+            Err(ProtocolError::MissingArgument("layout"))
+        );
+        assert_eq!(
+            Request::decode("ACTIVATE xyz en-US:00000409"),
+            Err(ProtocolError::InvalidArgument("window"))
+        );
+        assert_eq!(
+            Request::decode("ACTIVATE 1a2b en-US"),
+            Err(ProtocolError::InvalidArgument("layout"))
+        );
+        assert_eq!(
+            Request::decode("CAPS maybe"),
+            Err(ProtocolError::InvalidArgument("state"))
+        );
+        assert_eq!(
+            Request::decode("PING PONG"),
+            Err(ProtocolError::UnexpectedArgument)
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            Response::Ok,
+            Response::Error(String::from("Window is not in the foreground")),
+            Response::Error(String::new()),
+            Response::Text(String::from("Hook entry to decision: no events\n\n")),
+            Response::Text(String::from(r"C:\Users\n\r")),
+            Response::Text(String::new()),
+        ] {
+            assert_eq!(Response::decode(&response.encode()), Ok(response.clone()));
+            assert_eq!(
+                Response::decode(&format!("{}\n", response.encode())),
+                Ok(response)
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_error_responses_on_one_line() {
+        let response = Response::Error(String::from("first\r\nsecond"));
+
+        assert!(!response.encode().contains(['\r', '\n']));
+        assert_eq!(
+            Response::decode(&response.encode()),
+            Ok(Response::Error(String::from("first  second")))
+        );
+    }
+
+    #[test]
+    fn keeps_text_responses_on_one_line() {
+        let response = Response::Text(String::from("p50 ≤ 50 µs\r\n  > 250.0 ms: 1\n"));
+
+        assert_eq!(response.encode(), r"TEXT p50 ≤ 50 µs\r\n  > 250.0 ms: 1\n");
+        assert_eq!(Response::decode(&response.encode()), Ok(response));
+    }
+
+    #[test]
+    fn rejects_invalid_escapes() {
+        for line in [r"TEXT a\tb", r"TEXT trailing\"] {
+            assert_eq!(
+                Response::decode(line),
+                Err(ProtocolError::InvalidArgument("text"))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_responses() {
+        assert_eq!(Response::decode(""), Err(ProtocolError::Empty));
+        assert_eq!(
+            Response::decode("OK then"),
+            Err(ProtocolError::UnexpectedArgument)
+        );
+        assert_eq!(
+            Response::decode("FINE"),
+            Err(ProtocolError::UnknownCommand(String::from("FINE")))
+        );
+    }
+
+    #[test]
+    fn rejects_lines_over_max_len() {
+        let ping = format!("PING{}", " ".repeat(MAX_LINE_LEN - 4));
+        assert_eq!(ping.len(), MAX_LINE_LEN);
+        assert_eq!(Request::decode(&ping), Ok(Request::Ping));
+
+        let ping = format!("{} ", ping);
+        assert_eq!(Request::decode(&ping), Err(ProtocolError::TooLong));
+        // Responses may be longer, e.g. to carry the stats
+        let text = format!("TEXT {}", "x".repeat(MAX_LINE_LEN));
+        assert!(Response::decode(&text).is_ok());
+        let text = format!("TEXT {}", "x".repeat(MAX_RESPONSE_LEN));
+        assert_eq!(Response::decode(&text), Err(ProtocolError::TooLong));
+    }
+
+    #[test]
+    fn accepts_own_executable_in_same_session() {
+        let own = peer("/opt/CapsWitch/CapsWitch.exe", 1);
+
+        assert_eq!(check_peer(&own, &own.clone()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_foreign_executable() {
+        let own = peer("/opt/CapsWitch/CapsWitch.exe", 1);
+        let other = peer("/home/user/CapsWitch.exe", 1);
+
+        assert_eq!(check_peer(&own, &other), Err(Denied::ForeignExecutable));
+    }
+
+    #[test]
+    fn rejects_other_session() {
+        let own = peer("/opt/CapsWitch/CapsWitch.exe", 1);
+        let other = peer("/opt/CapsWitch/CapsWitch.exe", 2);
+
+        assert_eq!(check_peer(&own, &other), Err(Denied::OtherSession));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn compares_executables_ignoring_case() {
+        let own = peer(r"C:\Program Files\CapsWitch\CapsWitch.exe", 1);
+        let other = peer(r"c:\program files\capswitch\CAPSWITCH.EXE", 1);
+
+        assert_eq!(check_peer(&own, &other), Ok(()));
+    }
+
+    #[test]
+    fn requires_hello_first() {
+        let mut session = Session::default();
+
+        for request in [
+            Request::Ping,
+            Request::Activate {
+                window: WINDOW,
+                layout: us(),
+            },
+            Request::Caps {
+                command: CapsCommand::Off,
+            },
+            Request::Stats,
+        ] {
+            assert_eq!(session.handle(request, WINDOW), Denied::NoHello.into());
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_version() {
+        let mut session = Session::default();
+
+        assert_eq!(
+            session.handle(
+                Request::Hello {
+                    version: PROTOCOL_VERSION + 1
+                },
+                WINDOW
+            ),
+            Denied::UnsupportedVersion(PROTOCOL_VERSION + 1).into()
+        );
+        // The session stays closed
+        assert_eq!(
+            session.handle(Request::Ping, WINDOW),
+            Denied::NoHello.into()
+        );
+    }
+
+    #[test]
+    fn handles_requests_after_hello() {
+        let mut session = greeted();
+
+        assert_eq!(
+            session.handle(Request::Ping, WINDOW),
+            Action::Reply(Response::Ok)
+        );
+        assert_eq!(
+            session.handle(
+                Request::Activate {
+                    window: WINDOW,
+                    layout: us(),
+                },
+                WINDOW
+            ),
+            Action::Activate {
+                window: WINDOW,
+                layout: us(),
+            }
+        );
+        assert_eq!(
+            session.handle(
+                Request::Caps {
+                    command: CapsCommand::Toggle
+                },
+                WINDOW
+            ),
+            Action::SetCaps(CapsCommand::Toggle)
+        );
+        assert_eq!(session.handle(Request::Stats, WINDOW), Action::SendStats);
+    }
+
+    #[test]
+    fn rejects_window_not_in_foreground() {
+        let mut session = greeted();
+
+        assert_eq!(
+            session.handle(
+                Request::Activate {
+                    window: WINDOW,
+                    layout: us(),
+                },
+                WINDOW + 1
+            ),
+            Denied::NotForeground.into()
+        );
+    }
+
+    #[test]
+    fn finds_executables_in_protected_dirs() {
+        let dirs = [PathBuf::from(PROGRAM_FILES)];
+        let root = Path::new(PROGRAM_FILES);
+
+        assert!(is_in_protected_dir(
+            &root.join("CapsWitch").join("CapsWitch.exe"),
+            &dirs
+        ));
+        assert!(is_in_protected_dir(
+            &root
+                .join("WindowsApps")
+                .join("CapsWitch")
+                .join("CapsWitch.exe"),
+            &dirs
+        ));
+        assert!(!is_in_protected_dir(root, &dirs));
+        assert!(!is_in_protected_dir(
+            &root.join("..").join("CapsWitch.exe"),
+            &dirs
+        ));
+        assert!(!is_in_protected_dir(Path::new("CapsWitch.exe"), &dirs));
+        assert!(!is_in_protected_dir(&root.join("CapsWitch.exe"), &[]));
+    }
+
+    #[test]
+    fn rejects_dirs_sharing_a_prefix() {
+        let dirs = [PathBuf::from(PROGRAM_FILES)];
+        let exe = PathBuf::from(format!("{} Evil", PROGRAM_FILES)).join("CapsWitch.exe");
+
+        assert!(!is_in_protected_dir(&exe, &dirs));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn finds_protected_dirs_ignoring_case() {
+        let dirs = [PathBuf::from(PROGRAM_FILES)];
+
+        assert!(is_in_protected_dir(
+            Path::new(r"c:\PROGRAM FILES\CapsWitch\CapsWitch.exe"),
+            &dirs
+        ));
+    }
+}
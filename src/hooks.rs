@@ -0,0 +1,442 @@
+use crate::config::CONFIG;
+use crate::constants::LAYOUT_SETTLE_DELAY_MS;
+use crate::layout_id::format_layout;
+use crate::mode::SwitchMode;
+use crate::switch::{get_foreground_layout, get_layout_id};
+use log::{error, info, warn};
+use std::{
+    io,
+    os::windows::process::CommandExt,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+use windows::Win32::{System::Threading::CREATE_NO_WINDOW, UI::TextServices::HKL};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Number of kinds of events whose commands run independently
+const RUNNERS: usize = 3;
+
+static HOOK_SENDER: OnceLock<Sender<HookEvent>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookEvent {
+    /// `new` is `None` when the layout was switched with a synthetic
+    /// keystroke and is only known once the foreground window applies it.
+    LayoutChange {
+        old: HKL,
+        new: Option<HKL>,
+    },
+    Pause,
+    Resume,
+    ModeChange {
+        mode: SwitchMode,
+    },
+}
+
+impl HookEvent {
+    fn name(&self) -> &str {
+        match self {
+            HookEvent::LayoutChange { .. } => "layout_change",
+            HookEvent::Pause => "pause",
+            HookEvent::Resume => "resume",
+            HookEvent::ModeChange { .. } => "mode_change",
+        }
+    }
+
+    /// Runner the command is queued on. Pauses and resumes share one, so
+    /// their commands can't finish out of order.
+    fn runner(&self) -> usize {
+        match self {
+            HookEvent::LayoutChange { .. } => 0,
+            HookEvent::Pause | HookEvent::Resume => 1,
+            HookEvent::ModeChange { .. } => 2,
+        }
+    }
+
+    fn command(&self) -> Option<&'static str> {
+        let hooks = &CONFIG.hooks;
+        let command = match self {
+            HookEvent::LayoutChange { .. } => &hooks.on_layout_change,
+            HookEvent::Pause => &hooks.on_pause,
+            HookEvent::Resume => &hooks.on_resume,
+            HookEvent::ModeChange { .. } => &hooks.on_mode_change,
+        };
+
+        command.as_deref()
+    }
+
+    fn env_vars(&self) -> Vec<(&str, String)> {
+        let mut vars = vec![("CAPSWITCH_EVENT", self.name().to_string())];
+
+        match self {
+            HookEvent::LayoutChange { old, new } => {
+                let new = new.unwrap_or_else(|| {
+                    thread::sleep(Duration::from_millis(LAYOUT_SETTLE_DELAY_MS.into()));
+                    get_foreground_layout()
+                });
+                vars.push(("CAPSWITCH_OLD_LAYOUT", format_layout(old)));
+                vars.push(("CAPSWITCH_NEW_LAYOUT", format_layout(&new)));
+                vars.push(("CAPSWITCH_OLD_LAYOUT_ID", get_layout_id(old).to_string()));
+                vars.push(("CAPSWITCH_NEW_LAYOUT_ID", get_layout_id(&new).to_string()));
+            }
+            HookEvent::ModeChange { mode } => {
+                vars.push(("CAPSWITCH_MODE", mode.as_str().to_string()));
+            }
+            HookEvent::Pause | HookEvent::Resume => {}
+        }
+
+        vars
+    }
+}
+
+/// Started hook command
+pub trait HookProcess {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+    fn kill(&mut self) -> io::Result<()>;
+}
+
+pub trait CommandRunner {
+    type Process: HookProcess;
+
+    fn spawn(&self, command: &str, vars: &[(&str, String)]) -> io::Result<Self::Process>;
+    fn sleep(&self, duration: Duration);
+}
+
+impl HookProcess for Child {
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Child::try_wait(self)
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        Child::kill(self)?;
+        Child::wait(self).map(|_| ())
+    }
+}
+
+/// Runs commands through `cmd /C` without a console window
+struct ShellRunner;
+
+impl CommandRunner for ShellRunner {
+    type Process = Child;
+
+    fn spawn(&self, command: &str, vars: &[(&str, String)]) -> io::Result<Child> {
+        Command::new("cmd")
+            .arg("/C")
+            .raw_arg(command)
+            .envs(vars.iter().map(|(name, value)| (name, value)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .creation_flags(CREATE_NO_WINDOW.0)
+            .spawn()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Runs the command of an event, killing it once it runs longer than
+/// `timeout`
+fn run_command<R: CommandRunner>(runner: &R, event: &HookEvent, command: &str, timeout: Duration) {
+    let mut child = match runner.spawn(command, &event.env_vars()) {
+        Ok(child) => child,
+        Err(err) => {
+            error!(
+                "Failed to run `{}` hook `{}`: {}",
+                event.name(),
+                command,
+                err
+            );
+            return;
+        }
+    };
+
+    let mut waited = Duration::ZERO;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => {
+                if !status.success() {
+                    warn!(
+                        "`{}` hook `{}` failed with {}",
+                        event.name(),
+                        command,
+                        status
+                    );
+                }
+                return;
+            }
+            Ok(None) if waited >= timeout => {
+                warn!(
+                    "`{}` hook `{}` timed out after {} ms, killing it",
+                    event.name(),
+                    command,
+                    timeout.as_millis()
+                );
+                let _ = child.kill();
+                return;
+            }
+            Ok(None) => {
+                runner.sleep(POLL_INTERVAL);
+                waited += POLL_INTERVAL;
+            }
+            Err(err) => {
+                error!(
+                    "Failed to wait for `{}` hook `{}`: {}",
+                    event.name(),
+                    command,
+                    err
+                );
+                return;
+            }
+        }
+    }
+}
+
+/// Pauses can be toggled from the keyboard hook, which mustn't wait for the
+/// log file, so they are logged here
+fn log_event(event: &HookEvent) {
+    match event {
+        HookEvent::Pause => info!("Paused"),
+        HookEvent::Resume => info!("Resumed"),
+        HookEvent::LayoutChange { .. } | HookEvent::ModeChange { .. } => {}
+    }
+}
+
+/// Runs the commands of its events one at a time, in the order they were
+/// queued. The timeout bounds how long each of them holds up the rest.
+fn spawn_runner() -> Sender<HookEvent> {
+    let (tx, rx) = mpsc::channel::<HookEvent>();
+
+    thread::spawn(move || {
+        let timeout = Duration::from_millis(CONFIG.hooks.timeout_ms);
+        for event in rx {
+            if let Some(command) = event.command() {
+                run_command(&ShellRunner, &event, command, timeout);
+            }
+        }
+    });
+
+    tx
+}
+
+/// Starts the worker thread that logs events and hands their commands to
+/// runners, so a slow command only holds up the ones of its own events
+pub fn start() {
+    let (tx, rx) = mpsc::channel::<HookEvent>();
+    if HOOK_SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut runners: [Option<Sender<HookEvent>>; RUNNERS] = Default::default();
+        for event in rx {
+            log_event(&event);
+            if event.command().is_some() {
+                let _ = runners[event.runner()]
+                    .get_or_insert_with(spawn_runner)
+                    .send(event);
+            }
+        }
+    });
+}
+
+/// Queues an event for the hooks worker. Never blocks, so it is safe to call
+/// from the keyboard hook.
+pub fn dispatch(event: HookEvent) {
+    if let Some(tx) = HOOK_SENDER.get() {
+        let _ = tx.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::capture;
+    use log::Level;
+    use std::{
+        cell::{Cell, RefCell},
+        os::windows::process::ExitStatusExt,
+    };
+
+    const US: HKL = HKL(0x0409_0409);
+    const RU: HKL = HKL(0x0419_0419);
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    type Vars = Vec<(String, String)>;
+
+    /// Command that exits with the runner's `exit` code after as many
+    /// polls, or never
+    struct FakeProcess<'a> {
+        runner: &'a FakeRunner,
+        polls: u32,
+    }
+
+    impl HookProcess for FakeProcess<'_> {
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            self.polls += 1;
+            Ok(self
+                .runner
+                .exit
+                .filter(|&(polls, _)| self.polls > polls)
+                .map(|(_, code)| ExitStatus::from_raw(code)))
+        }
+
+        fn kill(&mut self) -> io::Result<()> {
+            self.runner.killed.set(true);
+            Ok(())
+        }
+    }
+
+    /// Starts `FakeProcess`es and records what they were started with and
+    /// how they ended
+    struct FakeRunner {
+        exit: Option<(u32, u32)>,
+        spawned: RefCell<Vec<(String, Vars)>>,
+        slept: Cell<Duration>,
+        killed: Cell<bool>,
+    }
+
+    impl FakeRunner {
+        fn new(exit: Option<(u32, u32)>) -> Self {
+            Self {
+                exit,
+                spawned: RefCell::new(Vec::new()),
+                slept: Cell::new(Duration::ZERO),
+                killed: Cell::new(false),
+            }
+        }
+    }
+
+    impl<'a> CommandRunner for &'a FakeRunner {
+        type Process = FakeProcess<'a>;
+
+        fn spawn(&self, command: &str, vars: &[(&str, String)]) -> io::Result<FakeProcess<'a>> {
+            self.spawned.borrow_mut().push((
+                command.to_string(),
+                vars.iter()
+                    .map(|(name, value)| (name.to_string(), value.clone()))
+                    .collect(),
+            ));
+
+            Ok(FakeProcess {
+                runner: self,
+                polls: 0,
+            })
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.slept.set(self.slept.get() + duration);
+        }
+    }
+
+    fn vars(runner: &FakeRunner) -> Vec<Vars> {
+        runner
+            .spawned
+            .borrow()
+            .iter()
+            .map(|(_, vars)| vars.clone())
+            .collect()
+    }
+
+    fn var(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn passes_event_vars() {
+        let runner = FakeRunner::new(Some((0, 0)));
+        let events = [
+            HookEvent::LayoutChange {
+                old: US,
+                new: Some(RU),
+            },
+            HookEvent::Pause,
+            HookEvent::Resume,
+            HookEvent::ModeChange {
+                mode: SwitchMode::Previous,
+            },
+        ];
+        for event in &events {
+            run_command(&&runner, event, "hook.cmd", TIMEOUT);
+        }
+
+        assert_eq!(
+            vars(&runner),
+            [
+                vec![
+                    var("CAPSWITCH_EVENT", "layout_change"),
+                    var("CAPSWITCH_OLD_LAYOUT", "04090409"),
+                    var("CAPSWITCH_NEW_LAYOUT", "04190419"),
+                    var("CAPSWITCH_OLD_LAYOUT_ID", &get_layout_id(&US).to_string()),
+                    var("CAPSWITCH_NEW_LAYOUT_ID", &get_layout_id(&RU).to_string()),
+                ],
+                vec![var("CAPSWITCH_EVENT", "pause")],
+                vec![var("CAPSWITCH_EVENT", "resume")],
+                vec![
+                    var("CAPSWITCH_EVENT", "mode_change"),
+                    var("CAPSWITCH_MODE", SwitchMode::Previous.as_str()),
+                ],
+            ]
+        );
+        assert!(runner
+            .spawned
+            .borrow()
+            .iter()
+            .all(|(command, _)| command == "hook.cmd"));
+    }
+
+    #[test]
+    fn waits_for_command_quietly() {
+        let runner = FakeRunner::new(Some((3, 0)));
+        capture::start();
+
+        run_command(&&runner, &HookEvent::Pause, "hook.cmd", TIMEOUT);
+
+        assert_eq!(runner.slept.get(), 3 * POLL_INTERVAL);
+        assert!(!runner.killed.get());
+        assert!(capture::take().is_empty());
+    }
+
+    #[test]
+    fn logs_failed_command() {
+        let runner = FakeRunner::new(Some((1, 2)));
+        capture::start();
+
+        run_command(&&runner, &HookEvent::Resume, "hook.cmd", TIMEOUT);
+
+        assert_eq!(
+            capture::take(),
+            [(
+                Level::Warn,
+                format!(
+                    "`resume` hook `hook.cmd` failed with {}",
+                    ExitStatus::from_raw(2)
+                )
+            )]
+        );
+    }
+
+    #[test]
+    fn kills_command_after_timeout() {
+        let runner = FakeRunner::new(None);
+        capture::start();
+
+        run_command(&&runner, &HookEvent::Pause, "hook.cmd", TIMEOUT);
+
+        assert!(runner.killed.get());
+        assert_eq!(runner.slept.get(), TIMEOUT);
+        assert_eq!(
+            capture::take(),
+            [(
+                Level::Warn,
+                "`pause` hook `hook.cmd` timed out after 200 ms, killing it".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,346 @@
+use image::{Rgba, RgbaImage};
+
+pub const ICON_SIZE: u32 = 32;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const GLYPH_SCALE: u32 = 2;
+const GLYPH_SPACING: u32 = 2;
+const MAX_CODE_LEN: usize = 2;
+const CORNER_RADIUS: u32 = 5;
+
+const ACTIVE_BACKGROUND: Rgba<u8> = Rgba([38, 166, 124, 255]);
+const PAUSED_BACKGROUND: Rgba<u8> = Rgba([128, 128, 128, 255]);
+const FOREGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const TRANSPARENT: Rgba<u8> = Rgba([0, 0, 0, 0]);
+
+/// 5x7 bitmap glyphs, one byte per row with the leftmost pixel in bit 4.
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT as usize] {
+    match c {
+        'A' => [
+            0b01110, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+        'B' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10001, 0b10001, 0b11110,
+        ],
+        'C' => [
+            0b01110, 0b10001, 0b10000, 0b10000, 0b10000, 0b10001, 0b01110,
+        ],
+        'D' => [
+            0b11110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b11110,
+        ],
+        'E' => [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b11111,
+        ],
+        'F' => [
+            0b11111, 0b10000, 0b10000, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+        'G' => [
+            0b01110, 0b10001, 0b10000, 0b10111, 0b10001, 0b10001, 0b01111,
+        ],
+        'H' => [
+            0b10001, 0b10001, 0b10001, 0b11111, 0b10001, 0b10001, 0b10001,
+        ],
+        'I' => [
+            0b01110, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+        'J' => [
+            0b00111, 0b00010, 0b00010, 0b00010, 0b00010, 0b10010, 0b01100,
+        ],
+        'K' => [
+            0b10001, 0b10010, 0b10100, 0b11000, 0b10100, 0b10010, 0b10001,
+        ],
+        'L' => [
+            0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b10000, 0b11111,
+        ],
+        'M' => [
+            0b10001, 0b11011, 0b10101, 0b10101, 0b10001, 0b10001, 0b10001,
+        ],
+        'N' => [
+            0b10001, 0b10001, 0b11001, 0b10101, 0b10011, 0b10001, 0b10001,
+        ],
+        'O' => [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+        'P' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10000, 0b10000, 0b10000,
+        ],
+        'Q' => [
+            0b01110, 0b10001, 0b10001, 0b10001, 0b10101, 0b10010, 0b01101,
+        ],
+        'R' => [
+            0b11110, 0b10001, 0b10001, 0b11110, 0b10100, 0b10010, 0b10001,
+        ],
+        'S' => [
+            0b01111, 0b10000, 0b10000, 0b01110, 0b00001, 0b00001, 0b11110,
+        ],
+        'T' => [
+            0b11111, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100, 0b00100,
+        ],
+        'U' => [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110,
+        ],
+        'V' => [
+            0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01010, 0b00100,
+        ],
+        'W' => [
+            0b10001, 0b10001, 0b10001, 0b10101, 0b10101, 0b10101, 0b01010,
+        ],
+        'X' => [
+            0b10001, 0b10001, 0b01010, 0b00100, 0b01010, 0b10001, 0b10001,
+        ],
+        'Y' => [
+            0b10001, 0b10001, 0b10001, 0b01010, 0b00100, 0b00100, 0b00100,
+        ],
+        'Z' => [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b10000, 0b11111,
+        ],
+        '0' => [
+            0b01110, 0b10001, 0b10011, 0b10101, 0b11001, 0b10001, 0b01110,
+        ],
+        '1' => [
+            0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110,
+        ],
+        '2' => [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111,
+        ],
+        '3' => [
+            0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110,
+        ],
+        '4' => [
+            0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010,
+        ],
+        '5' => [
+            0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110,
+        ],
+        '6' => [
+            0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110,
+        ],
+        '7' => [
+            0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000,
+        ],
+        '8' => [
+            0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110,
+        ],
+        '9' => [
+            0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100,
+        ],
+        _ => [
+            0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b00000, 0b00100,
+        ],
+    }
+}
+
+fn is_inside_rounded_square(x: u32, y: u32) -> bool {
+    let r = CORNER_RADIUS;
+    let far = ICON_SIZE - 1 - r;
+    let cx = x.clamp(r, far);
+    let cy = y.clamp(r, far);
+    let (dx, dy) = (x.abs_diff(cx), y.abs_diff(cy));
+
+    dx * dx + dy * dy <= r * r
+}
+
+fn draw_glyph(img: &mut RgbaImage, c: char, left: u32, top: u32) {
+    for (row, bits) in glyph(c).iter().enumerate() {
+        for col in 0..GLYPH_WIDTH {
+            if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                continue;
+            }
+
+            let x0 = left + col * GLYPH_SCALE;
+            let y0 = top + row as u32 * GLYPH_SCALE;
+            for y in y0..y0 + GLYPH_SCALE {
+                for x in x0..x0 + GLYPH_SCALE {
+                    img.put_pixel(x, y, FOREGROUND);
+                }
+            }
+        }
+    }
+}
+
+/// Renders a tray icon with the layout short code (e.g. "EN") in white on a
+/// rounded square, green while active and grey while paused. Only the first
+/// two characters of the code are drawn.
+pub fn render_layout_icon(code: &str, is_paused: bool) -> RgbaImage {
+    let background = if is_paused {
+        PAUSED_BACKGROUND
+    } else {
+        ACTIVE_BACKGROUND
+    };
+
+    let mut img = RgbaImage::from_fn(ICON_SIZE, ICON_SIZE, |x, y| {
+        if is_inside_rounded_square(x, y) {
+            background
+        } else {
+            TRANSPARENT
+        }
+    });
+
+    let chars: Vec<char> = code
+        .chars()
+        .flat_map(char::to_uppercase)
+        .take(MAX_CODE_LEN)
+        .collect();
+    if chars.is_empty() {
+        return img;
+    }
+
+    let glyph_width = GLYPH_WIDTH * GLYPH_SCALE;
+    let text_width = chars.len() as u32 * glyph_width + (chars.len() as u32 - 1) * GLYPH_SPACING;
+    let left = (ICON_SIZE - text_width) / 2;
+    let top = (ICON_SIZE - GLYPH_HEIGHT * GLYPH_SCALE) / 2;
+
+    for (i, c) in chars.into_iter().enumerate() {
+        draw_glyph(
+            &mut img,
+            c,
+            left + i as u32 * (glyph_width + GLYPH_SPACING),
+            top,
+        );
+    }
+
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORNERS: [(u32, u32); 4] = [
+        (0, 0),
+        (ICON_SIZE - 1, 0),
+        (0, ICON_SIZE - 1),
+        (ICON_SIZE - 1, ICON_SIZE - 1),
+    ];
+
+    fn count_pixels(img: &RgbaImage, color: Rgba<u8>) -> usize {
+        img.pixels().filter(|&&pixel| pixel == color).count()
+    }
+
+    #[test]
+    fn renders_icon_size() {
+        let img = render_layout_icon("EN", false);
+
+        assert_eq!(img.dimensions(), (ICON_SIZE, ICON_SIZE));
+    }
+
+    #[test]
+    fn paused_icon_differs_only_in_background() {
+        let active = render_layout_icon("EN", false);
+        let paused = render_layout_icon("EN", true);
+
+        for (x, y, &pixel) in active.enumerate_pixels() {
+            let paused_pixel = *paused.get_pixel(x, y);
+            if pixel == ACTIVE_BACKGROUND {
+                assert_eq!(paused_pixel, PAUSED_BACKGROUND, "({}, {})", x, y);
+            } else {
+                assert_eq!(paused_pixel, pixel, "({}, {})", x, y);
+            }
+        }
+        assert!(count_pixels(&active, ACTIVE_BACKGROUND) > 0);
+        assert_eq!(count_pixels(&paused, ACTIVE_BACKGROUND), 0);
+    }
+
+    #[test]
+    fn corners_are_transparent() {
+        for is_paused in [false, true] {
+            let img = render_layout_icon("EN", is_paused);
+
+            for (x, y) in CORNERS {
+                assert_eq!(*img.get_pixel(x, y), TRANSPARENT, "({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn edge_centers_are_background() {
+        let img = render_layout_icon("", false);
+        let middle = ICON_SIZE / 2;
+
+        for (x, y) in [
+            (middle, 0),
+            (0, middle),
+            (middle, ICON_SIZE - 1),
+            (ICON_SIZE - 1, middle),
+        ] {
+            assert_eq!(*img.get_pixel(x, y), ACTIVE_BACKGROUND, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn truncates_long_codes() {
+        assert_eq!(
+            render_layout_icon("ENG", false),
+            render_layout_icon("EN", false)
+        );
+        assert_eq!(
+            render_layout_icon("US(DVORAK)", false),
+            render_layout_icon("US", false)
+        );
+    }
+
+    #[test]
+    fn uppercases_codes() {
+        assert_eq!(
+            render_layout_icon("en", false),
+            render_layout_icon("EN", false)
+        );
+    }
+
+    #[test]
+    fn empty_code_gives_only_background() {
+        let img = render_layout_icon("", false);
+
+        assert_eq!(count_pixels(&img, FOREGROUND), 0);
+        assert_eq!(
+            count_pixels(&img, ACTIVE_BACKGROUND) + count_pixels(&img, TRANSPARENT),
+            (ICON_SIZE * ICON_SIZE) as usize
+        );
+        assert_eq!(
+            img,
+            RgbaImage::from_fn(ICON_SIZE, ICON_SIZE, |x, y| {
+                if is_inside_rounded_square(x, y) {
+                    ACTIVE_BACKGROUND
+                } else {
+                    TRANSPARENT
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn draws_text_inside_background() {
+        let background = render_layout_icon("", false);
+        let img = render_layout_icon("WM", false);
+
+        assert!(count_pixels(&img, FOREGROUND) > 0);
+        for (x, y, &pixel) in img.enumerate_pixels() {
+            if pixel == FOREGROUND {
+                assert_eq!(
+                    *background.get_pixel(x, y),
+                    ACTIVE_BACKGROUND,
+                    "({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn single_char_is_centered() {
+        let img = render_layout_icon("I", false);
+        let columns: Vec<u32> = img
+            .enumerate_pixels()
+            .filter(|(_, _, &pixel)| pixel == FOREGROUND)
+            .map(|(x, _, _)| x)
+            .collect();
+
+        let (left, right) = (
+            *columns.iter().min().unwrap(),
+            *columns.iter().max().unwrap(),
+        );
+        assert_eq!(left, ICON_SIZE - 1 - right);
+    }
+}
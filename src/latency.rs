@@ -0,0 +1,450 @@
+use crate::switch::get_foreground_layout;
+use log::{debug, warn};
+use std::{
+    cell::Cell,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use windows::Win32::UI::TextServices::HKL;
+
+/// Upper bounds of the histogram buckets in microseconds. The last bucket
+/// takes everything above.
+const BUCKET_BOUNDS_US: [u64; 12] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+];
+const BUCKETS: usize = BUCKET_BOUNDS_US.len() + 1;
+
+/// Time spent in the hook above which Windows may start skipping it
+const SLOW_HOOK: Duration = Duration::from_millis(50);
+/// Time from CapsLock to the new layout above which a switch feels laggy
+const SLOW_SWITCH: Duration = Duration::from_millis(250);
+
+/// Prints the stats of the running app instead of starting another one
+const STATS_ARG: &str = "--stats";
+
+const OBSERVE_INTERVAL: Duration = Duration::from_millis(1);
+const OBSERVE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Hook entry to the choice of the layout
+pub static DECISION: Histogram = Histogram::new();
+/// Layout change request, i.e. the `SendInput` or
+/// `SendMessageTimeoutW` call
+pub static APPLY: Histogram = Histogram::new();
+/// Hook entry to the foreground window reporting another layout. Measured
+/// by polling, so it is precise to the system timer resolution.
+pub static OBSERVED: Histogram = Histogram::new();
+
+static OBSERVE_SENDER: OnceLock<Sender<SwitchTiming>> = OnceLock::new();
+
+thread_local! {
+    /// Entry time of the key event the hook is handling
+    static EVENT_STARTED: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Counts of durations by bucket, updated without locks from the hook
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub buckets: [u64; BUCKETS],
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let bucket = BUCKET_BOUNDS_US
+            .iter()
+            .position(|&bound| us <= bound)
+            .unwrap_or(BUCKETS - 1);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bound of the bucket holding the `percent` percentile, or the
+    /// maximum if it falls in the last bucket
+    pub fn percentile_us(&self, percent: u64) -> u64 {
+        let rank = (self.count() * percent).div_ceil(100).max(1);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BUCKET_BOUNDS_US
+                    .get(i)
+                    .map_or(self.max_us, |&bound| bound.min(self.max_us));
+            }
+        }
+
+        self.max_us
+    }
+}
+
+/// Microseconds as a short human-readable duration
+fn format_us(us: u64) -> String {
+    if us < 1_000 {
+        format!("{} µs", us)
+    } else {
+        format!("{:.1} ms", us as f64 / 1_000.0)
+    }
+}
+
+/// Table with a line per stage and one per bucket of each
+pub fn render_stats(stages: &[(&str, HistogramSnapshot)]) -> String {
+    let mut stats = String::new();
+
+    for (name, snapshot) in stages {
+        let count = snapshot.count();
+        if count == 0 {
+            let _ = writeln!(stats, "{}: no events\n", name);
+            continue;
+        }
+
+        let _ = writeln!(
+            stats,
+            "{}: {} events, mean {}, p50 ≤ {}, p95 ≤ {}, p99 ≤ {}, max {}",
+            name,
+            count,
+            format_us(snapshot.total_us / count),
+            format_us(snapshot.percentile_us(50)),
+            format_us(snapshot.percentile_us(95)),
+            format_us(snapshot.percentile_us(99)),
+            format_us(snapshot.max_us),
+        );
+        for (i, bucket_count) in snapshot.buckets.iter().enumerate() {
+            if *bucket_count == 0 {
+                continue;
+            }
+            let bound = match BUCKET_BOUNDS_US.get(i) {
+                Some(&bound) => format!("≤ {}", format_us(bound)),
+                None => format!("> {}", format_us(BUCKET_BOUNDS_US[i - 1])),
+            };
+            let _ = writeln!(stats, "  {:>10}: {}", bound, bucket_count);
+        }
+        let _ = writeln!(stats);
+    }
+
+    stats
+}
+
+pub fn is_stats_requested(args: &[String]) -> bool {
+    args.iter().skip(1).any(|arg| arg == STATS_ARG)
+}
+
+/// Stats of all the stages of the current session
+pub fn get_stats() -> String {
+    render_stats(&[
+        ("Hook entry to decision", DECISION.snapshot()),
+        ("Layout change request", APPLY.snapshot()),
+        ("Hook entry to new layout", OBSERVED.snapshot()),
+    ])
+}
+
+struct SwitchTiming {
+    started: Instant,
+    decision: Duration,
+    apply: Duration,
+    old: HKL,
+}
+
+/// Marks the entry of the hook into a key event that may switch the layout
+pub fn begin_event() {
+    EVENT_STARTED.set(Some(Instant::now()));
+}
+
+pub fn end_event() {
+    EVENT_STARTED.set(None);
+}
+
+/// Runs the layout change request made for the current key event, timing
+/// it and the time it took to decide on it. `old` is the layout before.
+pub fn measure_apply<T>(old: HKL, apply: impl FnOnce() -> T) -> T {
+    let Some(started) = EVENT_STARTED.get() else {
+        return apply();
+    };
+
+    let decided = Instant::now();
+    let result = apply();
+    let applied = Instant::now();
+
+    let timing = SwitchTiming {
+        started,
+        decision: decided - started,
+        apply: applied - decided,
+        old,
+    };
+    DECISION.record(timing.decision);
+    APPLY.record(timing.apply);
+    if let Some(sender) = OBSERVE_SENDER.get() {
+        let _ = sender.send(timing);
+    }
+
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Observation {
+    /// The new layout was seen this long after the hook entry
+    Changed(Duration),
+    Pending,
+    TimedOut,
+}
+
+/// Whether the switch has taken effect given the foreground `layout` at `now`
+fn observe(timing: &SwitchTiming, layout: HKL, now: Instant) -> Observation {
+    let elapsed = now.saturating_duration_since(timing.started);
+    if layout != timing.old {
+        Observation::Changed(elapsed)
+    } else if elapsed >= OBSERVE_TIMEOUT {
+        Observation::TimedOut
+    } else {
+        Observation::Pending
+    }
+}
+
+fn check_hook_time(timing: &SwitchTiming) {
+    let in_hook = timing.decision + timing.apply;
+    if in_hook > SLOW_HOOK {
+        warn!(
+            "Slow keyboard hook: decision {}, layout change request {}",
+            format_us(timing.decision.as_micros() as u64),
+            format_us(timing.apply.as_micros() as u64)
+        );
+    }
+}
+
+fn record_observed(observed: Duration) {
+    OBSERVED.record(observed);
+    if observed > SLOW_SWITCH {
+        warn!(
+            "Slow layout switch: new layout after {}",
+            format_us(observed.as_micros() as u64)
+        );
+    }
+}
+
+/// Starts the worker that measures when switches take effect and logs slow
+/// ones, as the hook itself must not wait or write files. All the queued
+/// switches are watched at once, so back-to-back ones are timed from their
+/// own hook entries rather than from when the previous one was done.
+pub fn start() {
+    let (tx, rx) = mpsc::channel::<SwitchTiming>();
+    if OBSERVE_SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut pending: Vec<SwitchTiming> = Vec::new();
+        loop {
+            // Only blocks while there is nothing to watch
+            if pending.is_empty() {
+                let Ok(timing) = rx.recv() else {
+                    break;
+                };
+                check_hook_time(&timing);
+                pending.push(timing);
+            }
+            for timing in rx.try_iter() {
+                check_hook_time(&timing);
+                pending.push(timing);
+            }
+
+            let layout = get_foreground_layout();
+            let now = Instant::now();
+            pending.retain(|timing| match observe(timing, layout, now) {
+                Observation::Changed(observed) => {
+                    record_observed(observed);
+                    false
+                }
+                Observation::Pending => true,
+                Observation::TimedOut => {
+                    debug!("No layout change observed after a switch");
+                    false
+                }
+            });
+
+            if !pending.is_empty() {
+                thread::sleep(OBSERVE_INTERVAL);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: HKL = HKL(0x0409_0409);
+    const RU: HKL = HKL(0x0419_0419);
+
+    fn histogram(durations_us: &[u64]) -> HistogramSnapshot {
+        let histogram = Histogram::new();
+        for &us in durations_us {
+            histogram.record(Duration::from_micros(us));
+        }
+
+        histogram.snapshot()
+    }
+
+    fn bucket_of(us: u64) -> usize {
+        let snapshot = histogram(&[us]);
+        snapshot
+            .buckets
+            .iter()
+            .position(|&count| count == 1)
+            .unwrap()
+    }
+
+    #[test]
+    fn reads_stats_arg() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        assert!(is_stats_requested(&args(&["CapsWitch.exe", "--stats"])));
+        assert!(!is_stats_requested(&args(&["CapsWitch.exe"])));
+        assert!(!is_stats_requested(&args(&["--stats"])));
+        assert!(!is_stats_requested(&args(&[
+            "CapsWitch.exe",
+            "--stats=all"
+        ])));
+    }
+
+    #[test]
+    fn records_at_bucket_bounds() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(50), 0);
+        assert_eq!(bucket_of(51), 1);
+        assert_eq!(bucket_of(1_000), 4);
+        assert_eq!(bucket_of(1_001), 5);
+        assert_eq!(bucket_of(250_000), BUCKETS - 2);
+        assert_eq!(bucket_of(250_001), BUCKETS - 1);
+        assert_eq!(bucket_of(60_000_000), BUCKETS - 1);
+    }
+
+    #[test]
+    fn records_total_and_max() {
+        let snapshot = histogram(&[10, 300, 20]);
+
+        assert_eq!(snapshot.count(), 3);
+        assert_eq!(snapshot.total_us, 330);
+        assert_eq!(snapshot.max_us, 300);
+    }
+
+    #[test]
+    fn percentiles_of_empty_histogram() {
+        let snapshot = histogram(&[]);
+
+        assert_eq!(snapshot.count(), 0);
+        for percent in [0, 50, 99, 100] {
+            assert_eq!(snapshot.percentile_us(percent), 0);
+        }
+    }
+
+    #[test]
+    fn percentiles_of_one_sample() {
+        // Capped by the maximum rather than the bucket bound
+        let snapshot = histogram(&[700]);
+        for percent in [0, 50, 99, 100] {
+            assert_eq!(snapshot.percentile_us(percent), 700);
+        }
+
+        let snapshot = histogram(&[400_000]);
+        assert_eq!(snapshot.percentile_us(50), 400_000);
+    }
+
+    #[test]
+    fn percentiles_of_known_distribution() {
+        let mut durations = vec![40; 98];
+        durations.extend([3_000, 300_000]);
+        let snapshot = histogram(&durations);
+
+        assert_eq!(snapshot.percentile_us(50), 50);
+        assert_eq!(snapshot.percentile_us(95), 50);
+        assert_eq!(snapshot.percentile_us(98), 50);
+        assert_eq!(snapshot.percentile_us(99), 5_000);
+        assert_eq!(snapshot.percentile_us(100), 300_000);
+    }
+
+    #[test]
+    fn renders_stats_table() {
+        let stats = render_stats(&[
+            ("Decision", histogram(&[40, 40, 40, 2_000])),
+            ("Request", histogram(&[])),
+            ("Observed", histogram(&[300_000])),
+        ]);
+
+        assert_eq!(
+            stats,
+            "Decision: 4 events, mean 530 µs, p50 ≤ 50 µs, p95 ≤ 2.0 ms, p99 ≤ 2.0 ms, max 2.0 ms\n\
+             \x20    ≤ 50 µs: 3\n\
+             \x20   ≤ 2.5 ms: 1\n\
+             \n\
+             Request: no events\n\
+             \n\
+             Observed: 1 events, mean 300.0 ms, p50 ≤ 300.0 ms, p95 ≤ 300.0 ms, p99 ≤ 300.0 ms, max 300.0 ms\n\
+             \x20 > 250.0 ms: 1\n\
+             \n"
+        );
+    }
+
+    #[test]
+    fn observes_switches_from_hook_entry() {
+        let started = Instant::now();
+        let timing = SwitchTiming {
+            started,
+            decision: Duration::from_micros(20),
+            apply: Duration::from_micros(80),
+            old: US,
+        };
+        let at = |ms| started + Duration::from_millis(ms);
+
+        assert_eq!(observe(&timing, US, at(5)), Observation::Pending);
+        assert_eq!(
+            observe(&timing, RU, at(30)),
+            Observation::Changed(Duration::from_millis(30))
+        );
+        assert_eq!(
+            observe(&timing, US, at(OBSERVE_TIMEOUT.as_millis() as u64)),
+            Observation::TimedOut
+        );
+        // A change is still counted when it is only seen after the timeout,
+        // e.g. once the previous switch is done
+        assert_eq!(
+            observe(&timing, RU, at(1_500)),
+            Observation::Changed(Duration::from_millis(1_500))
+        );
+    }
+}
@@ -0,0 +1,547 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+use windows::Win32::UI::TextServices::HKL;
+
+/// Language tag of layouts whose language isn't known, e.g. XKB ones
+pub const UNDETERMINED_LANGUAGE: &str = "und";
+
+/// Keyboard layout identified independently of the Win32 `HKL` handle, so it
+/// can be written to the config, sent to other processes and shown to users.
+///
+/// Formatted as `<language>:<variant>`, e.g. `en-US:00000409` or
+/// `und:us(dvorak)`. Two ids are equal if their language and variant are,
+/// the name and the short code are only for display.
+#[derive(Debug, Clone)]
+pub struct LayoutId {
+    /// BCP 47 language tag, e.g. `en-US`
+    pub language: String,
+    pub variant: LayoutVariant,
+    /// Human-readable name, e.g. "English (United States)"
+    pub name: String,
+    /// Code shown in the tray icon, e.g. "EN"
+    pub short_code: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LayoutVariant {
+    /// Windows keyboard layout identifier, e.g. `00000409` for US or
+    /// `00010409` for US Dvorak
+    Klid(u32),
+    /// XKB layout with an optional variant, e.g. `us(dvorak)`
+    Xkb {
+        layout: String,
+        variant: Option<String>,
+    },
+}
+
+/// What the high word of an `HKL` refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HklLayout {
+    Klid(u32),
+    /// "Layout Id" value of a layout in the registry, for HKLs of layout
+    /// variants like `F0020409` that don't carry the KLID itself
+    LayoutIdIndex(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodedHkl {
+    /// Language of the layout, also a valid LCID
+    pub lang_id: u16,
+    pub layout: HklLayout,
+}
+
+/// Splits a raw `HKL` into the language in the low word and the layout in
+/// the high word
+pub fn decode_hkl(raw: u32) -> DecodedHkl {
+    let lang_id = (raw & 0xFFFF) as u16;
+    let device = (raw >> 16) as u16;
+
+    let layout = match device & 0xF000 {
+        // IMEs are identified by the whole handle, e.g. `E0010411`
+        0xE000 => HklLayout::Klid(raw),
+        0xF000 => HklLayout::LayoutIdIndex(device & 0x0FFF),
+        _ => HklLayout::Klid(u32::from(device)),
+    };
+
+    DecodedHkl { lang_id, layout }
+}
+
+/// Formats a layout as 8 hex digits, e.g. `04090409`
+pub fn format_layout(hkl: &HKL) -> String {
+    format!("{:08X}", hkl.0 as u32)
+}
+
+/// Parses a layout formatted with `format_layout`
+pub fn parse_layout(value: &str) -> Option<HKL> {
+    let value = u32::from_str_radix(value.trim(), 16).ok()?;
+
+    // HKLs are sign-extended 32-bit values on 64-bit Windows
+    Some(HKL(value as i32 as isize))
+}
+
+/// Canonical case of a BCP 47 tag: lowercase language, titlecase script and
+/// uppercase region, e.g. `sr-Latn-RS`
+fn normalize_language(tag: &str) -> String {
+    tag.trim()
+        .split(['-', '_'])
+        .enumerate()
+        .map(|(i, subtag)| match subtag.len() {
+            _ if i == 0 => subtag.to_ascii_lowercase(),
+            2 => subtag.to_ascii_uppercase(),
+            4 => {
+                let mut script = subtag.to_ascii_lowercase();
+                script[..1].make_ascii_uppercase();
+                script
+            }
+            _ => subtag.to_ascii_lowercase(),
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+fn is_valid_language(tag: &str) -> bool {
+    let mut subtags = tag.split('-');
+    let primary_is_valid = subtags
+        .next()
+        .is_some_and(|s| (2..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphabetic()));
+
+    primary_is_valid
+        && subtags
+            .all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn is_xkb_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'))
+}
+
+impl LayoutVariant {
+    /// Parses an XKB group name, e.g. `us` or `us(dvorak)`
+    pub fn from_xkb(group: &str) -> Option<Self> {
+        let group = group.trim();
+        let (layout, variant) = match group.split_once('(') {
+            Some((layout, rest)) => (layout, Some(rest.strip_suffix(')')?)),
+            None => (group, None),
+        };
+        if !is_xkb_name(layout) || variant.is_some_and(|v| !is_xkb_name(v)) {
+            return None;
+        }
+
+        Some(Self::Xkb {
+            layout: layout.to_ascii_lowercase(),
+            variant: variant.map(str::to_ascii_lowercase),
+        })
+    }
+}
+
+impl fmt::Display for LayoutVariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Klid(klid) => write!(f, "{:08X}", klid),
+            Self::Xkb {
+                layout,
+                variant: Some(variant),
+            } => write!(f, "{}({})", layout, variant),
+            Self::Xkb {
+                layout,
+                variant: None,
+            } => write!(f, "{}", layout),
+        }
+    }
+}
+
+impl FromStr for LayoutVariant {
+    type Err = ParseLayoutIdError;
+
+    /// Eight hex digits are a KLID, anything else an XKB group name
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim();
+        if value.len() == 8 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return u32::from_str_radix(value, 16)
+                .map(Self::Klid)
+                .map_err(|_| ParseLayoutIdError);
+        }
+
+        Self::from_xkb(value).ok_or(ParseLayoutIdError)
+    }
+}
+
+impl LayoutId {
+    /// Id with a name and a short code derived from the language and the
+    /// variant, for layouts the system doesn't describe
+    pub fn new(language: &str, variant: LayoutVariant) -> Self {
+        let language = normalize_language(language);
+        let short_code = match (&variant, language.as_str()) {
+            (LayoutVariant::Xkb { layout, .. }, UNDETERMINED_LANGUAGE) => layout.clone(),
+            _ => language.split('-').next().unwrap_or_default().to_string(),
+        }
+        .chars()
+        .take(2)
+        .collect::<String>()
+        .to_uppercase();
+        let name = match language.as_str() {
+            UNDETERMINED_LANGUAGE => variant.to_string(),
+            _ => format!("{} {}", language, variant),
+        };
+
+        Self {
+            language,
+            variant,
+            name,
+            short_code,
+        }
+    }
+
+    /// Id of an XKB group name like `us(dvorak)`, whose language is unknown
+    #[cfg_attr(windows, allow(dead_code))]
+    pub fn from_xkb_group(group: &str) -> Option<Self> {
+        LayoutVariant::from_xkb(group).map(|variant| Self::new(UNDETERMINED_LANGUAGE, variant))
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Whether the id matches a value from the config or a command: a full
+    /// id, a bare variant like `00000409` or `us(dvorak)`, or a language tag
+    pub fn matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if let Ok(id) = value.parse::<LayoutId>() {
+            return *self == id;
+        }
+
+        value
+            .parse::<LayoutVariant>()
+            .is_ok_and(|variant| variant == self.variant)
+            || normalize_language(value) == self.language
+    }
+}
+
+impl PartialEq for LayoutId {
+    fn eq(&self, other: &Self) -> bool {
+        self.language == other.language && self.variant == other.variant
+    }
+}
+
+impl Eq for LayoutId {}
+
+impl Hash for LayoutId {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.language.hash(state);
+        self.variant.hash(state);
+    }
+}
+
+impl fmt::Display for LayoutId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.language, self.variant)
+    }
+}
+
+impl FromStr for LayoutId {
+    type Err = ParseLayoutIdError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (language, variant) = value.trim().split_once(':').ok_or(ParseLayoutIdError)?;
+        let language = normalize_language(language);
+        if !is_valid_language(&language) {
+            return Err(ParseLayoutIdError);
+        }
+
+        Ok(Self::new(&language, variant.parse()?))
+    }
+}
+
+/// Ids of HKLs, which take registry and locale lookups to build. They are
+/// kept for as long as the installed layouts stay the same, as reinstalling
+/// a layout may give its HKL another meaning.
+#[derive(Debug, Default)]
+pub struct LayoutIdCache {
+    installed: Vec<HKL>,
+    ids: HashMap<isize, LayoutId>,
+}
+
+impl LayoutIdCache {
+    pub fn get(&mut self, installed: &[HKL], hkl: &HKL) -> Option<LayoutId> {
+        if self.installed != installed {
+            self.installed = installed.to_vec();
+            self.ids.clear();
+        }
+
+        self.ids.get(&hkl.0).cloned()
+    }
+
+    /// Keeps the id built for the layouts `get` was last called with
+    pub fn insert(&mut self, installed: &[HKL], hkl: &HKL, id: LayoutId) {
+        if self.installed == installed {
+            self.ids.insert(hkl.0, id);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLayoutIdError;
+
+impl fmt::Display for ParseLayoutIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid layout id, expected e.g. `en-US:00000409` or `und:us(dvorak)`"
+        )
+    }
+}
+
+impl std::error::Error for ParseLayoutIdError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn us() -> LayoutId {
+        LayoutId::new("en-US", LayoutVariant::Klid(0x0000_0409))
+    }
+
+    fn xkb(layout: &str, variant: Option<&str>) -> LayoutVariant {
+        LayoutVariant::Xkb {
+            layout: layout.to_string(),
+            variant: variant.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn decodes_plain_hkl() {
+        assert_eq!(
+            decode_hkl(0x0409_0409),
+            DecodedHkl {
+                lang_id: 0x0409,
+                layout: HklLayout::Klid(0x0409),
+            }
+        );
+        // US keyboard with a Russian language
+        assert_eq!(
+            decode_hkl(0x0409_0419),
+            DecodedHkl {
+                lang_id: 0x0419,
+                layout: HklLayout::Klid(0x0409),
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_layout_variant_hkl() {
+        assert_eq!(
+            decode_hkl(0xF002_0409),
+            DecodedHkl {
+                lang_id: 0x0409,
+                layout: HklLayout::LayoutIdIndex(0x0002),
+            }
+        );
+    }
+
+    #[test]
+    fn decodes_ime_hkl() {
+        assert_eq!(
+            decode_hkl(0xE001_0411),
+            DecodedHkl {
+                lang_id: 0x0411,
+                layout: HklLayout::Klid(0xE001_0411),
+            }
+        );
+    }
+
+    #[test]
+    fn round_trips_hkl_formatting() {
+        for raw in [0x0409_0409u32, 0xF002_0409, 0xE001_0411] {
+            let hkl = HKL(raw as i32 as isize);
+
+            assert_eq!(parse_layout(&format_layout(&hkl)), Some(hkl));
+        }
+        assert_eq!(format_layout(&HKL(0x0409_0409)), "04090409");
+        assert_eq!(
+            parse_layout(" f0020409 "),
+            Some(HKL(0xF002_0409u32 as i32 as isize))
+        );
+        assert_eq!(parse_layout("US"), None);
+        assert_eq!(parse_layout(""), None);
+    }
+
+    #[test]
+    fn round_trips_layout_ids() {
+        for value in [
+            "en-US:00000409",
+            "en-US:00010409",
+            "ja-JP:E0010411",
+            "sr-Latn-RS:0000081A",
+            "und:us",
+            "und:us(dvorak)",
+            "de:de(nodeadkeys)",
+        ] {
+            let id: LayoutId = value.parse().unwrap();
+
+            assert_eq!(id.to_string(), value);
+            assert_eq!(id.to_string().parse(), Ok(id));
+        }
+    }
+
+    #[test]
+    fn normalizes_parsed_layout_ids() {
+        let id: LayoutId = " EN_us:00000409 ".parse().unwrap();
+        assert_eq!(id, us());
+        assert_eq!(id.to_string(), "en-US:00000409");
+
+        let id: LayoutId = "und:US(Dvorak)".parse().unwrap();
+        assert_eq!(id.to_string(), "und:us(dvorak)");
+    }
+
+    #[test]
+    fn rejects_invalid_layout_ids() {
+        for value in [
+            "",
+            "en-US",
+            "00000409",
+            ":00000409",
+            "e:00000409",
+            "en-US:",
+            "en US:00000409",
+            "en-US:us(",
+            "en-US:us()",
+            "en-US:us(dvorak",
+            "toolonglang:00000409",
+        ] {
+            assert_eq!(
+                value.parse::<LayoutId>(),
+                Err(ParseLayoutIdError),
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_name_and_short_code_in_equality() {
+        let renamed = us().with_name("My keyboard");
+
+        assert_eq!(renamed, us());
+        assert_eq!(us().short_code, "EN");
+        assert_eq!(
+            LayoutId::from_xkb_group("us(dvorak)").unwrap().short_code,
+            "US"
+        );
+    }
+
+    #[test]
+    fn normalizes_languages() {
+        assert_eq!(normalize_language("en-us"), "en-US");
+        assert_eq!(normalize_language("EN_US"), "en-US");
+        assert_eq!(normalize_language(" sr-latn-rs "), "sr-Latn-RS");
+        assert_eq!(normalize_language("ZH-HANS"), "zh-Hans");
+        assert_eq!(normalize_language("es-419"), "es-419");
+        assert_eq!(normalize_language("de"), "de");
+    }
+
+    #[test]
+    fn parses_xkb_groups() {
+        assert_eq!(LayoutVariant::from_xkb("us"), Some(xkb("us", None)));
+        assert_eq!(
+            LayoutVariant::from_xkb(" US(Dvorak) "),
+            Some(xkb("us", Some("dvorak")))
+        );
+        assert_eq!(
+            LayoutVariant::from_xkb("de(nodeadkeys)"),
+            Some(xkb("de", Some("nodeadkeys")))
+        );
+        assert_eq!(
+            LayoutVariant::from_xkb("ca(multix-2)"),
+            Some(xkb("ca", Some("multix-2")))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_xkb_groups() {
+        for group in [
+            "",
+            "()",
+            "(dvorak)",
+            "us(",
+            "us()",
+            "us(dvorak",
+            "us)dvorak(",
+            "us(dvorak)x",
+            "us(dv(orak))",
+            "us dvorak",
+            "us,ru",
+            "us:dvorak",
+        ] {
+            assert_eq!(LayoutVariant::from_xkb(group), None, "{}", group);
+        }
+    }
+
+    #[test]
+    fn tells_klids_from_xkb_groups() {
+        assert_eq!("00000409".parse(), Ok(LayoutVariant::Klid(0x0409)));
+        assert_eq!("e0010411".parse(), Ok(LayoutVariant::Klid(0xE001_0411)));
+        // Not eight digits, so an XKB name
+        assert_eq!("0409".parse(), Ok(xkb("0409", None)));
+        assert_eq!(LayoutVariant::Klid(0x0409).to_string(), "00000409");
+    }
+
+    #[test]
+    fn matches_full_id() {
+        assert!(us().matches("en-US:00000409"));
+        assert!(us().matches(" en-us:00000409 "));
+        assert!(!us().matches("en-GB:00000409"));
+        assert!(!us().matches("en-US:00010409"));
+    }
+
+    #[test]
+    fn matches_bare_variant() {
+        assert!(us().matches("00000409"));
+        assert!(!us().matches("00010409"));
+
+        let dvorak = LayoutId::from_xkb_group("us(dvorak)").unwrap();
+        assert!(dvorak.matches("us(dvorak)"));
+        assert!(!dvorak.matches("us"));
+    }
+
+    #[test]
+    fn matches_language_tag() {
+        assert!(us().matches("en-US"));
+        assert!(us().matches("en_us"));
+        assert!(!us().matches("en"));
+        assert!(!us().matches("ru-RU"));
+        assert!(!us().matches(""));
+    }
+
+    #[test]
+    fn caches_ids_of_installed_layouts() {
+        let (en, ru) = (HKL(0x0409_0409), HKL(0x0419_0419));
+        let installed = [en, ru];
+        let mut cache = LayoutIdCache::default();
+
+        assert_eq!(cache.get(&installed, &en), None);
+        cache.insert(&installed, &en, us());
+        assert_eq!(cache.get(&installed, &en), Some(us()));
+        assert_eq!(cache.get(&installed, &ru), None);
+    }
+
+    #[test]
+    fn forgets_ids_when_installed_layouts_change() {
+        let (en, ru) = (HKL(0x0409_0409), HKL(0x0419_0419));
+        let mut cache = LayoutIdCache::default();
+
+        cache.get(&[en, ru], &en);
+        cache.insert(&[en, ru], &en, us());
+        assert_eq!(cache.get(&[en], &en), None);
+
+        // Built before the change, so it may already be outdated
+        cache.insert(&[en, ru], &en, us());
+        assert_eq!(cache.get(&[en], &en), None);
+    }
+}
@@ -0,0 +1,253 @@
+use crate::config::config_dir;
+use crate::constants::LAYOUT_NAMES_FILE_NAME;
+use crate::layout_id::{LayoutId, LayoutVariant};
+use log::warn;
+use serde::Deserialize;
+use std::{collections::HashMap, fs, io::ErrorKind, sync::LazyLock};
+
+const BUNDLED_NAMES: &str = include_str!("../assets/layouts.toml");
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LayoutName {
+    /// E.g. "Russian — ЙЦУКЕН"
+    pub name: String,
+    /// Two letters shown in the tray icon, e.g. "RU"
+    pub short_code: String,
+}
+
+/// Names of layouts by their KLID or XKB group, e.g. `00000419` or
+/// `us(dvorak)`
+#[derive(Debug, Default)]
+pub struct LayoutNames {
+    names: HashMap<LayoutVariant, LayoutName>,
+}
+
+impl LayoutNames {
+    /// Parses a table of `["<variant>"]` entries with a name and a short
+    /// code. Entries with invalid variants are skipped.
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        let entries: HashMap<String, LayoutName> = toml::from_str(content)?;
+        let names = entries
+            .into_iter()
+            .filter_map(|(key, name)| match key.parse::<LayoutVariant>() {
+                Ok(variant) => Some((variant, name)),
+                Err(err) => {
+                    warn!("Skipping layout name for \"{}\": {}", key, err);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self { names })
+    }
+
+    /// Adds the entries of `other`, replacing the ones of the same layouts
+    pub fn merge(&mut self, other: Self) {
+        self.names.extend(other.names);
+    }
+
+    pub fn get(&self, variant: &LayoutVariant) -> Option<&LayoutName> {
+        self.names.get(variant).or_else(|| match variant {
+            // A variant the table doesn't know is still better named after
+            // its base layout than not at all
+            LayoutVariant::Xkb {
+                layout,
+                variant: Some(_),
+            } => self.names.get(&LayoutVariant::Xkb {
+                layout: layout.clone(),
+                variant: None,
+            }),
+            _ => None,
+        })
+    }
+
+    /// The id with the name and the short code from the table, if it has any
+    pub fn describe(&self, id: LayoutId) -> LayoutId {
+        match self.get(&id.variant) {
+            Some(name) => LayoutId {
+                name: name.name.clone(),
+                short_code: name.short_code.clone(),
+                ..id
+            },
+            None => id,
+        }
+    }
+
+    /// Bundled names with the ones from `%APPDATA%\CapsWitch\layouts.toml`
+    /// on top
+    fn load() -> Self {
+        let mut names = Self::parse(BUNDLED_NAMES).unwrap_or_else(|err| {
+            warn!("Failed to parse bundled layout names: {}", err);
+            Self::default()
+        });
+
+        let Some(path) = config_dir().map(|dir| dir.join(LAYOUT_NAMES_FILE_NAME)) else {
+            return names;
+        };
+        match fs::read_to_string(&path) {
+            Ok(content) => match Self::parse(&content) {
+                Ok(overrides) => names.merge(overrides),
+                Err(err) => warn!("Failed to parse {}: {}", path.display(), err),
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to read {}: {}", path.display(), err),
+        }
+
+        names
+    }
+}
+
+pub static LAYOUT_NAMES: LazyLock<LayoutNames> = LazyLock::new(LayoutNames::load);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn klid(klid: u32) -> LayoutVariant {
+        LayoutVariant::Klid(klid)
+    }
+
+    fn xkb(group: &str) -> LayoutVariant {
+        LayoutVariant::from_xkb(group).unwrap()
+    }
+
+    fn name(name: &str, short_code: &str) -> LayoutName {
+        LayoutName {
+            name: name.to_string(),
+            short_code: short_code.to_string(),
+        }
+    }
+
+    #[test]
+    fn bundled_keys_round_trip() {
+        let entries: HashMap<String, LayoutName> = toml::from_str(BUNDLED_NAMES).unwrap();
+        assert!(!entries.is_empty());
+
+        for (key, name) in &entries {
+            let variant: LayoutVariant = key.parse().unwrap();
+            assert_eq!(&variant.to_string(), key);
+            assert!(!name.name.is_empty(), "{}", key);
+            assert!(
+                (1..=2).contains(&name.short_code.chars().count()),
+                "{}",
+                key
+            );
+        }
+
+        let names = LayoutNames::parse(BUNDLED_NAMES).unwrap();
+        assert_eq!(names.names.len(), entries.len());
+        assert_eq!(names.get(&klid(0x0409)), Some(&name("English — US", "EN")));
+    }
+
+    #[test]
+    fn skips_invalid_keys() {
+        let names = LayoutNames::parse(
+            r#"
+            ["00000419"]
+            name = "Russian"
+            short_code = "RU"
+
+            ["not a layout"]
+            name = "Broken"
+            short_code = "XX"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(names.names.len(), 1);
+        assert_eq!(names.get(&klid(0x0419)), Some(&name("Russian", "RU")));
+    }
+
+    #[test]
+    fn rejects_entries_without_short_code() {
+        assert!(LayoutNames::parse(
+            r#"
+            ["00000419"]
+            name = "Russian"
+            "#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let mut names = LayoutNames::parse(
+            r#"
+            ["00000419"]
+            name = "Russian"
+            short_code = "RU"
+
+            ["00000409"]
+            name = "English"
+            short_code = "EN"
+            "#,
+        )
+        .unwrap();
+        let overrides = LayoutNames::parse(
+            r#"
+            ["00000419"]
+            name = "Русская"
+            short_code = "РУ"
+
+            ["us(dvorak)"]
+            name = "Dvorak"
+            short_code = "DV"
+            "#,
+        )
+        .unwrap();
+
+        names.merge(overrides);
+
+        assert_eq!(names.get(&klid(0x0419)), Some(&name("Русская", "РУ")));
+        assert_eq!(names.get(&klid(0x0409)), Some(&name("English", "EN")));
+        assert_eq!(names.get(&xkb("us(dvorak)")), Some(&name("Dvorak", "DV")));
+    }
+
+    #[test]
+    fn falls_back_to_xkb_base_layout() {
+        let names = LayoutNames::parse(
+            r#"
+            ["us"]
+            name = "English (US)"
+            short_code = "EN"
+
+            ["us(dvorak)"]
+            name = "English (Dvorak)"
+            short_code = "DV"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            names.get(&xkb("us(dvorak)")),
+            Some(&name("English (Dvorak)", "DV"))
+        );
+        assert_eq!(
+            names.get(&xkb("us(colemak)")),
+            Some(&name("English (US)", "EN"))
+        );
+        assert_eq!(names.get(&xkb("de(nodeadkeys)")), None);
+        assert_eq!(names.get(&xkb("de")), None);
+        assert_eq!(names.get(&klid(0x0409)), None);
+    }
+
+    #[test]
+    fn describes_known_layouts_only() {
+        let names = LayoutNames::parse(
+            r#"
+            ["00000419"]
+            name = "Russian"
+            short_code = "RU"
+            "#,
+        )
+        .unwrap();
+
+        let russian = names.describe(LayoutId::new("ru-RU", klid(0x0419)));
+        assert_eq!(russian.name, "Russian");
+        assert_eq!(russian.short_code, "RU");
+        assert_eq!(russian.language, "ru-RU");
+
+        let unknown = LayoutId::new("de-DE", klid(0x0407));
+        assert_eq!(names.describe(unknown.clone()).name, unknown.name);
+    }
+}
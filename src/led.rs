@@ -0,0 +1,274 @@
+#[cfg(windows)]
+use {
+    crate::config::CONFIG,
+    crate::constants::LAYOUT_SETTLE_DELAY_MS,
+    crate::switch::{get_foreground_layout, get_installed_layouts, is_layout_matching},
+    log::{error, warn},
+    std::{
+        mem,
+        sync::{
+            mpsc::{self, RecvTimeoutError, Sender},
+            OnceLock,
+        },
+        thread,
+        time::{Duration, Instant},
+    },
+    windows::{
+        core::*,
+        Win32::{
+            Devices::HumanInterfaceDevice::{
+                IOCTL_KEYBOARD_QUERY_INDICATORS, IOCTL_KEYBOARD_SET_INDICATORS,
+                KEYBOARD_CAPS_LOCK_ON, KEYBOARD_INDICATOR_PARAMETERS,
+            },
+            Foundation::{CloseHandle, HANDLE},
+            Storage::FileSystem::*,
+            System::IO::DeviceIoControl,
+            UI::TextServices::HKL,
+        },
+    },
+};
+
+/// Windows turns the LEDs back to the real lock states on its own, e.g. when
+/// NumLock is pressed, so the indicator is periodically restored.
+#[cfg(windows)]
+const LED_REFRESH_INTERVAL: Duration = Duration::from_millis(500);
+/// Keyboards plugged in later are only picked up by reopening the devices
+#[cfg(windows)]
+const KEYBOARD_RESCAN_INTERVAL: Duration = Duration::from_secs(10);
+/// Keyboard class devices are numbered from 0, with gaps left by unplugged
+/// keyboards, so a fixed range is tried
+const MAX_KEYBOARD_CLASSES: u32 = 32;
+
+#[cfg(windows)]
+static LED_SENDER: OnceLock<Sender<()>> = OnceLock::new();
+
+/// DOS device name, its path and the keyboard class device it points to
+fn get_keyboard_paths(index: u32) -> (String, String, String) {
+    let name = format!("CapsWitchKbd{index}");
+    let path = format!("\\\\.\\{name}");
+    let target = format!("\\Device\\KeyboardClass{index}");
+
+    (name, path, target)
+}
+
+/// Handle to a keyboard class device, which controls the LEDs
+#[cfg(windows)]
+struct KeyboardDevice {
+    handle: HANDLE,
+    name: HSTRING,
+    target: HSTRING,
+}
+
+#[cfg(windows)]
+impl KeyboardDevice {
+    fn open(index: u32) -> Result<Self> {
+        let (name, path, target) = get_keyboard_paths(index);
+        let (name, path, target) = (
+            HSTRING::from(name),
+            HSTRING::from(path),
+            HSTRING::from(target),
+        );
+        unsafe {
+            DefineDosDeviceW(DDD_RAW_TARGET_PATH, &name, &target)?;
+            let handle = CreateFileW(
+                &path,
+                0,
+                FILE_SHARE_READ | FILE_SHARE_WRITE,
+                None,
+                OPEN_EXISTING,
+                FILE_FLAGS_AND_ATTRIBUTES(0),
+                None,
+            );
+
+            match handle {
+                Ok(handle) => Ok(Self {
+                    handle,
+                    name,
+                    target,
+                }),
+                Err(err) => {
+                    remove_dos_device(&name, &target);
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    fn query_indicators(&self) -> Result<KEYBOARD_INDICATOR_PARAMETERS> {
+        let mut indicators = KEYBOARD_INDICATOR_PARAMETERS::default();
+        let mut bytes_returned = 0u32;
+        unsafe {
+            DeviceIoControl(
+                self.handle,
+                IOCTL_KEYBOARD_QUERY_INDICATORS,
+                None,
+                0,
+                Some(&mut indicators as *mut _ as *mut _),
+                mem::size_of::<KEYBOARD_INDICATOR_PARAMETERS>() as u32,
+                Some(&mut bytes_returned),
+                None,
+            )?;
+        }
+
+        Ok(indicators)
+    }
+
+    /// Lights or turns off the CapsLock LED keeping the other ones as they are
+    fn set_caps_led(&self, is_on: bool) -> Result<()> {
+        let mut indicators = self.query_indicators()?;
+        let is_lit = indicators.LedFlags & KEYBOARD_CAPS_LOCK_ON as u16 != 0;
+        if is_lit == is_on {
+            return Ok(());
+        }
+
+        indicators.LedFlags ^= KEYBOARD_CAPS_LOCK_ON as u16;
+        let mut bytes_returned = 0u32;
+        unsafe {
+            DeviceIoControl(
+                self.handle,
+                IOCTL_KEYBOARD_SET_INDICATORS,
+                Some(&indicators as *const _ as *const _),
+                mem::size_of::<KEYBOARD_INDICATOR_PARAMETERS>() as u32,
+                None,
+                0,
+                Some(&mut bytes_returned),
+                None,
+            )
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for KeyboardDevice {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = CloseHandle(self.handle);
+        }
+        remove_dos_device(&self.name, &self.target);
+    }
+}
+
+#[cfg(windows)]
+fn remove_dos_device(name: &HSTRING, target: &HSTRING) {
+    unsafe {
+        let _ = DefineDosDeviceW(
+            DDD_RAW_TARGET_PATH | DDD_REMOVE_DEFINITION | DDD_EXACT_MATCH_ON_REMOVE,
+            name,
+            target,
+        );
+    }
+}
+
+/// Opens the class devices of all connected keyboards
+#[cfg(windows)]
+fn open_keyboards() -> Vec<KeyboardDevice> {
+    (0..MAX_KEYBOARD_CLASSES)
+        .filter_map(|index| KeyboardDevice::open(index).ok())
+        .collect()
+}
+
+/// The configured primary layout or the first installed one
+#[cfg(windows)]
+fn get_primary_layout() -> Option<HKL> {
+    let layouts = get_installed_layouts();
+
+    match CONFIG.led.primary_layout.as_deref() {
+        Some(primary) => layouts
+            .into_iter()
+            .find(|layout| is_layout_matching(layout, primary)),
+        None => layouts.first().copied(),
+    }
+}
+
+/// Sets the LED of every keyboard, dropping the ones which were unplugged
+#[cfg(windows)]
+fn update_leds(devices: &mut Vec<KeyboardDevice>) {
+    let Some(primary_layout) = get_primary_layout() else {
+        return;
+    };
+
+    let is_secondary = get_foreground_layout() != primary_layout;
+    devices.retain(|device| match device.set_caps_led(is_secondary) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("Failed to set CapsLock LED of {}: {}", device.target, err);
+            false
+        }
+    });
+}
+
+/// Starts the thread keeping the CapsLock LED lit while a non-primary layout
+/// is active, if the option is enabled
+#[cfg(windows)]
+pub fn start() {
+    if !CONFIG.led.enabled {
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel::<()>();
+    if LED_SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        let mut devices = open_keyboards();
+        if devices.is_empty() {
+            error!("Failed to open keyboard devices for CapsLock LED");
+        }
+        let mut scanned_at = Instant::now();
+
+        loop {
+            if scanned_at.elapsed() >= KEYBOARD_RESCAN_INTERVAL {
+                // The DOS device names are reused, so the old ones go first
+                devices.clear();
+                devices = open_keyboards();
+                scanned_at = Instant::now();
+            }
+            update_leds(&mut devices);
+
+            match rx.recv_timeout(LED_REFRESH_INTERVAL) {
+                // Gives the system time to apply the layout or the CapsLock
+                // state it's been notified about
+                Ok(()) => thread::sleep(Duration::from_millis(LAYOUT_SETTLE_DELAY_MS.into())),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+/// Requests the LED to be updated after a layout switch or a CapsLock press.
+/// Never blocks, so it is safe to call from the keyboard hook.
+#[cfg(windows)]
+pub fn refresh() {
+    if let Some(tx) = LED_SENDER.get() {
+        let _ = tx.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn names_keyboard_class_devices() {
+        let (name, path, target) = get_keyboard_paths(0);
+        assert_eq!(name, "CapsWitchKbd0");
+        assert_eq!(path, r"\\.\CapsWitchKbd0");
+        assert_eq!(target, r"\Device\KeyboardClass0");
+
+        let (name, path, target) = get_keyboard_paths(12);
+        assert_eq!(name, "CapsWitchKbd12");
+        assert_eq!(path, r"\\.\CapsWitchKbd12");
+        assert_eq!(target, r"\Device\KeyboardClass12");
+    }
+
+    #[test]
+    fn names_each_keyboard_apart() {
+        let names = (0..MAX_KEYBOARD_CLASSES)
+            .map(|index| get_keyboard_paths(index).0)
+            .collect::<HashSet<_>>();
+        assert_eq!(names.len(), MAX_KEYBOARD_CLASSES as usize);
+    }
+}
@@ -0,0 +1,291 @@
+// Platform-independent modules are built elsewhere only to be tested, apart
+// from the ones `AppState` needs. Some have parts only the app itself uses,
+// which are dead code there.
+#[cfg(any(windows, test))]
+mod activation;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod autoload;
+#[cfg(any(windows, test))]
+mod bindings;
+#[cfg(any(windows, test))]
+mod caps;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod config;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod constants;
+mod decision;
+#[cfg(windows)]
+mod diagnostics;
+// Elsewhere only the bundle, which is built from already collected data
+#[cfg(all(not(windows), test))]
+mod diagnostics {
+    pub mod bundle;
+}
+#[cfg_attr(not(windows), allow(dead_code))]
+mod error;
+#[cfg(any(windows, test))]
+mod gesture;
+#[cfg(windows)]
+mod helper;
+// Elsewhere only the protocol, which is platform-neutral
+#[cfg(all(not(windows), test))]
+mod helper {
+    pub mod protocol;
+}
+#[cfg(windows)]
+mod hooks;
+#[cfg(any(windows, test))]
+mod icon;
+#[cfg(windows)]
+mod latency;
+#[cfg_attr(not(windows), allow(dead_code))]
+mod layout_id;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod layout_names;
+#[cfg(any(windows, test))]
+mod led;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod logging;
+mod mode;
+#[cfg(any(windows, test))]
+mod osd;
+mod settings;
+#[cfg(windows)]
+mod sound;
+#[cfg(windows)]
+mod switch;
+#[cfg(windows)]
+mod tray;
+#[cfg(windows)]
+mod utils;
+#[cfg(any(windows, test))]
+mod watchdog;
+#[cfg(any(windows, test))]
+mod word;
+
+pub use decision::{decide, HookDecision, HookKey};
+
+use arc_swap::ArcSwap;
+use log::error;
+use mode::{RecentLayouts, SwitchMode};
+use std::env;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU8, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::Instant;
+use windows::Win32::UI::TextServices::HKL;
+#[cfg(windows)]
+use {
+    caps::CapsCommand,
+    constants::{HELPER_LOG_FILE_NAME, LOG_FILE_NAME},
+    error::{CapsWitchError, Result},
+    log::info,
+    std::io,
+};
+
+/// State shared by the keyboard hook and the other threads. Fields are
+/// atomics or snapshots, so reading them in the hook never blocks, and a
+/// panic on another thread can't leave them poisoned.
+#[derive(Debug)]
+pub struct AppState {
+    _is_paused: AtomicBool,
+    _is_muted: AtomicBool,
+    /// Index in `SwitchMode::ALL`
+    _mode: AtomicU8,
+    /// HKLs are never 0, so 0 stands for no layout
+    _prev_layout: AtomicIsize,
+    /// Only used by the hook thread, so the lock is never contended
+    _recent_layouts: Mutex<RecentLayouts>,
+    _hold_origin: AtomicIsize,
+    _excluded_layouts: ArcSwap<Vec<HKL>>,
+}
+
+fn layout_to_raw(layout: Option<HKL>) -> isize {
+    layout.map_or(0, |layout| layout.0)
+}
+
+fn layout_from_raw(raw: isize) -> Option<HKL> {
+    (raw != 0).then_some(HKL(raw))
+}
+
+impl AppState {
+    pub fn new(args: Vec<String>) -> Self {
+        let mode = SwitchMode::from_args(&args)
+            .or_else(settings::load_mode)
+            .unwrap_or(SwitchMode::Circular);
+
+        Self {
+            _is_paused: AtomicBool::new(false),
+            _is_muted: AtomicBool::new(settings::load_muted()),
+            _mode: AtomicU8::new(mode.index()),
+            _prev_layout: AtomicIsize::new(0),
+            _recent_layouts: Mutex::new(RecentLayouts::default()),
+            _hold_origin: AtomicIsize::new(0),
+            _excluded_layouts: ArcSwap::from_pointee(settings::load_excluded_layouts()),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self._is_paused.load(Ordering::Acquire)
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self._is_muted.load(Ordering::Acquire)
+    }
+
+    pub fn mode(&self) -> SwitchMode {
+        SwitchMode::from_index(self._mode.load(Ordering::Acquire))
+    }
+
+    pub fn prev_layout(&self) -> Option<HKL> {
+        layout_from_raw(self._prev_layout.load(Ordering::Acquire))
+    }
+
+    pub fn set_prev_layout(&self, layout: Option<HKL>) {
+        self._prev_layout
+            .store(layout_to_raw(layout), Ordering::Release);
+    }
+
+    pub fn next_recent_layout(&self, current: HKL, installed: &[HKL]) -> Option<HKL> {
+        let mut recent_layouts = self
+            ._recent_layouts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        recent_layouts.next(current, installed, Instant::now())
+    }
+
+    pub fn hold_origin(&self) -> Option<HKL> {
+        layout_from_raw(self._hold_origin.load(Ordering::Acquire))
+    }
+
+    pub fn set_hold_origin(&self, layout: Option<HKL>) {
+        self._hold_origin
+            .store(layout_to_raw(layout), Ordering::Release);
+    }
+
+    pub fn excluded_layouts(&self) -> Arc<Vec<HKL>> {
+        self._excluded_layouts.load_full()
+    }
+
+    /// Includes or excludes the layout from rotation. Returns `true` if the
+    /// layout is now excluded.
+    pub fn toggle_excluded_layout(&self, layout: HKL) -> bool {
+        let previous = self._excluded_layouts.rcu(|excluded_layouts| {
+            let mut excluded_layouts = Vec::clone(excluded_layouts);
+            match excluded_layouts.iter().position(|&l| l == layout) {
+                Some(index) => {
+                    excluded_layouts.remove(index);
+                }
+                None => excluded_layouts.push(layout),
+            }
+            excluded_layouts
+        });
+        if let Err(err) = settings::save_excluded_layouts(&self.excluded_layouts()) {
+            error!("{}", err);
+        }
+
+        !previous.contains(&layout)
+    }
+
+    pub fn toggle_pause(&self) -> bool {
+        !self._is_paused.fetch_xor(true, Ordering::AcqRel)
+    }
+
+    pub fn toggle_mute(&self) -> bool {
+        let is_muted = !self._is_muted.fetch_xor(true, Ordering::AcqRel);
+        if let Err(err) = settings::save_muted(is_muted) {
+            error!("{}", err);
+        }
+
+        is_muted
+    }
+
+    pub fn set_mode(&self, mode: SwitchMode) {
+        self._mode.store(mode.index(), Ordering::Release);
+        if let Err(err) = settings::save_mode(mode) {
+            error!("{}", err);
+        }
+    }
+}
+
+pub static APP_STATE: LazyLock<AppState> = LazyLock::new(|| AppState::new(env::args().collect()));
+
+#[cfg(windows)]
+fn run() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    // Commands are handed to the running app, they don't start another one
+    match CapsCommand::from_args(&args) {
+        Some(Ok(command)) => return helper::send_caps_command(command),
+        Some(Err(_)) => {
+            return Err(CapsWitchError::Command(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected --caps=on, --caps=off or --caps=toggle",
+            )))
+        }
+        None => {}
+    }
+    if latency::is_stats_requested(&args) {
+        utils::print_to_console(&helper::request_stats()?);
+        return Ok(());
+    }
+    if helper::is_helper_process(&args) {
+        logging::init(&args, HELPER_LOG_FILE_NAME);
+        info!("Starting elevated helper");
+        return helper::run_server();
+    }
+
+    logging::init(&args, LOG_FILE_NAME);
+    info!(
+        "Starting {} {}",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+
+    match utils::check_for_another_instance() {
+        Ok(_) => {}
+        Err(CapsWitchError::AnotherInstance) => return Err(CapsWitchError::AnotherInstance),
+        // Running twice is less harmful than not running at all
+        Err(err) => error!("{}", err),
+    }
+    let _state = &*APP_STATE;
+
+    autoload::repair_stale_autoload();
+    hooks::start();
+    osd::start();
+    led::start();
+    sound::start();
+    latency::start();
+    activation::start();
+    helper::start();
+    helper::start_commands();
+    tray::create_tray();
+    watchdog::start();
+    switch::process_switch()
+}
+
+/// Runs the app, or the elevated helper if started as one
+#[cfg(windows)]
+pub fn main() -> ExitCode {
+    let code = match run() {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            error::notify(&err);
+            ExitCode::FAILURE
+        }
+    };
+    logging::flush();
+
+    code
+}
+
+#[cfg(not(windows))]
+pub fn main() -> ExitCode {
+    eprintln!("{} only runs on Windows", env!("CARGO_PKG_NAME"));
+    ExitCode::FAILURE
+}
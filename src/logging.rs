@@ -0,0 +1,358 @@
+use crate::config::local_data_dir;
+use crate::constants::LOG_FILE_NAME;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Sender, SyncSender},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Size after which the log file is rotated
+const MAX_LOG_SIZE: u64 = 1024 * 1024;
+/// Number of rotated files kept next to the current one
+const MAX_LOG_BACKUPS: usize = 3;
+
+/// Log file that is renamed to `<name>.1` when it grows too large, shifting
+/// older backups up to `MAX_LOG_BACKUPS`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self { path, file, size })
+    }
+
+    fn backup_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        for index in (1..MAX_LOG_BACKUPS).rev() {
+            let from = self.backup_path(index);
+            if from.exists() {
+                fs::rename(&from, self.backup_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.backup_path(1))?;
+
+        *self = Self::open(self.path.clone())?;
+
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+}
+
+enum Message {
+    Line(String),
+    /// Answered once the lines sent before are written
+    Flush(SyncSender<()>),
+}
+
+/// Writes the lines on its own thread, so logging never waits for the disk
+/// or the console, e.g. in the keyboard hook
+fn spawn_writer(mut file: Option<RotatingFile>) -> Sender<Message> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for message in rx {
+            match message {
+                Message::Line(line) => {
+                    // Only seen when started from a console
+                    eprint!("{}", line);
+                    if let Some(file) = &mut file {
+                        let _ = file.write_line(&line);
+                    }
+                }
+                Message::Flush(done) => {
+                    if let Some(file) = &mut file {
+                        let _ = file.file.flush();
+                    }
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+
+    tx
+}
+
+struct Logger {
+    level: LevelFilter,
+    writer: Sender<Message>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format_record(
+            SystemTime::now(),
+            record.level(),
+            record.target(),
+            &record.args().to_string(),
+        );
+        let _ = self.writer.send(Message::Line(line));
+    }
+
+    /// Waits for the writer to catch up
+    fn flush(&self) {
+        let (done_tx, done_rx) = mpsc::sync_channel(1);
+        if self.writer.send(Message::Flush(done_tx)).is_ok() {
+            let _ = done_rx.recv();
+        }
+    }
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Howard Hinnant's algorithm, with eras of 400 years starting in March
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// UTC time with milliseconds, e.g. `2024-05-01T09:30:12.345Z`
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let secs_of_day = secs.rem_euclid(86_400);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis(),
+    )
+}
+
+/// One log line: timestamp, level, module and message, e.g.
+/// `2024-05-01T09:30:12.345Z ERROR CapsWitch::switch: Failed to ...`
+fn format_record(time: SystemTime, level: Level, target: &str, message: &str) -> String {
+    format!(
+        "{} {:<5} {}: {}\n",
+        format_timestamp(time),
+        level,
+        target,
+        message.replace('\n', " "),
+    )
+}
+
+/// Writes the lines logged so far, e.g. before the process exits
+pub fn flush() {
+    log::logger().flush();
+}
+
+/// `%LOCALAPPDATA%\CapsWitch\Logs`
+pub fn log_dir() -> Option<PathBuf> {
+    local_data_dir().map(|dir| dir.join("Logs"))
+}
+
+pub fn log_path() -> Option<PathBuf> {
+    log_dir().map(|dir| dir.join(LOG_FILE_NAME))
+}
+
+/// Level set with `--log-level=<level>`, e.g. `--log-level=debug`
+fn parse_level(args: &[String]) -> Option<LevelFilter> {
+    args.iter()
+        .find_map(|arg| arg.strip_prefix("--log-level="))
+        .and_then(|level| level.parse().ok())
+}
+
+fn open_log_file(dir: &Path, file_name: &str) -> std::io::Result<RotatingFile> {
+    fs::create_dir_all(dir)?;
+
+    RotatingFile::open(dir.join(file_name))
+}
+
+/// Sets up background logging to the log file and stderr.
+///
+/// Panics are logged too, as they would otherwise vanish without a console.
+/// Every process of the app writes its own `file_name`, as rotation can't be
+/// shared.
+pub fn init(args: &[String], file_name: &str) {
+    let level = parse_level(args).unwrap_or(DEFAULT_LEVEL);
+    let file = log_dir().and_then(|dir| match open_log_file(&dir, file_name) {
+        Ok(file) => Some(file),
+        Err(err) => {
+            eprintln!("Failed to open log file in {}: {}", dir.display(), err);
+            None
+        }
+    });
+
+    let writer = spawn_writer(file);
+    if log::set_boxed_logger(Box::new(Logger { level, writer })).is_ok() {
+        log::set_max_level(level);
+    }
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        log::error!("{}", info);
+        // The process may be about to abort
+        flush();
+        default_hook(info);
+    }));
+}
+
+/// Records logged on the current thread, for tests checking what is logged.
+/// Other tests' records are kept apart as tests run on threads of their own.
+#[cfg(test)]
+pub mod capture {
+    use log::{Level, Log, Metadata, Record};
+    use std::{cell::RefCell, sync::Once};
+
+    thread_local! {
+        static RECORDS: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+    }
+
+    struct CaptureLogger;
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &Record) {
+            RECORDS.with_borrow_mut(|records| {
+                records.push((record.level(), record.args().to_string()))
+            });
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Installs the logger once per test binary and forgets earlier records
+    pub fn start() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            if log::set_boxed_logger(Box::new(CaptureLogger)).is_ok() {
+                log::set_max_level(log::LevelFilter::Trace);
+            }
+        });
+        RECORDS.with_borrow_mut(Vec::clear);
+    }
+
+    /// Records logged since `start`
+    pub fn take() -> Vec<(Level, String)> {
+        RECORDS.with_borrow_mut(std::mem::take)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    fn log(logger: &Logger, level: Level, message: &str) {
+        logger.log(
+            &Record::builder()
+                .level(level)
+                .target("capswitch::tests")
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn formats_records() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_714_555_812_345);
+
+        assert_eq!(
+            format_record(time, Level::Warn, "capswitch::switch", "first\nsecond"),
+            "2024-05-01T09:30:12.345Z WARN  capswitch::switch: first second\n"
+        );
+    }
+
+    #[test]
+    fn writes_records_in_background() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = open_log_file(dir.path(), LOG_FILE_NAME).unwrap();
+        let logger = Logger {
+            level: LevelFilter::Info,
+            writer: spawn_writer(Some(file)),
+        };
+
+        for i in 0..100 {
+            log(&logger, Level::Info, &format!("record {}", i));
+        }
+        log(&logger, Level::Debug, "filtered out");
+        logger.flush();
+
+        let content = fs::read_to_string(dir.path().join(LOG_FILE_NAME)).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 100);
+        for (i, line) in lines.iter().enumerate() {
+            assert!(line.ends_with(&format!("INFO  capswitch::tests: record {}", i)));
+        }
+    }
+
+    #[test]
+    fn flushes_without_file() {
+        let logger = Logger {
+            level: LevelFilter::Info,
+            writer: spawn_writer(None),
+        };
+
+        log(&logger, Level::Info, "only on stderr");
+        logger.flush();
+    }
+
+    #[test]
+    fn rotates_large_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = open_log_file(dir.path(), LOG_FILE_NAME).unwrap();
+        let line = format!("{}\n", "x".repeat(1023));
+
+        for _ in 0..(MAX_LOG_SIZE as usize / line.len()) * (MAX_LOG_BACKUPS + 2) {
+            file.write_line(&line).unwrap();
+        }
+
+        assert!(fs::metadata(dir.path().join(LOG_FILE_NAME)).unwrap().len() <= MAX_LOG_SIZE);
+        for index in 1..=MAX_LOG_BACKUPS {
+            assert!(file.backup_path(index).exists(), "{}", index);
+        }
+        assert!(!file.backup_path(MAX_LOG_BACKUPS + 1).exists());
+    }
+}
@@ -0,0 +1,389 @@
+use std::time::{Duration, Instant};
+use windows::Win32::UI::TextServices::HKL;
+
+/// Presses of CapsLock closer to each other than this in the MRU mode go
+/// further down the list of recent layouts instead of toggling between two.
+const MRU_CHAIN_TIMEOUT: Duration = Duration::from_millis(1000);
+
+const MODE_ARG_PREFIX: &str = "--mode=";
+/// Argument used by versions that only had the Previous mode
+const LEGACY_PREVIOUS_ARG: &str = "--previous";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwitchMode {
+    /// Cycles through all installed layouts, like Windows does
+    Circular,
+    /// Toggles between the two most recently used layouts
+    Previous,
+    /// Goes through the layouts from the most to the least recently used
+    Mru,
+    /// Cycles only through the layouts included in rotation
+    Subset,
+    /// Switches to the next layout while CapsLock is held down
+    Hold,
+}
+
+impl SwitchMode {
+    pub const ALL: [SwitchMode; 5] = [
+        SwitchMode::Circular,
+        SwitchMode::Previous,
+        SwitchMode::Mru,
+        SwitchMode::Subset,
+        SwitchMode::Hold,
+    ];
+
+    /// Position in `ALL`, to be stored in an atomic
+    pub fn index(self) -> u8 {
+        SwitchMode::ALL
+            .iter()
+            .position(|&mode| mode == self)
+            .unwrap_or(0) as u8
+    }
+
+    pub fn from_index(index: u8) -> Self {
+        SwitchMode::ALL
+            .get(usize::from(index))
+            .copied()
+            .unwrap_or(SwitchMode::Circular)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SwitchMode::Circular => "circular",
+            SwitchMode::Previous => "previous",
+            SwitchMode::Mru => "mru",
+            SwitchMode::Subset => "subset",
+            SwitchMode::Hold => "hold",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SwitchMode::Circular => "Circular",
+            SwitchMode::Previous => "Previous",
+            SwitchMode::Mru => "Most recently used",
+            SwitchMode::Subset => "Subset",
+            SwitchMode::Hold => "Hold",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        SwitchMode::ALL
+            .into_iter()
+            .find(|mode| mode.as_str().eq_ignore_ascii_case(value.trim()))
+    }
+
+    /// Reads the mode from `--mode=<name>` or the legacy `--previous` argument
+    pub fn from_args(args: &[String]) -> Option<Self> {
+        args.iter().skip(1).find_map(|arg| {
+            if arg == LEGACY_PREVIOUS_ARG {
+                Some(SwitchMode::Previous)
+            } else {
+                arg.strip_prefix(MODE_ARG_PREFIX)
+                    .and_then(SwitchMode::parse)
+            }
+        })
+    }
+
+    /// Startup argument preserving the mode, `None` for the default one
+    pub fn arg(&self) -> Option<String> {
+        match self {
+            SwitchMode::Circular => None,
+            _ => Some(format!("{}{}", MODE_ARG_PREFIX, self.as_str())),
+        }
+    }
+}
+
+/// Recently used layouts, the most recent first, and the position in the
+/// current chain of quick CapsLock presses.
+#[derive(Debug, Default)]
+pub struct RecentLayouts {
+    layouts: Vec<HKL>,
+    depth: usize,
+    last_switch: Option<Instant>,
+}
+
+impl RecentLayouts {
+    /// Picks the layout to switch to from `current`. The first press goes to
+    /// the most recently used layout, each chained press goes one further and
+    /// wraps around back to `current`.
+    pub fn next(&mut self, current: HKL, installed: &[HKL], now: Instant) -> Option<HKL> {
+        let is_chained = self
+            .last_switch
+            .is_some_and(|last_switch| now.duration_since(last_switch) < MRU_CHAIN_TIMEOUT);
+        if !is_chained {
+            self.layouts.retain(|&layout| layout != current);
+            self.layouts.insert(0, current);
+            self.depth = 0;
+        }
+
+        self.layouts.retain(|layout| installed.contains(layout));
+        for layout in installed {
+            if !self.layouts.contains(layout) {
+                self.layouts.push(*layout);
+            }
+        }
+        if self.layouts.len() < 2 {
+            return None;
+        }
+
+        self.depth = (self.depth + 1) % self.layouts.len();
+        self.last_switch = Some(now);
+
+        Some(self.layouts[self.depth])
+    }
+}
+
+/// Way through the installed layouts, in the order Windows lists them
+#[cfg_attr(not(windows), allow(dead_code))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// Next layout after `current` in `layouts` skipping the `excluded` ones.
+/// Returns `None` if there is no other layout to switch to.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn next_in_rotation(layouts: &[HKL], current: HKL, excluded: &[HKL]) -> Option<HKL> {
+    let start = layouts
+        .iter()
+        .position(|&l| l == current)
+        .map_or(0, |i| i + 1);
+
+    layouts
+        .iter()
+        .cycle()
+        .skip(start)
+        .take(layouts.len())
+        .find(|&&l| l != current && !excluded.contains(&l))
+        .copied()
+}
+
+/// Like `next_in_rotation`, but in either direction
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn step_in_rotation(
+    layouts: &[HKL],
+    current: HKL,
+    excluded: &[HKL],
+    direction: Direction,
+) -> Option<HKL> {
+    match direction {
+        Direction::Forward => next_in_rotation(layouts, current, excluded),
+        Direction::Backward => {
+            let reversed: Vec<HKL> = layouts.iter().rev().copied().collect();
+            next_in_rotation(&reversed, current, excluded)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: HKL = HKL(0x0409_0409);
+    const RU: HKL = HKL(0x0419_0419);
+    const DE: HKL = HKL(0x0407_0407);
+    const FR: HKL = HKL(0x040C_040C);
+    const LAYOUTS: [HKL; 4] = [US, RU, DE, FR];
+
+    #[test]
+    fn next_in_rotation_goes_forward_and_wraps() {
+        assert_eq!(next_in_rotation(&LAYOUTS, US, &[]), Some(RU));
+        assert_eq!(next_in_rotation(&LAYOUTS, DE, &[]), Some(FR));
+        assert_eq!(next_in_rotation(&LAYOUTS, FR, &[]), Some(US));
+    }
+
+    #[test]
+    fn next_in_rotation_skips_excluded() {
+        assert_eq!(next_in_rotation(&LAYOUTS, US, &[RU]), Some(DE));
+        assert_eq!(next_in_rotation(&LAYOUTS, DE, &[FR, US]), Some(RU));
+    }
+
+    #[test]
+    fn next_in_rotation_from_unlisted_layout_starts_at_first() {
+        let unlisted = HKL(0x0411_0411);
+
+        assert_eq!(next_in_rotation(&LAYOUTS, unlisted, &[]), Some(US));
+        assert_eq!(next_in_rotation(&LAYOUTS, unlisted, &[US, RU]), Some(DE));
+    }
+
+    #[test]
+    fn next_in_rotation_from_excluded_layout_leaves_it() {
+        assert_eq!(next_in_rotation(&LAYOUTS, RU, &[RU]), Some(DE));
+    }
+
+    #[test]
+    fn next_in_rotation_without_other_layouts() {
+        assert_eq!(next_in_rotation(&LAYOUTS, US, &[RU, DE, FR]), None);
+        assert_eq!(next_in_rotation(&LAYOUTS, US, &LAYOUTS), None);
+        assert_eq!(next_in_rotation(&[US], US, &[]), None);
+        assert_eq!(next_in_rotation(&[], US, &[]), None);
+    }
+
+    #[test]
+    fn next_in_rotation_with_single_other_layout() {
+        assert_eq!(next_in_rotation(&[US], RU, &[]), Some(US));
+        assert_eq!(next_in_rotation(&[US, RU], RU, &[]), Some(US));
+    }
+
+    #[test]
+    fn step_in_rotation_goes_backward_and_wraps() {
+        let step = |current, excluded: &[HKL]| {
+            step_in_rotation(&LAYOUTS, current, excluded, Direction::Backward)
+        };
+
+        assert_eq!(step(RU, &[]), Some(US));
+        assert_eq!(step(US, &[]), Some(FR));
+        assert_eq!(step(US, &[FR]), Some(DE));
+        assert_eq!(step(US, &[RU, DE, FR]), None);
+    }
+
+    #[test]
+    fn step_in_rotation_forward_matches_next() {
+        for current in LAYOUTS {
+            assert_eq!(
+                step_in_rotation(&LAYOUTS, current, &[DE], Direction::Forward),
+                next_in_rotation(&LAYOUTS, current, &[DE])
+            );
+        }
+    }
+
+    #[test]
+    fn step_in_rotation_backward_from_unlisted_layout_starts_at_last() {
+        let unlisted = HKL(0x0411_0411);
+
+        assert_eq!(
+            step_in_rotation(&LAYOUTS, unlisted, &[], Direction::Backward),
+            Some(FR)
+        );
+    }
+
+    #[test]
+    fn step_in_rotation_backward_and_forward_undo_each_other() {
+        for current in LAYOUTS {
+            let next = step_in_rotation(&LAYOUTS, current, &[], Direction::Forward).unwrap();
+            assert_eq!(
+                step_in_rotation(&LAYOUTS, next, &[], Direction::Backward),
+                Some(current)
+            );
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        ["CapsWitch.exe"]
+            .iter()
+            .chain(args)
+            .map(|arg| arg.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn mode_round_trips_through_arg() {
+        for mode in SwitchMode::ALL {
+            let parsed = match mode.arg() {
+                Some(arg) => SwitchMode::from_args(&args(&[&arg])),
+                None => SwitchMode::from_args(&args(&[])),
+            };
+            // The default mode has no argument, so none is read back
+            let expected = (mode != SwitchMode::Circular).then_some(mode);
+            assert_eq!(parsed, expected, "{:?}", mode);
+        }
+        assert_eq!(
+            SwitchMode::from_args(&args(&["--mode=circular"])),
+            Some(SwitchMode::Circular)
+        );
+    }
+
+    #[test]
+    fn reads_legacy_previous_arg() {
+        assert_eq!(
+            SwitchMode::from_args(&args(&["--previous"])),
+            Some(SwitchMode::Previous)
+        );
+        assert_eq!(
+            SwitchMode::from_args(&args(&["--log-level=debug", "--previous"])),
+            Some(SwitchMode::Previous)
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_modes() {
+        assert_eq!(SwitchMode::parse("random"), None);
+        assert_eq!(SwitchMode::parse(""), None);
+        assert_eq!(SwitchMode::parse(" MRU "), Some(SwitchMode::Mru));
+        assert_eq!(SwitchMode::from_args(&args(&["--mode=random"])), None);
+        assert_eq!(
+            SwitchMode::from_args(&args(&["--mode=random", "--mode=hold"])),
+            Some(SwitchMode::Hold)
+        );
+        // The program name is never read as an argument
+        assert_eq!(SwitchMode::from_args(&["--previous".to_string()]), None);
+    }
+
+    #[test]
+    fn mode_index_round_trips() {
+        for mode in SwitchMode::ALL {
+            assert_eq!(SwitchMode::from_index(mode.index()), mode);
+        }
+        assert_eq!(SwitchMode::from_index(u8::MAX), SwitchMode::Circular);
+    }
+
+    #[test]
+    fn recent_layouts_toggle_between_two_after_timeout() {
+        let mut recent = RecentLayouts::default();
+        let start = Instant::now();
+        let later = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(recent.next(US, &LAYOUTS, start), Some(RU));
+        assert_eq!(recent.next(RU, &LAYOUTS, later(2)), Some(US));
+        assert_eq!(recent.next(US, &LAYOUTS, later(4)), Some(RU));
+    }
+
+    #[test]
+    fn recent_layouts_chain_goes_down_the_list_and_wraps() {
+        let mut recent = RecentLayouts::default();
+        let start = Instant::now();
+        let chained =
+            |presses: u32| start + Duration::from_secs(2) + presses * MRU_CHAIN_TIMEOUT / 2;
+
+        // Used last: RU, then US
+        assert_eq!(recent.next(US, &LAYOUTS, start), Some(RU));
+        assert_eq!(recent.next(RU, &LAYOUTS, chained(0)), Some(US));
+        assert_eq!(recent.next(US, &LAYOUTS, chained(1)), Some(DE));
+        assert_eq!(recent.next(DE, &LAYOUTS, chained(2)), Some(FR));
+        assert_eq!(recent.next(FR, &LAYOUTS, chained(3)), Some(RU));
+    }
+
+    #[test]
+    fn recent_layouts_chain_expires() {
+        let mut recent = RecentLayouts::default();
+        let start = Instant::now();
+
+        assert_eq!(recent.next(US, &LAYOUTS, start), Some(RU));
+        assert_eq!(
+            recent.next(RU, &LAYOUTS, start + MRU_CHAIN_TIMEOUT / 2),
+            Some(DE)
+        );
+        // The chain only passed through RU, so the US it started from is
+        // the most recent after DE
+        assert_eq!(
+            recent.next(DE, &LAYOUTS, start + MRU_CHAIN_TIMEOUT * 3),
+            Some(US)
+        );
+    }
+
+    #[test]
+    fn recent_layouts_follow_installed_layouts() {
+        let mut recent = RecentLayouts::default();
+        let start = Instant::now();
+
+        assert_eq!(recent.next(US, &LAYOUTS, start), Some(RU));
+        let later = |timeouts| start + MRU_CHAIN_TIMEOUT * timeouts;
+        // US was removed, DE is left
+        assert_eq!(recent.next(RU, &[RU, DE], later(2)), Some(DE));
+        // FR was added, after the ones used before
+        assert_eq!(recent.next(DE, &[DE, RU, FR], later(4)), Some(RU));
+        assert_eq!(recent.next(RU, &[RU], later(6)), None);
+    }
+}
@@ -0,0 +1,453 @@
+#[cfg(target_os = "linux")]
+pub mod x11;
+
+use crate::config::OsdPosition;
+use windows::Win32::Foundation::{POINT, RECT, SIZE};
+#[cfg(windows)]
+use {
+    crate::config::CONFIG,
+    crate::constants::LAYOUT_SETTLE_DELAY_MS,
+    crate::switch::{get_foreground_layout, get_layout_display_name},
+    log::error,
+    std::{
+        cell::RefCell,
+        mem,
+        sync::atomic::{AtomicIsize, Ordering},
+        thread,
+    },
+    windows::{
+        core::*,
+        Win32::{
+            Foundation::*,
+            Graphics::Gdi::*,
+            System::LibraryLoader::GetModuleHandleW,
+            UI::{TextServices::HKL, WindowsAndMessaging::*},
+        },
+    },
+};
+
+/// Posted to the indicator window with the new HKL in `lparam`, or 0 if the
+/// layout has to be queried from the foreground window
+#[cfg(windows)]
+const WM_SHOW_LAYOUT: u32 = WM_APP + 1;
+/// Posted to the indicator window with 1 in `wparam` if the app was paused,
+/// or 0 if it was resumed
+#[cfg(windows)]
+const WM_SHOW_PAUSE: u32 = WM_APP + 2;
+
+#[cfg(windows)]
+const HIDE_TIMER_ID: usize = 1;
+#[cfg(windows)]
+const RESOLVE_TIMER_ID: usize = 2;
+
+#[cfg(windows)]
+const FONT_HEIGHT: i32 = 28;
+const PADDING_X: i32 = 24;
+const PADDING_Y: i32 = 14;
+/// Distance from the screen edges or the caret
+const MARGIN: i32 = 48;
+
+#[cfg(windows)]
+const BACKGROUND_COLOR: COLORREF = COLORREF(0x00202020);
+#[cfg(windows)]
+const TEXT_COLOR: COLORREF = COLORREF(0x00FFFFFF);
+
+#[cfg(windows)]
+static OSD_WINDOW: AtomicIsize = AtomicIsize::new(0);
+
+#[cfg(windows)]
+thread_local! {
+    static OSD_TEXT: RefCell<Vec<u16>> = const { RefCell::new(Vec::new()) };
+    static OSD_FONT: HFONT = unsafe {
+        CreateFontW(
+            FONT_HEIGHT,
+            0,
+            0,
+            0,
+            FW_SEMIBOLD.0 as i32,
+            0,
+            0,
+            0,
+            DEFAULT_CHARSET.0 as u32,
+            OUT_DEFAULT_PRECIS.0 as u32,
+            CLIP_DEFAULT_PRECIS.0 as u32,
+            CLEARTYPE_QUALITY.0 as u32,
+            0,
+            w!("Segoe UI"),
+        )
+    };
+}
+
+/// Top-left corner of the indicator of `size` within the monitor `work_area`
+fn get_osd_origin(
+    work_area: &RECT,
+    size: &SIZE,
+    position: OsdPosition,
+    caret: Option<POINT>,
+) -> POINT {
+    let center_x = (work_area.left + work_area.right - size.cx) / 2;
+    let center_y = (work_area.top + work_area.bottom - size.cy) / 2;
+
+    let origin = match (position, caret) {
+        (OsdPosition::Caret, Some(caret)) => POINT {
+            x: caret.x - size.cx / 2,
+            y: caret.y + MARGIN / 2,
+        },
+        (OsdPosition::Center, _) | (OsdPosition::Caret, None) => POINT {
+            x: center_x,
+            y: center_y,
+        },
+        (OsdPosition::Top, _) => POINT {
+            x: center_x,
+            y: work_area.top + MARGIN,
+        },
+        (OsdPosition::Bottom, _) => POINT {
+            x: center_x,
+            y: work_area.bottom - MARGIN - size.cy,
+        },
+    };
+
+    // Keeps the indicator on screen, e.g. when the caret is at the bottom
+    POINT {
+        x: origin.x.clamp(
+            work_area.left,
+            (work_area.right - size.cx).max(work_area.left),
+        ),
+        y: origin.y.clamp(
+            work_area.top,
+            (work_area.bottom - size.cy).max(work_area.top),
+        ),
+    }
+}
+
+/// Screen position of the text cursor in the foreground window, if it has one
+#[cfg(windows)]
+fn get_caret_position(foreground: HWND) -> Option<POINT> {
+    unsafe {
+        let thread_id = GetWindowThreadProcessId(foreground, None);
+        let mut info = GUITHREADINFO {
+            cbSize: mem::size_of::<GUITHREADINFO>() as u32,
+            ..Default::default()
+        };
+        GetGUIThreadInfo(thread_id, &mut info).ok()?;
+        if info.hwndCaret.0 == 0 {
+            return None;
+        }
+
+        let mut point = POINT {
+            x: info.rcCaret.left,
+            y: info.rcCaret.bottom,
+        };
+        ClientToScreen(info.hwndCaret, &mut point)
+            .as_bool()
+            .then_some(point)
+    }
+}
+
+#[cfg(windows)]
+fn get_text_size(hwnd: HWND, text: &[u16]) -> SIZE {
+    let mut size = SIZE::default();
+    unsafe {
+        let hdc = GetDC(hwnd);
+        let prev_font = SelectObject(hdc, OSD_FONT.with(|font| *font));
+        GetTextExtentPoint32W(hdc, text, &mut size);
+        SelectObject(hdc, prev_font);
+        ReleaseDC(hwnd, hdc);
+    }
+
+    size
+}
+
+#[cfg(windows)]
+fn display_layout(hwnd: HWND, layout: HKL) {
+    display_text(hwnd, &get_layout_display_name(&layout));
+}
+
+#[cfg(windows)]
+fn display_text(hwnd: HWND, text: &str) {
+    let text: Vec<u16> = text.encode_utf16().collect();
+    let text_size = get_text_size(hwnd, &text);
+    OSD_TEXT.with(|osd_text| *osd_text.borrow_mut() = text);
+
+    let size = SIZE {
+        cx: text_size.cx + 2 * PADDING_X,
+        cy: text_size.cy + 2 * PADDING_Y,
+    };
+
+    unsafe {
+        let foreground = GetForegroundWindow();
+        let mut monitor_info = MONITORINFO {
+            cbSize: mem::size_of::<MONITORINFO>() as u32,
+            ..Default::default()
+        };
+        let monitor = MonitorFromWindow(foreground, MONITOR_DEFAULTTONEAREST);
+        if !GetMonitorInfoW(monitor, &mut monitor_info).as_bool() {
+            return;
+        }
+
+        let caret = match CONFIG.osd.position {
+            OsdPosition::Caret => get_caret_position(foreground),
+            _ => None,
+        };
+        let origin = get_osd_origin(&monitor_info.rcWork, &size, CONFIG.osd.position, caret);
+
+        if let Err(err) = SetWindowPos(
+            hwnd,
+            HWND_TOPMOST,
+            origin.x,
+            origin.y,
+            size.cx,
+            size.cy,
+            SWP_NOACTIVATE | SWP_SHOWWINDOW,
+        ) {
+            error!("Failed to show layout indicator: {}", err);
+            return;
+        }
+        InvalidateRect(hwnd, None, true);
+        SetTimer(hwnd, HIDE_TIMER_ID, CONFIG.osd.duration_ms, None);
+    }
+}
+
+#[cfg(windows)]
+fn paint(hwnd: HWND) {
+    unsafe {
+        let mut ps = PAINTSTRUCT::default();
+        let hdc = BeginPaint(hwnd, &mut ps);
+
+        let mut rect = RECT::default();
+        if GetClientRect(hwnd, &mut rect).is_ok() {
+            let brush = CreateSolidBrush(BACKGROUND_COLOR);
+            FillRect(hdc, &rect, brush);
+            DeleteObject(brush);
+
+            let prev_font = SelectObject(hdc, OSD_FONT.with(|font| *font));
+            SetBkMode(hdc, TRANSPARENT);
+            SetTextColor(hdc, TEXT_COLOR);
+            OSD_TEXT.with(|text| {
+                DrawTextW(
+                    hdc,
+                    &mut text.borrow_mut(),
+                    &mut rect,
+                    DT_CENTER | DT_VCENTER | DT_SINGLELINE,
+                );
+            });
+            SelectObject(hdc, prev_font);
+        }
+
+        EndPaint(hwnd, &ps);
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn osd_window_proc(
+    hwnd: HWND,
+    msg: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match msg {
+        WM_SHOW_LAYOUT => {
+            if lparam.0 == 0 {
+                // The layout was switched with a synthetic keystroke and has
+                // to be applied by the foreground window first
+                SetTimer(hwnd, RESOLVE_TIMER_ID, LAYOUT_SETTLE_DELAY_MS, None);
+            } else {
+                display_layout(hwnd, HKL(lparam.0));
+            }
+            LRESULT(0)
+        }
+        WM_SHOW_PAUSE => {
+            let _ = KillTimer(hwnd, RESOLVE_TIMER_ID);
+            display_text(hwnd, if wparam.0 != 0 { "Paused" } else { "Resumed" });
+            LRESULT(0)
+        }
+        WM_TIMER => {
+            let _ = KillTimer(hwnd, wparam.0);
+            match wparam.0 {
+                RESOLVE_TIMER_ID => display_layout(hwnd, get_foreground_layout()),
+                HIDE_TIMER_ID => {
+                    ShowWindow(hwnd, SW_HIDE);
+                }
+                _ => {}
+            }
+            LRESULT(0)
+        }
+        WM_PAINT => {
+            paint(hwnd);
+            LRESULT(0)
+        }
+        _ => DefWindowProcW(hwnd, msg, wparam, lparam),
+    }
+}
+
+#[cfg(windows)]
+fn create_osd_window() -> Result<HWND> {
+    unsafe {
+        let instance = GetModuleHandleW(None)?;
+        let class_name = w!("CapsWitchOsd");
+
+        let class = WNDCLASSW {
+            lpfnWndProc: Some(osd_window_proc),
+            hInstance: instance.into(),
+            lpszClassName: class_name,
+            ..Default::default()
+        };
+        if RegisterClassW(&class) == 0 {
+            return Err(Error::from_win32());
+        }
+
+        // Click-through window that never takes focus from the one being typed in
+        let hwnd = CreateWindowExW(
+            WS_EX_LAYERED | WS_EX_TOPMOST | WS_EX_TOOLWINDOW | WS_EX_NOACTIVATE | WS_EX_TRANSPARENT,
+            class_name,
+            w!("CapsWitch layout indicator"),
+            WS_POPUP,
+            0,
+            0,
+            0,
+            0,
+            None,
+            None,
+            instance,
+            None,
+        );
+        if hwnd.0 == 0 {
+            return Err(Error::from_win32());
+        }
+
+        let alpha = (CONFIG.osd.opacity.clamp(0.0, 1.0) * 255.0).round() as u8;
+        SetLayeredWindowAttributes(hwnd, COLORREF(0), alpha, LWA_ALPHA)?;
+
+        Ok(hwnd)
+    }
+}
+
+/// Starts the thread owning the indicator window, if it is enabled
+#[cfg(windows)]
+pub fn start() {
+    if !CONFIG.osd.enabled {
+        return;
+    }
+
+    thread::spawn(|| {
+        let hwnd = match create_osd_window() {
+            Ok(hwnd) => hwnd,
+            Err(err) => {
+                error!("Failed to create layout indicator window: {}", err);
+                return;
+            }
+        };
+        OSD_WINDOW.store(hwnd.0, Ordering::Release);
+
+        unsafe {
+            let mut msg = MSG::default();
+            while GetMessageW(&mut msg, HWND::default(), 0, 0).as_bool() {
+                TranslateMessage(&msg);
+                DispatchMessageW(&msg);
+            }
+        }
+    });
+}
+
+/// Shows the indicator for the new layout. Only posts a message to the
+/// indicator thread, so it is safe to call from the keyboard hook.
+#[cfg(windows)]
+pub fn show(layout: Option<HKL>) {
+    let hwnd = OSD_WINDOW.load(Ordering::Acquire);
+    if hwnd == 0 {
+        return;
+    }
+
+    let layout = layout.map_or(0, |layout| layout.0);
+    unsafe {
+        let _ = PostMessageW(HWND(hwnd), WM_SHOW_LAYOUT, WPARAM(0), LPARAM(layout));
+    }
+}
+
+/// Shows that the app was paused or resumed
+#[cfg(windows)]
+pub fn show_pause(is_paused: bool) {
+    let hwnd = OSD_WINDOW.load(Ordering::Acquire);
+    if hwnd == 0 {
+        return;
+    }
+
+    unsafe {
+        let _ = PostMessageW(
+            HWND(hwnd),
+            WM_SHOW_PAUSE,
+            WPARAM(usize::from(is_paused)),
+            LPARAM(0),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORK_AREA: RECT = RECT {
+        left: 0,
+        top: 0,
+        right: 1920,
+        bottom: 1040,
+    };
+    const SIZE_200X60: SIZE = SIZE { cx: 200, cy: 60 };
+
+    fn origin(position: OsdPosition, caret: Option<POINT>) -> (i32, i32) {
+        let origin = get_osd_origin(&WORK_AREA, &SIZE_200X60, position, caret);
+        (origin.x, origin.y)
+    }
+
+    #[test]
+    fn centers_in_work_area() {
+        assert_eq!(origin(OsdPosition::Center, None), (860, 490));
+    }
+
+    #[test]
+    fn places_at_edges() {
+        assert_eq!(origin(OsdPosition::Top, None), (860, MARGIN));
+        assert_eq!(origin(OsdPosition::Bottom, None), (860, 1040 - MARGIN - 60));
+    }
+
+    #[test]
+    fn places_below_caret() {
+        let caret = POINT { x: 500, y: 300 };
+        assert_eq!(
+            origin(OsdPosition::Caret, Some(caret)),
+            (400, 300 + MARGIN / 2)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_center_without_caret() {
+        assert_eq!(origin(OsdPosition::Caret, None), (860, 490));
+    }
+
+    #[test]
+    fn keeps_on_screen() {
+        let corner = POINT { x: 1910, y: 1030 };
+        assert_eq!(origin(OsdPosition::Caret, Some(corner)), (1720, 980));
+
+        let monitor = RECT {
+            left: -1280,
+            top: 0,
+            right: 0,
+            bottom: 1024,
+        };
+        let origin = get_osd_origin(
+            &monitor,
+            &SIZE_200X60,
+            OsdPosition::Caret,
+            Some(POINT { x: -1270, y: 10 }),
+        );
+        assert_eq!((origin.x, origin.y), (-1280, 10 + MARGIN / 2));
+    }
+
+    #[test]
+    fn keeps_left_top_when_larger_than_work_area() {
+        let size = SIZE { cx: 4000, cy: 2000 };
+        let origin = get_osd_origin(&WORK_AREA, &size, OsdPosition::Center, None);
+        assert_eq!((origin.x, origin.y), (0, 0));
+    }
+}
@@ -0,0 +1,425 @@
+//! Layout indicator on X11 desktops: an override-redirect window, which the
+//! window manager leaves alone, drawn with core fonts on a thread of its own.
+
+use super::{get_osd_origin, PADDING_X, PADDING_Y};
+use crate::config::{OsdConfig, OsdPosition};
+use log::error;
+use std::{
+    io,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+use windows::Win32::Foundation::{RECT, SIZE};
+use x11rb::{
+    connection::Connection,
+    errors::ReplyError,
+    protocol::{xproto::*, Event},
+    rust_connection::RustConnection,
+    wrapper::ConnectionExt as _,
+    COPY_DEPTH_FROM_PARENT,
+};
+
+/// Fonts tried in order, `fixed` is there on every X server
+const FONT_NAMES: [&str; 3] = [
+    "-*-dejavu sans-bold-r-normal--28-*-*-*-*-*-iso10646-1",
+    "-misc-fixed-bold-r-normal--18-*-*-*-*-*-iso10646-1",
+    "fixed",
+];
+/// Longest text drawn with one request
+const MAX_TEXT_LEN: usize = 255;
+/// How often the shown window is checked for having to be redrawn
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// Same dark grey as on Windows, in 16 bits per channel
+const BACKGROUND_RGB: (u16, u16, u16) = (0x2020, 0x2020, 0x2020);
+const WINDOW_NAME: &[u8] = b"CapsWitch layout indicator";
+
+struct Indicator {
+    conn: RustConnection,
+    window: Window,
+    gc: Gcontext,
+    font: Font,
+    screen: RECT,
+    position: OsdPosition,
+    text: Vec<Char2b>,
+    /// Left end of the text baseline
+    baseline: (i16, i16),
+}
+
+impl Indicator {
+    fn show(&mut self, text: &str) -> Result<(), ReplyError> {
+        self.text = text
+            .encode_utf16()
+            .take(MAX_TEXT_LEN)
+            .map(|unit| {
+                let [byte1, byte2] = unit.to_be_bytes();
+                Char2b { byte1, byte2 }
+            })
+            .collect();
+        let extents = self
+            .conn
+            .query_text_extents(self.font, &self.text)?
+            .reply()?;
+        let text_height = i32::from(extents.font_ascent) + i32::from(extents.font_descent);
+        let size = SIZE {
+            cx: extents.overall_width + 2 * PADDING_X,
+            cy: text_height + 2 * PADDING_Y,
+        };
+        self.baseline = (
+            PADDING_X as i16,
+            (PADDING_Y + i32::from(extents.font_ascent)) as i16,
+        );
+
+        // The caret of other apps can't be found on X11
+        let origin = get_osd_origin(&self.screen, &size, self.position, None);
+        self.conn.configure_window(
+            self.window,
+            &ConfigureWindowAux::new()
+                .x(origin.x)
+                .y(origin.y)
+                .width(size.cx as u32)
+                .height(size.cy as u32)
+                .stack_mode(StackMode::ABOVE),
+        )?;
+        self.conn.map_window(self.window)?;
+        self.draw()?;
+        self.conn.flush()?;
+
+        Ok(())
+    }
+
+    fn draw(&self) -> Result<(), ReplyError> {
+        self.conn.clear_area(false, self.window, 0, 0, 0, 0)?;
+        self.conn.image_text16(
+            self.window,
+            self.gc,
+            self.baseline.0,
+            self.baseline.1,
+            &self.text,
+        )?;
+
+        Ok(())
+    }
+
+    fn hide(&self) -> Result<(), ReplyError> {
+        self.conn.unmap_window(self.window)?;
+        self.conn.flush()?;
+
+        Ok(())
+    }
+
+    /// Redraws the window if it was covered
+    fn handle_events(&self) -> Result<(), ReplyError> {
+        while let Some(event) = self.conn.poll_for_event()? {
+            if let Event::Expose(_) = event {
+                self.draw()?;
+            }
+        }
+        self.conn.flush()?;
+
+        Ok(())
+    }
+}
+
+fn run(mut indicator: Indicator, rx: Receiver<String>, duration: Duration) {
+    let mut hide_at: Option<Instant> = None;
+    loop {
+        let message = match hide_at {
+            Some(at) => {
+                rx.recv_timeout(POLL_INTERVAL.min(at.saturating_duration_since(Instant::now())))
+            }
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        let result = match message {
+            Ok(text) => {
+                hide_at = Some(Instant::now() + duration);
+                indicator.show(&text)
+            }
+            Err(RecvTimeoutError::Timeout) if hide_at.is_some_and(|at| Instant::now() >= at) => {
+                hide_at = None;
+                indicator.hide()
+            }
+            Err(RecvTimeoutError::Timeout) => indicator.handle_events(),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if let Err(err) = result {
+            error!("Layout indicator failed: {}", err);
+            return;
+        }
+    }
+}
+
+fn open_font(conn: &RustConnection) -> io::Result<Font> {
+    for name in FONT_NAMES {
+        let font = conn.generate_id().map_err(io::Error::other)?;
+        let opened = conn
+            .open_font(font, name.as_bytes())
+            .map_err(io::Error::other)?
+            .check();
+        if opened.is_ok() {
+            return Ok(font);
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "No font found"))
+}
+
+/// Indicator window on the display from `$DISPLAY`
+pub struct X11Osd {
+    sender: Sender<String>,
+    window: Window,
+}
+
+impl X11Osd {
+    /// Connects to the display and starts the thread drawing the indicator
+    pub fn start(config: &OsdConfig) -> io::Result<Self> {
+        let (conn, screen_num) = x11rb::connect(None).map_err(io::Error::other)?;
+        let screen = conn.setup().roots[screen_num].clone();
+
+        let font = open_font(&conn)?;
+        let (red, green, blue) = BACKGROUND_RGB;
+        let background = conn
+            .alloc_color(screen.default_colormap, red, green, blue)
+            .map_err(io::Error::other)?
+            .reply()
+            .map_or(screen.black_pixel, |color| color.pixel);
+
+        let window = conn.generate_id().map_err(io::Error::other)?;
+        conn.create_window(
+            COPY_DEPTH_FROM_PARENT,
+            window,
+            screen.root,
+            0,
+            0,
+            1,
+            1,
+            0,
+            WindowClass::INPUT_OUTPUT,
+            screen.root_visual,
+            &CreateWindowAux::new()
+                .background_pixel(background)
+                .override_redirect(1)
+                .event_mask(EventMask::EXPOSURE),
+        )
+        .map_err(io::Error::other)?;
+        conn.change_property8(
+            PropMode::REPLACE,
+            window,
+            AtomEnum::WM_NAME,
+            AtomEnum::STRING,
+            WINDOW_NAME,
+        )
+        .map_err(io::Error::other)?;
+
+        // Applied by compositors, others show the window opaque
+        let opacity_atom = conn
+            .intern_atom(false, b"_NET_WM_WINDOW_OPACITY")
+            .map_err(io::Error::other)?
+            .reply()
+            .map_err(io::Error::other)?
+            .atom;
+        conn.change_property32(
+            PropMode::REPLACE,
+            window,
+            opacity_atom,
+            AtomEnum::CARDINAL,
+            &[to_opacity(config.opacity)],
+        )
+        .map_err(io::Error::other)?;
+
+        let gc = conn.generate_id().map_err(io::Error::other)?;
+        conn.create_gc(
+            gc,
+            window,
+            &CreateGCAux::new()
+                .foreground(screen.white_pixel)
+                .background(background)
+                .font(font),
+        )
+        .map_err(io::Error::other)?;
+        conn.flush().map_err(io::Error::other)?;
+
+        let indicator = Indicator {
+            conn,
+            window,
+            gc,
+            font,
+            screen: RECT {
+                left: 0,
+                top: 0,
+                right: i32::from(screen.width_in_pixels),
+                bottom: i32::from(screen.height_in_pixels),
+            },
+            position: config.position,
+            text: Vec::new(),
+            baseline: (0, 0),
+        };
+        let duration = Duration::from_millis(config.duration_ms.into());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || run(indicator, rx, duration));
+
+        Ok(Self { sender: tx, window })
+    }
+
+    /// Shows the text, e.g. the name of the new layout, for the configured
+    /// time. Only queues it for the indicator thread, so it never blocks.
+    pub fn show(&self, text: &str) {
+        let _ = self.sender.send(text.to_string());
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+}
+
+/// `_NET_WM_WINDOW_OPACITY` value, where `u32::MAX` is opaque
+fn to_opacity(opacity: f32) -> u32 {
+    (f64::from(opacity.clamp(0.0, 1.0)) * f64::from(u32::MAX)).round() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osd::MARGIN;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn connect() -> RustConnection {
+        x11rb::connect(None).unwrap().0
+    }
+
+    fn config(position: OsdPosition, duration_ms: u32) -> OsdConfig {
+        OsdConfig {
+            enabled: true,
+            duration_ms,
+            position,
+            opacity: 0.5,
+        }
+    }
+
+    fn wait_for(conn: &RustConnection, window: Window, state: MapState) -> bool {
+        let started = Instant::now();
+        while started.elapsed() < TIMEOUT {
+            let attributes = conn.get_window_attributes(window).unwrap().reply().unwrap();
+            if attributes.map_state == state {
+                return true;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        false
+    }
+
+    fn geometry(conn: &RustConnection, window: Window) -> GetGeometryReply {
+        conn.get_geometry(window).unwrap().reply().unwrap()
+    }
+
+    fn screen_size(conn: &RustConnection) -> (i32, i32) {
+        let screen = &conn.setup().roots[0];
+
+        (
+            i32::from(screen.width_in_pixels),
+            i32::from(screen.height_in_pixels),
+        )
+    }
+
+    #[test]
+    fn converts_opacity() {
+        assert_eq!(to_opacity(1.0), u32::MAX);
+        assert_eq!(to_opacity(0.0), 0);
+        assert_eq!(to_opacity(2.0), u32::MAX);
+        assert_eq!(to_opacity(0.5), u32::MAX / 2 + 1);
+    }
+
+    #[test]
+    #[ignore = "needs an X server"]
+    fn shows_centered_and_hides_after_duration() {
+        let conn = connect();
+        let osd = X11Osd::start(&config(OsdPosition::Center, 300)).unwrap();
+
+        osd.show("English — US");
+        assert!(wait_for(&conn, osd.window(), MapState::VIEWABLE));
+        let attributes = conn
+            .get_window_attributes(osd.window())
+            .unwrap()
+            .reply()
+            .unwrap();
+        assert!(attributes.override_redirect);
+        let geometry = geometry(&conn, osd.window());
+        let (width, height) = screen_size(&conn);
+        assert_eq!(
+            i32::from(geometry.x),
+            (width - i32::from(geometry.width)) / 2
+        );
+        assert_eq!(
+            i32::from(geometry.y),
+            (height - i32::from(geometry.height)) / 2
+        );
+
+        assert!(wait_for(&conn, osd.window(), MapState::UNMAPPED));
+    }
+
+    #[test]
+    #[ignore = "needs an X server"]
+    fn places_at_top_and_bottom() {
+        let conn = connect();
+        let (_, height) = screen_size(&conn);
+
+        let top = X11Osd::start(&config(OsdPosition::Top, 5000)).unwrap();
+        top.show("EN");
+        assert!(wait_for(&conn, top.window(), MapState::VIEWABLE));
+        assert_eq!(i32::from(geometry(&conn, top.window()).y), MARGIN);
+
+        let bottom = X11Osd::start(&config(OsdPosition::Bottom, 5000)).unwrap();
+        bottom.show("EN");
+        assert!(wait_for(&conn, bottom.window(), MapState::VIEWABLE));
+        let geometry = geometry(&conn, bottom.window());
+        assert_eq!(
+            i32::from(geometry.y) + i32::from(geometry.height),
+            height - MARGIN
+        );
+    }
+
+    #[test]
+    #[ignore = "needs an X server"]
+    fn fits_window_to_text() {
+        let conn = connect();
+        let osd = X11Osd::start(&config(OsdPosition::Center, 5000)).unwrap();
+
+        osd.show("EN");
+        assert!(wait_for(&conn, osd.window(), MapState::VIEWABLE));
+        let short = geometry(&conn, osd.window()).width;
+
+        osd.show("English — United States");
+        let started = Instant::now();
+        while geometry(&conn, osd.window()).width == short && started.elapsed() < TIMEOUT {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(geometry(&conn, osd.window()).width > short);
+        assert!(i32::from(short) > 2 * PADDING_X);
+    }
+
+    #[test]
+    #[ignore = "needs an X server"]
+    fn sets_opacity() {
+        let conn = connect();
+        let osd = X11Osd::start(&config(OsdPosition::Center, 300)).unwrap();
+
+        let atom = conn
+            .intern_atom(true, b"_NET_WM_WINDOW_OPACITY")
+            .unwrap()
+            .reply()
+            .unwrap()
+            .atom;
+        let property = conn
+            .get_property(false, osd.window(), atom, AtomEnum::CARDINAL, 0, 1)
+            .unwrap()
+            .reply()
+            .unwrap();
+        assert_eq!(
+            property.value32().unwrap().collect::<Vec<_>>(),
+            [to_opacity(0.5)]
+        );
+    }
+}
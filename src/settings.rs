@@ -0,0 +1,72 @@
+#[cfg(windows)]
+use crate::constants::{HKCU, REG_SETTINGS_PATH};
+#[cfg(windows)]
+use crate::error::CapsWitchError;
+use crate::error::Result;
+use crate::layout_id::{format_layout, parse_layout};
+use crate::mode::SwitchMode;
+use windows::Win32::UI::TextServices::HKL;
+
+const EXCLUDED_LAYOUTS_VALUE: &str = "ExcludedLayouts";
+const MODE_VALUE: &str = "Mode";
+const MUTED_VALUE: &str = "Muted";
+
+#[cfg(windows)]
+fn get_setting(name: &str) -> Option<String> {
+    let settings = HKCU.open_subkey(REG_SETTINGS_PATH).ok()?;
+
+    settings.get_value(name).ok()
+}
+
+#[cfg(windows)]
+fn set_setting(name: &'static str, value: &str) -> Result<()> {
+    let (settings, _) = HKCU
+        .create_subkey(REG_SETTINGS_PATH)
+        .map_err(|err| CapsWitchError::Settings(name, err))?;
+
+    settings
+        .set_value(name, &value)
+        .map_err(|err| CapsWitchError::Settings(name, err))
+}
+
+// Settings live in the registry, elsewhere the defaults are used and changes
+// last until the app exits
+#[cfg(not(windows))]
+fn get_setting(_name: &str) -> Option<String> {
+    None
+}
+
+#[cfg(not(windows))]
+fn set_setting(_name: &'static str, _value: &str) -> Result<()> {
+    Ok(())
+}
+
+/// Layouts the user excluded from the CapsLock rotation
+pub fn load_excluded_layouts() -> Vec<HKL> {
+    get_setting(EXCLUDED_LAYOUTS_VALUE)
+        .map(|value| value.split(',').filter_map(parse_layout).collect())
+        .unwrap_or_default()
+}
+
+pub fn save_excluded_layouts(layouts: &[HKL]) -> Result<()> {
+    let value: Vec<String> = layouts.iter().map(format_layout).collect();
+
+    set_setting(EXCLUDED_LAYOUTS_VALUE, &value.join(","))
+}
+
+/// Switching mode chosen in the tray menu
+pub fn load_mode() -> Option<SwitchMode> {
+    get_setting(MODE_VALUE).and_then(|value| SwitchMode::parse(&value))
+}
+
+pub fn save_mode(mode: SwitchMode) -> Result<()> {
+    set_setting(MODE_VALUE, mode.as_str())
+}
+
+pub fn load_muted() -> bool {
+    get_setting(MUTED_VALUE).is_some_and(|value| value == "1")
+}
+
+pub fn save_muted(is_muted: bool) -> Result<()> {
+    set_setting(MUTED_VALUE, if is_muted { "1" } else { "0" })
+}
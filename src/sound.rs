@@ -0,0 +1,195 @@
+use crate::config::CONFIG;
+use crate::constants::LAYOUT_SETTLE_DELAY_MS;
+use crate::switch::{get_foreground_layout, get_installed_layouts, is_layout_matching};
+use crate::APP_STATE;
+use log::error;
+use std::{
+    f32::consts::PI,
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+use windows::{
+    core::{HSTRING, PCWSTR},
+    Win32::{
+        Foundation::HMODULE,
+        Media::Audio::{PlaySoundW, SND_FILENAME, SND_MEMORY, SND_NODEFAULT, SND_SYNC},
+        UI::TextServices::HKL,
+    },
+};
+
+const SAMPLE_RATE: u32 = 22050;
+const TONE_DURATION_MS: u32 = 70;
+/// Fade in and out to avoid clicks at the tone edges
+const TONE_FADE_MS: u32 = 10;
+const TONE_AMPLITUDE: f32 = 0.3;
+/// Pitches of the layouts in the order they are installed, a major
+/// pentatonic scale so that neighbouring layouts are easy to tell apart
+const TONE_FREQUENCIES: [f32; 5] = [523.25, 659.25, 783.99, 987.77, 1174.66];
+
+static SOUND_SENDER: OnceLock<Sender<Option<HKL>>> = OnceLock::new();
+
+/// Renders a mono 16-bit PCM WAV file with a sine tone of `frequency`
+fn render_tone(frequency: f32) -> Vec<u8> {
+    let sample_count = SAMPLE_RATE * TONE_DURATION_MS / 1000;
+    let fade_count = SAMPLE_RATE * TONE_FADE_MS / 1000;
+    let data_size = sample_count * 2;
+
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+
+    for i in 0..sample_count {
+        let envelope = (i.min(sample_count - 1 - i) as f32 / fade_count as f32).min(1.0);
+        let t = i as f32 / SAMPLE_RATE as f32;
+        let sample = (2.0 * PI * frequency * t).sin() * TONE_AMPLITUDE * envelope;
+        wav.extend_from_slice(&((sample * i16::MAX as f32) as i16).to_le_bytes());
+    }
+
+    wav
+}
+
+/// Custom sound file configured for the layout
+fn get_sound_file(layout: &HKL) -> Option<&'static Path> {
+    CONFIG
+        .sound
+        .files
+        .iter()
+        .find(|(key, _)| is_layout_matching(layout, key))
+        .map(|(_, path)| path.as_path())
+}
+
+fn play_layout_sound(layout: HKL) {
+    let is_played = match get_sound_file(&layout) {
+        Some(path) => unsafe {
+            PlaySoundW(
+                &HSTRING::from(path),
+                HMODULE::default(),
+                SND_FILENAME | SND_NODEFAULT | SND_SYNC,
+            )
+        },
+        None => {
+            let index = get_installed_layouts()
+                .iter()
+                .position(|&l| l == layout)
+                .unwrap_or(0);
+            let wav = render_tone(TONE_FREQUENCIES[index % TONE_FREQUENCIES.len()]);
+            // The buffer has to outlive the playback, so it is played synchronously
+            unsafe {
+                PlaySoundW(
+                    PCWSTR(wav.as_ptr() as *const u16),
+                    HMODULE::default(),
+                    SND_MEMORY | SND_NODEFAULT | SND_SYNC,
+                )
+            }
+        }
+    };
+
+    if !is_played.as_bool() {
+        error!("Failed to play sound for layout {:?}", layout);
+    }
+}
+
+/// Starts the thread playing sounds on layout switches, if they are enabled
+pub fn start() {
+    if !CONFIG.sound.enabled {
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel::<Option<HKL>>();
+    if SOUND_SENDER.set(tx).is_err() {
+        return;
+    }
+
+    thread::spawn(move || {
+        for layout in rx.iter() {
+            // Sounds are played one after another, so the ones queued
+            // meanwhile by fast switching would keep playing after it ends
+            let layout = rx.try_iter().last().unwrap_or(layout);
+            if APP_STATE.is_muted() {
+                continue;
+            }
+
+            let layout = layout.unwrap_or_else(|| {
+                thread::sleep(Duration::from_millis(LAYOUT_SETTLE_DELAY_MS.into()));
+                get_foreground_layout()
+            });
+            play_layout_sound(layout);
+        }
+    });
+}
+
+/// Queues the sound of the new layout, `None` if it has to be queried from
+/// the foreground window. Never blocks, so it is safe to call from the
+/// keyboard hook.
+pub fn play(layout: Option<HKL>) {
+    if let Some(tx) = SOUND_SENDER.get() {
+        let _ = tx.send(layout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u16(wav: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(wav[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(wav: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(wav[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn renders_wav_header() {
+        let wav = render_tone(TONE_FREQUENCIES[0]);
+        let data_size = SAMPLE_RATE * TONE_DURATION_MS / 1000 * 2;
+
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(read_u32(&wav, 4), 36 + data_size);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(&wav, 16), 16);
+        assert_eq!(read_u16(&wav, 20), 1);
+        assert_eq!(read_u16(&wav, 22), 1);
+        assert_eq!(read_u32(&wav, 24), SAMPLE_RATE);
+        assert_eq!(read_u32(&wav, 28), SAMPLE_RATE * 2);
+        assert_eq!(read_u16(&wav, 32), 2);
+        assert_eq!(read_u16(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(read_u32(&wav, 40), data_size);
+        assert_eq!(wav.len(), 44 + data_size as usize);
+    }
+
+    #[test]
+    fn fades_tone_edges() {
+        let wav = render_tone(TONE_FREQUENCIES[2]);
+        let samples: Vec<i16> = wav[44..]
+            .chunks_exact(2)
+            .map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+            .collect();
+
+        assert_eq!(samples[0], 0);
+        assert_eq!(*samples.last().unwrap(), 0);
+        let peak = samples
+            .iter()
+            .map(|sample| sample.unsigned_abs())
+            .max()
+            .unwrap();
+        assert!(f32::from(peak) <= TONE_AMPLITUDE * f32::from(i16::MAX));
+        assert!(f32::from(peak) > TONE_AMPLITUDE * f32::from(i16::MAX) * 0.9);
+    }
+}
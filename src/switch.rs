@@ -1,36 +1,301 @@
+use crate::activation::{self, Expected};
+use crate::bindings::{Bindings, KeyAction, Modifiers};
+use crate::caps::{CapsCommand, CapsTracker};
+use crate::config::CONFIG;
+use crate::constants::{HKLM, LAYOUT_REQUEST_TIMEOUT_MS, REG_KEYBOARD_LAYOUTS_PATH};
+use crate::decision::{decide, HookDecision, HookKey, CAPS_MARKER, HEARTBEAT_MARKER};
+use crate::error::{CapsWitchError, Result};
+use crate::gesture::{Gesture, GestureTimings, Recognizer};
+use crate::helper;
+use crate::hooks::{self, HookEvent};
+use crate::latency;
+use crate::layout_id::{
+    decode_hkl, format_layout, parse_layout, HklLayout, LayoutId, LayoutIdCache, LayoutVariant,
+    UNDETERMINED_LANGUAGE,
+};
+use crate::layout_names::LAYOUT_NAMES;
+use crate::led;
+use crate::mode::{next_in_rotation, step_in_rotation, Direction, SwitchMode};
+use crate::osd;
+use crate::sound;
+use crate::tray;
+use crate::watchdog::{HookHealth, SystemClock};
+use crate::word::{KeyEvent, LastWord};
 use crate::APP_STATE;
-use std::mem;
+use log::{debug, error, info, warn};
+use std::{
+    cell::{Cell, RefCell},
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering},
+        Arc, LazyLock, Mutex, PoisonError,
+    },
+};
 use windows::{
     core::*,
     Win32::{
         Foundation::*,
+        Globalization::{GetLocaleInfoW, LCIDToLocaleName, LOCALE_SLOCALIZEDDISPLAYNAME},
+        System::{SystemInformation::GetTickCount, Threading::GetCurrentThreadId},
         UI::{Input::KeyboardAndMouse::*, TextServices::HKL, WindowsAndMessaging::*},
     },
 };
 
 static mut HOOK: HHOOK = HHOOK(0);
 
-fn get_foreground_layout() -> HKL {
+static HOOK_THREAD_ID: AtomicU32 = AtomicU32::new(0);
+/// Tick count of the last event the hook received
+static HOOK_LAST_EVENT: AtomicU32 = AtomicU32::new(0);
+
+static HOOK_INSTALLED: AtomicBool = AtomicBool::new(false);
+static HOOK_KEY_EVENTS: AtomicU64 = AtomicU64::new(0);
+static HOOK_SWITCHES: AtomicU64 = AtomicU64::new(0);
+static HOOK_ERRORS: AtomicU64 = AtomicU64::new(0);
+static HOOK_REINSTALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Posted to the hook thread to install the hook again
+const WM_REINSTALL_HOOK: u32 = WM_APP + 1;
+
+/// Marks the keys that type a converted word, which aren't tracked as typing
+const WORD_MARKER: usize = 0x4357_5744;
+
+/// `CapsTracker` bits, only changed by the hook
+static CAPS_STATE: AtomicU8 = AtomicU8::new(0);
+
+static BINDINGS: LazyLock<Bindings> =
+    LazyLock::new(|| Bindings::from_config(&CONFIG.keys.bindings));
+
+static GESTURE_TIMINGS: LazyLock<GestureTimings> = LazyLock::new(|| GestureTimings {
+    max_taps: BINDINGS.max_taps(),
+    tap_interval_ms: CONFIG.keys.tap_interval_ms,
+    long_press_ms: BINDINGS
+        .has_long_press()
+        .then_some(CONFIG.keys.long_press_ms),
+});
+
+/// Typing is only followed if there is a gesture to convert it
+static TRACKS_WORDS: LazyLock<bool> = LazyLock::new(|| BINDINGS.has_action(KeyAction::ConvertWord));
+
+static PAUSE_HOTKEY: LazyLock<Option<Modifiers>> = LazyLock::new(|| {
+    let value = CONFIG.keys.pause_hotkey.as_deref()?;
+    let hotkey = Modifiers::parse_hotkey(value);
+    if hotkey.is_none() {
+        warn!("Invalid pause hotkey `{}`", value);
+    }
+
+    hotkey
+});
+
+thread_local! {
+    /// Whether CapsLock is held after toggling pause
+    static PAUSE_KEY_DOWN: Cell<bool> = const { Cell::new(false) };
+    static RECOGNIZER: RefCell<Recognizer<SystemClock>> =
+        RefCell::new(Recognizer::new(SystemClock, *GESTURE_TIMINGS));
+    /// Thread timer polling the recognizer, 0 if there is none
+    static GESTURE_TIMER: Cell<usize> = const { Cell::new(0) };
+    static LAST_WORD: RefCell<LastWord> = RefCell::new(LastWord::default());
+}
+
+pub fn get_hook_health() -> HookHealth {
+    HookHealth {
+        is_installed: HOOK_INSTALLED.load(Ordering::Relaxed),
+        key_events: HOOK_KEY_EVENTS.load(Ordering::Relaxed),
+        switches: HOOK_SWITCHES.load(Ordering::Relaxed),
+        errors: HOOK_ERRORS.load(Ordering::Relaxed),
+        reinstalls: HOOK_REINSTALLS.load(Ordering::Relaxed),
+    }
+}
+
+pub fn get_hook_last_event() -> u32 {
+    HOOK_LAST_EVENT.load(Ordering::Relaxed)
+}
+
+/// Injects a key press and release that the hook recognizes and swallows, so
+/// no key is left down if it isn't. Returns `false` if the input couldn't be
+/// sent.
+pub fn send_heartbeat() -> bool {
+    let inputs = [false, true].map(|key_up| {
+        let mut input = create_kbd_input(VK_NONAME.0, key_up);
+        input.Anonymous.ki.dwExtraInfo = HEARTBEAT_MARKER;
+        input
+    });
+
+    send_input(&inputs).is_ok()
+}
+
+/// Asks the hook thread to install the hook again. Low-level hooks are called
+/// on the thread that installed them, so it can't be done from elsewhere.
+pub fn request_hook_reinstall() -> bool {
+    let thread_id = HOOK_THREAD_ID.load(Ordering::Relaxed);
+    if thread_id == 0 {
+        return false;
+    }
+
+    unsafe { PostThreadMessageW(thread_id, WM_REINSTALL_HOOK, WPARAM(0), LPARAM(0)) }.is_ok()
+}
+
+fn log_hook_error(err: CapsWitchError) {
+    HOOK_ERRORS.fetch_add(1, Ordering::Relaxed);
+    error!("{}", err);
+}
+
+pub fn get_window_layout(hwnd: HWND) -> HKL {
     unsafe {
-        let hwnd: HWND = GetForegroundWindow(); // Get the active window
         let mut process_id: u32 = 0;
         let thread_id = GetWindowThreadProcessId(hwnd, Some(&mut process_id));
 
-        GetKeyboardLayout(thread_id) // Returns the layout for the active window's thread
+        GetKeyboardLayout(thread_id) // Returns the layout for the window's thread
     }
 }
 
-fn change_keyboard_layout(hkl: &HKL) -> LRESULT {
+pub fn get_foreground_layout() -> HKL {
+    get_window_layout(unsafe { GetForegroundWindow() })
+}
+
+/// Layouts installed in the system, in the order Windows cycles through them
+pub fn get_installed_layouts() -> Vec<HKL> {
     unsafe {
-        let result = SendMessageA(
-            GetForegroundWindow(),
+        let count = GetKeyboardLayoutList(None);
+        let mut layouts = vec![HKL::default(); usize::try_from(count).unwrap_or(0)];
+        let count = GetKeyboardLayoutList(Some(&mut layouts));
+        layouts.truncate(usize::try_from(count).unwrap_or(0));
+
+        layouts
+    }
+}
+
+/// KLID of the registry layout with the "Layout Id" value, which identifies
+/// layout variants in HKLs
+fn find_klid_by_layout_id(index: u16) -> Option<u32> {
+    let layouts = HKLM.open_subkey(REG_KEYBOARD_LAYOUTS_PATH).ok()?;
+
+    layouts.enum_keys().flatten().find_map(|klid| {
+        let layout_id: String = layouts
+            .open_subkey(&klid)
+            .ok()?
+            .get_value("Layout Id")
+            .ok()?;
+        (u16::from_str_radix(&layout_id, 16).ok()? == index)
+            .then(|| u32::from_str_radix(&klid, 16).ok())
+            .flatten()
+    })
+}
+
+/// BCP 47 tag of a language identifier, e.g. `en-US` for `0409`
+fn get_language_tag(lang_id: u16) -> String {
+    // LOCALE_NAME_MAX_LENGTH
+    let mut buffer = [0u16; 85];
+    let len = unsafe { LCIDToLocaleName(u32::from(lang_id), Some(&mut buffer), 0) };
+    if len <= 1 {
+        return String::from(UNDETERMINED_LANGUAGE);
+    }
+
+    String::from_utf16_lossy(&buffer[..len as usize - 1])
+}
+
+static LAYOUT_IDS: LazyLock<Mutex<LayoutIdCache>> =
+    LazyLock::new(|| Mutex::new(LayoutIdCache::default()));
+
+/// Id of the layout named after the layout names table, or after its
+/// language if the table doesn't have it
+pub fn get_layout_id(hkl: &HKL) -> LayoutId {
+    let installed = get_installed_layouts();
+    let cached = LAYOUT_IDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(&installed, hkl);
+    if let Some(id) = cached {
+        return id;
+    }
+
+    // Built without the lock, so other threads never wait for the registry
+    let id = describe_layout(hkl);
+    LAYOUT_IDS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(&installed, hkl, id.clone());
+
+    id
+}
+
+fn describe_layout(hkl: &HKL) -> LayoutId {
+    let decoded = decode_hkl(hkl.0 as u32);
+    let klid = match decoded.layout {
+        HklLayout::Klid(klid) => klid,
+        HklLayout::LayoutIdIndex(index) => {
+            find_klid_by_layout_id(index).unwrap_or(u32::from(decoded.lang_id))
+        }
+    };
+    let id = LayoutId::new(
+        &get_language_tag(decoded.lang_id),
+        LayoutVariant::Klid(klid),
+    );
+
+    // The low word of an HKL is the language identifier, which is a valid LCID
+    let mut buffer = [0u16; 128];
+    let len = unsafe {
+        GetLocaleInfoW(
+            u32::from(decoded.lang_id),
+            LOCALE_SLOCALIZEDDISPLAYNAME,
+            Some(&mut buffer),
+        )
+    };
+    let id = if len > 1 {
+        id.with_name(String::from_utf16_lossy(&buffer[..len as usize - 1]))
+    } else {
+        id
+    };
+
+    LAYOUT_NAMES.describe(id)
+}
+
+/// Whether a layout from the config, either a layout id or an HKL formatted
+/// with `format_layout`, refers to `hkl`
+pub fn is_layout_matching(hkl: &HKL, value: &str) -> bool {
+    parse_layout(value) == Some(*hkl) || get_layout_id(hkl).matches(value)
+}
+
+/// Two-letter code of the layout, e.g. "EN" for `04090409`
+pub fn get_layout_short_code(hkl: &HKL) -> String {
+    get_layout_id(hkl).short_code
+}
+
+/// Name of the layout, e.g. "Russian — ЙЦУКЕН" for `04190419`
+pub fn get_layout_display_name(hkl: &HKL) -> String {
+    get_layout_id(hkl).name
+}
+
+/// Requests `hkl` for the window. Returns `None` if the window is hung or
+/// didn't answer in time, it may then still switch once it gets to it.
+pub fn change_window_layout(hwnd: HWND, hkl: &HKL) -> Option<LRESULT> {
+    let mut result = 0;
+    let is_answered = unsafe {
+        SendMessageTimeoutW(
+            hwnd,
             WM_INPUTLANGCHANGEREQUEST,
             WPARAM(0),
             LPARAM(hkl.0),
-        );
+            SMTO_ABORTIFHUNG,
+            LAYOUT_REQUEST_TIMEOUT_MS,
+            Some(&mut result),
+        )
+    };
 
-        result
-    }
+    (is_answered.0 != 0).then_some(LRESULT(result as isize))
+}
+
+/// Requests `hkl` for the foreground window, which currently has `curr_layout`
+fn change_keyboard_layout(curr_layout: HKL, hkl: &HKL) -> Option<LRESULT> {
+    let target = unsafe { GetForegroundWindow() };
+
+    latency::measure_apply(curr_layout, || {
+        // Elevated windows ignore requests from the app, the helper's
+        // result is only known to the verification of Previous mode
+        if helper::activate_for(target, *hkl) {
+            return Some(LRESULT(0));
+        }
+        change_window_layout(target, hkl)
+    })
 }
 
 fn create_kbd_input(vk_code: u16, key_up: bool) -> INPUT {
@@ -52,131 +317,563 @@ fn create_kbd_input(vk_code: u16, key_up: bool) -> INPUT {
     }
 }
 
-fn imitate_keyboard_layout_change() {
-    // Presses win + space, then releases space and afterwards win
-    let inputs = [
-        create_kbd_input(VK_LWIN.0, false),
+/// Sends all `inputs` or fails with the reason Windows gives
+fn send_input(inputs: &[INPUT]) -> Result<()> {
+    let sent = unsafe { SendInput(inputs, mem::size_of::<INPUT>() as i32) };
+    if sent as usize != inputs.len() {
+        return Err(CapsWitchError::SendInput(Error::from_win32()));
+    }
+
+    Ok(())
+}
+
+fn imitate_keyboard_layout_change(curr_layout: HKL, direction: Direction) -> Result<()> {
+    // Presses win + space, then releases space and afterwards win. Shift
+    // makes Windows cycle backwards.
+    let mut inputs = vec![create_kbd_input(VK_LWIN.0, false)];
+    if direction == Direction::Backward {
+        inputs.push(create_kbd_input(VK_SHIFT.0, false));
+    }
+    inputs.extend([
         create_kbd_input(VK_SPACE.0, false),
         create_kbd_input(VK_SPACE.0, true),
-        create_kbd_input(VK_LWIN.0, true),
-    ];
+    ]);
+    if direction == Direction::Backward {
+        inputs.push(create_kbd_input(VK_SHIFT.0, true));
+    }
+    inputs.push(create_kbd_input(VK_LWIN.0, true));
 
-    let result = unsafe {
-        SendInput(
-            &inputs,
-            i32::try_from(mem::size_of::<INPUT>()).expect("Converting sizeof Input Failed"),
-        )
+    latency::measure_apply(curr_layout, || send_input(&inputs))
+}
+
+/// Notifies hooks, indicators and sounds about a CapsLock switch.
+/// `new` is `None` if the layout was changed with the fallback keystroke.
+pub fn on_layout_switched(old: HKL, new: Option<HKL>) {
+    HOOK_SWITCHES.fetch_add(1, Ordering::Relaxed);
+    debug!(
+        "Switched layout from {} to {}",
+        format_layout(&old),
+        new.map_or_else(|| String::from("next"), |new| format_layout(&new))
+    );
+    // Previous mode only remembers layouts the window confirmed
+    if APP_STATE.mode() != SwitchMode::Previous {
+        APP_STATE.set_prev_layout(Some(old));
+    }
+    hooks::dispatch(HookEvent::LayoutChange { old, new });
+    if CONFIG.caps.off_on_switch {
+        if let Err(err) = set_caps(CapsCommand::Off) {
+            log_hook_error(err);
+        }
+    }
+    osd::show(new);
+    led::refresh();
+    sound::play(new);
+}
+
+/// What came of a layout request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Request {
+    /// There was no layout to switch to, or the window rejected it
+    NotMade,
+    /// The switch took effect and was announced
+    Announced,
+    /// The window didn't answer in time, so it may still switch
+    Unanswered,
+}
+
+/// Cycles layouts with Win+Space, or Win+Shift+Space backwards, unless the
+/// fallback is disabled
+fn switch_with_keystroke(curr_layout: HKL, direction: Direction) -> Result<Request> {
+    if !CONFIG.switch.keystroke_fallback {
+        warn!(
+            "Failed to activate the layout {} {}",
+            match direction {
+                Direction::Forward => "after",
+                Direction::Backward => "before",
+            },
+            format_layout(&curr_layout)
+        );
+        return Ok(Request::NotMade);
+    }
+
+    imitate_keyboard_layout_change(curr_layout, direction)?;
+    on_layout_switched(curr_layout, None);
+
+    Ok(Request::Announced)
+}
+
+fn switch_to_next_layout(
+    curr_layout: HKL,
+    excluded: &[HKL],
+    direction: Direction,
+) -> Result<Request> {
+    let layouts = get_installed_layouts();
+    let Some(next) = step_in_rotation(&layouts, curr_layout, excluded, direction) else {
+        // The list may be unavailable, then Windows still knows what's next
+        if layouts.is_empty() {
+            return switch_with_keystroke(curr_layout, direction);
+        }
+        return Ok(Request::NotMade);
     };
 
-    if result == 0 {
-        panic!("SendInput failed");
+    match change_keyboard_layout(curr_layout, &next) {
+        Some(LRESULT(0)) => {
+            on_layout_switched(curr_layout, Some(next));
+            Ok(Request::Announced)
+        }
+        Some(_) => switch_with_keystroke(curr_layout, direction),
+        // A keystroke would switch twice if the window catches up
+        None => {
+            debug!("Foreground window didn't answer the layout request");
+            Ok(Request::Unanswered)
+        }
+    }
+}
+
+fn switch_to_previous_layout(curr_layout: HKL) -> Result<()> {
+    let target = unsafe { GetForegroundWindow() };
+
+    match APP_STATE.prev_layout() {
+        Some(prev_layout) if prev_layout != curr_layout => {
+            let is_announced =
+                change_keyboard_layout(curr_layout, &prev_layout) == Some(LRESULT(0));
+            if is_announced {
+                on_layout_switched(curr_layout, Some(prev_layout));
+            }
+            // Also retries requests the window rejected
+            activation::verify(
+                target,
+                curr_layout,
+                Expected::Layout(prev_layout),
+                is_announced,
+            );
+        }
+        _ => match switch_to_next_layout(curr_layout, &[], Direction::Forward)? {
+            Request::NotMade => {}
+            request => activation::verify(
+                target,
+                curr_layout,
+                Expected::AnyBut(curr_layout),
+                request == Request::Announced,
+            ),
+        },
+    }
+
+    Ok(())
+}
+
+fn switch_to_recent_layout(curr_layout: HKL) -> Result<()> {
+    let installed = get_installed_layouts();
+
+    match APP_STATE.next_recent_layout(curr_layout, &installed) {
+        Some(next) if next == curr_layout => {}
+        Some(next) => {
+            if change_keyboard_layout(curr_layout, &next) == Some(LRESULT(0)) {
+                on_layout_switched(curr_layout, Some(next));
+            }
+        }
+        None => {
+            switch_to_next_layout(curr_layout, &[], Direction::Forward)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Switches to the next layout for as long as CapsLock is held. Autorepeated
+/// key downs are ignored.
+fn start_hold(curr_layout: HKL) {
+    if APP_STATE.hold_origin().is_some() {
+        return;
+    }
+
+    let layouts = get_installed_layouts();
+    let Some(next) = next_in_rotation(&layouts, curr_layout, &[]) else {
+        return;
+    };
+    if change_keyboard_layout(curr_layout, &next) == Some(LRESULT(0)) {
+        APP_STATE.set_hold_origin(Some(curr_layout));
+        on_layout_switched(curr_layout, Some(next));
     }
 }
 
+fn end_hold() {
+    let Some(origin) = APP_STATE.hold_origin() else {
+        return;
+    };
+
+    APP_STATE.set_hold_origin(None);
+    let curr_layout = get_foreground_layout();
+    if curr_layout != origin && change_keyboard_layout(curr_layout, &origin) == Some(LRESULT(0)) {
+        on_layout_switched(curr_layout, Some(origin));
+    }
+}
+
+fn track_caps(is_down: bool) {
+    let _ = CAPS_STATE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+        Some(CapsTracker::from_bits(bits).on_key(is_down).bits())
+    });
+}
+
+pub fn is_caps_on() -> bool {
+    CapsTracker::from_bits(CAPS_STATE.load(Ordering::Relaxed)).is_on()
+}
+
+fn create_caps_input(key_up: bool) -> INPUT {
+    let mut input = create_kbd_input(VK_CAPITAL.0, key_up);
+    input.Anonymous.ki.dwExtraInfo = CAPS_MARKER;
+
+    input
+}
+
+/// Toggles caps with a CapsLock press the hook lets through
+pub fn press_caps_lock() -> Result<()> {
+    send_input(&[create_caps_input(false), create_caps_input(true)])
+}
+
+/// Turns caps on or off with a CapsLock press, if it isn't already. Elevated
+/// windows don't receive the press from the app, the helper makes it then.
+pub fn set_caps(command: CapsCommand) -> Result<()> {
+    let tracker = CapsTracker::from_bits(CAPS_STATE.load(Ordering::Relaxed));
+    if !tracker.needs_press(command) {
+        return Ok(());
+    }
+    if helper::set_caps_for(unsafe { GetForegroundWindow() }, command) {
+        return Ok(());
+    }
+
+    press_caps_lock()
+}
+
+/// Sends a real CapsLock press, so the passthrough gesture toggles the lock
+/// state
+fn pass_caps_lock() -> Result<()> {
+    let input = INPUT {
+        r#type: INPUT_KEYBOARD,
+        Anonymous: INPUT_0 {
+            ki: KEYBDINPUT {
+                wVk: VIRTUAL_KEY(VK_CAPITAL.0),
+                wScan: 0,
+                dwFlags: KEYEVENTF_EXTENDEDKEY,
+                time: 0,
+                dwExtraInfo: CAPS_MARKER,
+            },
+        },
+    };
+    send_input(&[input])?;
+    // The system lights the LED for the real CapsLock state
+    led::refresh();
+
+    Ok(())
+}
+
+/// Steps back through the rotation, which skips excluded layouts in Subset
+/// mode and is the installed order in every other mode
+fn switch_layout_backwards(curr_layout: HKL) -> Result<()> {
+    let excluded = match APP_STATE.mode() {
+        SwitchMode::Subset => APP_STATE.excluded_layouts(),
+        _ => Arc::default(),
+    };
+
+    switch_to_next_layout(curr_layout, &excluded, Direction::Backward)?;
+
+    Ok(())
+}
+
+fn switch_layout(curr_layout: HKL) -> Result<()> {
+    match APP_STATE.mode() {
+        SwitchMode::Circular => {
+            switch_to_next_layout(curr_layout, &[], Direction::Forward)?;
+        }
+        SwitchMode::Previous => switch_to_previous_layout(curr_layout)?,
+        SwitchMode::Mru => switch_to_recent_layout(curr_layout)?,
+        SwitchMode::Subset => {
+            let excluded = APP_STATE.excluded_layouts();
+            switch_to_next_layout(curr_layout, &excluded, Direction::Forward)?;
+        }
+        SwitchMode::Hold => start_hold(curr_layout),
+    }
+
+    Ok(())
+}
+
+fn held_modifiers() -> Modifiers {
+    let is_held = |key: VIRTUAL_KEY| unsafe { GetAsyncKeyState(i32::from(key.0)) } < 0;
+
+    Modifiers::NONE
+        .with(Modifiers::CTRL, is_held(VK_CONTROL))
+        .with(Modifiers::ALT, is_held(VK_MENU))
+        .with(Modifiers::SHIFT, is_held(VK_SHIFT))
+        .with(Modifiers::WIN, is_held(VK_LWIN) || is_held(VK_RWIN))
+}
+
+/// Follows typing in the foreground window for `convert_last_word`. Runs
+/// while paused too, so the word is right once the app is resumed.
+fn track_word(vk_code: u32) {
+    let window = unsafe { GetForegroundWindow() };
+    let modifiers = held_modifiers();
+
+    LAST_WORD
+        .with_borrow_mut(|word| word.on_key_down(window.0, VIRTUAL_KEY(vk_code as u16), modifiers));
+}
+
+fn send_word_keys(events: &[KeyEvent]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    let inputs: Vec<INPUT> = events
+        .iter()
+        .map(|event| {
+            let mut input = create_kbd_input(event.vk.0, event.is_up);
+            input.Anonymous.ki.dwExtraInfo = WORD_MARKER;
+            input
+        })
+        .collect();
+    send_input(&inputs)
+}
+
+/// Erases the word typed last in the foreground window, switches the layout
+/// and types the word again, so it comes out in the new layout. Without a
+/// word only the layout is switched.
+fn convert_last_word(curr_layout: HKL) -> Result<()> {
+    // The keys would be typed with the modifiers of a chord
+    if !held_modifiers().is_empty() {
+        debug!("Last word isn't converted while modifiers are held");
+        return Ok(());
+    }
+
+    let window = unsafe { GetForegroundWindow() };
+    let (erase, retype) =
+        LAST_WORD.with_borrow(|word| (word.erase(window.0), word.retype(window.0)));
+
+    send_word_keys(&erase)?;
+    match APP_STATE.mode() {
+        // A gesture has no hold to end, so Hold mode just goes to the next one
+        SwitchMode::Hold => {
+            switch_to_next_layout(curr_layout, &[], Direction::Forward)?;
+        }
+        _ => switch_layout(curr_layout)?,
+    }
+    send_word_keys(&retype)
+}
+
+/// How the CapsLock press that made a gesture is seen by the system
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TriggerKey {
+    /// The key down is being handled and its key up is still to come
+    Down,
+    /// The gesture was recognized after the fact
+    Recognized,
+}
+
+fn run_action(action: KeyAction, trigger: TriggerKey) -> Result<()> {
+    let curr_layout = get_foreground_layout();
+
+    match action {
+        KeyAction::Switch => switch_layout(curr_layout),
+        KeyAction::Caps if trigger == TriggerKey::Down => pass_caps_lock(),
+        // The key may be up already, a whole press is made then
+        KeyAction::Caps => {
+            press_caps_lock()?;
+            led::refresh();
+            Ok(())
+        }
+        KeyAction::Previous => switch_to_previous_layout(curr_layout),
+        KeyAction::Reverse => switch_layout_backwards(curr_layout),
+        KeyAction::TogglePause => {
+            tray::toggle_pause();
+            Ok(())
+        }
+        KeyAction::ConvertWord => convert_last_word(curr_layout),
+        KeyAction::Ignore => Ok(()),
+    }
+}
+
+fn run_gestures(gestures: Vec<Gesture>, trigger: TriggerKey) -> Result<()> {
+    for gesture in gestures {
+        debug!("CapsLock gesture {:?}", gesture);
+        for action in BINDINGS.actions(gesture) {
+            run_action(action, trigger)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gestures other than chords are only recognized if any of them is bound.
+/// Hold mode needs every press as it comes.
+fn is_recognizing() -> bool {
+    APP_STATE.mode() != SwitchMode::Hold && Recognizer::<SystemClock>::is_needed(&GESTURE_TIMINGS)
+}
+
+/// Feeds the recognizer and wakes the hook thread up at its next deadline
+fn recognize(feed: impl FnOnce(&mut Recognizer<SystemClock>) -> Vec<Gesture>) -> Vec<Gesture> {
+    let (gestures, deadline) = RECOGNIZER.with_borrow_mut(|recognizer| {
+        let gestures = feed(recognizer);
+        (gestures, recognizer.deadline())
+    });
+
+    unsafe {
+        let timer = GESTURE_TIMER.replace(0);
+        if timer != 0 {
+            let _ = KillTimer(HWND(0), timer);
+        }
+        if let Some(deadline) = deadline {
+            let delay = deadline.wrapping_sub(GetTickCount()) as i32;
+            GESTURE_TIMER.set(SetTimer(HWND(0), 0, delay.max(0) as u32, None));
+        }
+    }
+
+    gestures
+}
+
+fn on_trigger_down() -> Result<()> {
+    let modifiers = held_modifiers();
+    if !modifiers.is_empty() {
+        return run_gestures(vec![Gesture::Chord(modifiers)], TriggerKey::Down);
+    }
+    if !is_recognizing() {
+        return run_gestures(vec![Gesture::Tap(1)], TriggerKey::Down);
+    }
+
+    let gestures = recognize(Recognizer::key_down);
+    run_gestures(gestures, TriggerKey::Recognized)
+}
+
+fn on_trigger_up() -> Result<()> {
+    end_hold();
+    if !is_recognizing() {
+        return Ok(());
+    }
+
+    let gestures = recognize(Recognizer::key_up);
+    run_gestures(gestures, TriggerKey::Recognized)
+}
+
+fn on_gesture_timer() {
+    let gestures = recognize(|recognizer| recognizer.poll().into_iter().collect());
+    if let Err(err) = run_gestures(gestures, TriggerKey::Recognized) {
+        log_hook_error(err);
+    }
+}
+
+/// Toggles pause when CapsLock is pressed with the hotkey modifiers. The
+/// press is swallowed up to its release. Returns whether the CapsLock event
+/// belonged to the hotkey.
+fn on_pause_hotkey(is_down: bool) -> bool {
+    let Some(hotkey) = *PAUSE_HOTKEY else {
+        return false;
+    };
+    if !is_down {
+        return PAUSE_KEY_DOWN.replace(false);
+    }
+    if PAUSE_KEY_DOWN.get() {
+        return true;
+    }
+    if held_modifiers() != hotkey {
+        return false;
+    }
+
+    PAUSE_KEY_DOWN.set(true);
+    tray::toggle_pause();
+    true
+}
+
+/// Errors are only logged here: unwinding out of the hook would abort the
+/// process, and a slow hook gets silently removed by Windows.
 unsafe extern "system" fn keyboard_hook_proc(code: i32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
-    match APP_STATE.is_paused() {
-        Ok(is_paused) => {
-            if is_paused {
-                return CallNextHookEx(HOOK, code, wparam, lparam);
+    if code < 0 {
+        return CallNextHookEx(HOOK, code, wparam, lparam);
+    }
+
+    let kb_struct = &*(lparam.0 as *const KBDLLHOOKSTRUCT);
+    HOOK_LAST_EVENT.store(kb_struct.time, Ordering::Relaxed);
+    let key = HookKey {
+        vk_code: kb_struct.vkCode,
+        // Alt+CapsLock comes as a system key
+        is_down: matches!(wparam.0 as u32, WM_KEYDOWN | WM_SYSKEYDOWN),
+        extra_info: kb_struct.dwExtraInfo,
+    };
+    if key.extra_info != HEARTBEAT_MARKER {
+        HOOK_KEY_EVENTS.fetch_add(1, Ordering::Relaxed);
+        if *TRACKS_WORDS && key.extra_info != WORD_MARKER && key.is_down {
+            track_word(key.vk_code);
+        }
+    }
+
+    match decide(&key, &APP_STATE, on_pause_hotkey) {
+        HookDecision::SwallowHeartbeat | HookDecision::SwallowHotkey => LRESULT(1),
+        HookDecision::PassCaps => {
+            track_caps(key.is_down);
+            CallNextHookEx(HOOK, code, wparam, lparam)
+        }
+        HookDecision::TriggerDown => {
+            latency::begin_event();
+            let result = on_trigger_down();
+            latency::end_event();
+            if let Err(err) = result {
+                log_hook_error(err);
             }
+            LRESULT(1)
         }
-        Err(e) => {
-            eprint!("Error: {:?}", e);
-            return CallNextHookEx(HOOK, code, wparam, lparam);
-        }
-    }
-
-    if code >= 0 {
-        let kb_struct = &*(lparam.0 as *const KBDLLHOOKSTRUCT);
-
-        if kb_struct.vkCode == u32::from(VK_CAPITAL.0) {
-            if wparam.0 == WM_KEYDOWN as usize {
-                let shift_state = GetAsyncKeyState(i32::from(VK_SHIFT.0));
-                if (shift_state as i16) < 0 {
-                    // shift is pressed
-                    let input = INPUT {
-                        r#type: INPUT_KEYBOARD,
-                        Anonymous: INPUT_0 {
-                            ki: KEYBDINPUT {
-                                wVk: VIRTUAL_KEY(VK_CAPITAL.0),
-                                wScan: 0,
-                                dwFlags: KEYEVENTF_EXTENDEDKEY,
-                                time: 0,
-                                dwExtraInfo: 0,
-                            },
-                        },
-                    };
-                    let cb_size = i32::try_from(mem::size_of::<INPUT>());
-                    match cb_size {
-                        Ok(cb_size) => {
-                            SendInput(&[input], cb_size);
-                        }
-                        Err(_) => panic!("SendInput failed"),
-                    }
-                } else {
-                    let curr_layout = get_foreground_layout();
-                    let is_prev_mode = APP_STATE.is_previous_mode().unwrap_or_else(|e| {
-                        eprintln!("Error: {e}");
-                        false
-                    });
-
-                    if !is_prev_mode {
-                        imitate_keyboard_layout_change();
-                    } else {
-                        let previous_layout = APP_STATE.prev_layout().unwrap_or_else(|e| {
-                            eprintln!("Error: {e}");
-                            None
-                        });
-                        match previous_layout {
-                            Some(prev_layout) => {
-                                if curr_layout == prev_layout {
-                                    imitate_keyboard_layout_change();
-                                } else {
-                                    let result = change_keyboard_layout(&prev_layout);
-                                    // In case of success
-                                    if result.0 == 0 {
-                                        match APP_STATE.set_prev_layout(Some(curr_layout)) {
-                                            Ok(_) => {}
-                                            Err(err) => {
-                                                eprintln!("Didn't manage to set previous layout to state. Error: {}", err)
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            None => {
-                                match APP_STATE.set_prev_layout(Some(curr_layout)) {
-                                    Ok(_) => {}
-                                    Err(err) => {
-                                        eprintln!("Didn't manage to set previous layout to state. Error: {}", err)
-                                    }
-                                }
-                                imitate_keyboard_layout_change();
-                            }
-                        }
-                    }
-                }
-                return LRESULT(1);
+        HookDecision::TriggerUp => {
+            latency::begin_event();
+            let result = on_trigger_up();
+            latency::end_event();
+            if let Err(err) = result {
+                log_hook_error(err);
             }
+            track_caps(false);
+            CallNextHookEx(HOOK, code, wparam, lparam)
         }
+        HookDecision::Pass => CallNextHookEx(HOOK, code, wparam, lparam),
+    }
+}
+
+unsafe fn install_hook() -> Result<()> {
+    HOOK = SetWindowsHookExA(WH_KEYBOARD_LL, Some(keyboard_hook_proc), None, 0)
+        .map_err(CapsWitchError::HookInstall)?;
+    HOOK_LAST_EVENT.store(GetTickCount(), Ordering::Relaxed);
+    HOOK_INSTALLED.store(true, Ordering::Relaxed);
+    let is_caps_on = GetKeyState(i32::from(VK_CAPITAL.0)) & 1 != 0;
+    CAPS_STATE.store(CapsTracker::new(is_caps_on).bits(), Ordering::Relaxed);
+
+    Ok(())
+}
+
+unsafe fn reinstall_hook() {
+    // The old hook is most likely gone already
+    let _ = UnhookWindowsHookEx(HOOK);
+    HOOK_INSTALLED.store(false, Ordering::Relaxed);
+    HOOK_REINSTALLS.fetch_add(1, Ordering::Relaxed);
+
+    match install_hook() {
+        Ok(_) => info!("Reinstalled keyboard hook"),
+        Err(err) => error!("{}", err),
     }
-    CallNextHookEx(HOOK, code, wparam, lparam)
 }
 
 pub fn process_switch() -> Result<()> {
     unsafe {
-        HOOK = SetWindowsHookExA(WH_KEYBOARD_LL, Some(keyboard_hook_proc), None, 0)?;
+        HOOK_THREAD_ID.store(GetCurrentThreadId(), Ordering::Relaxed);
+        install_hook()?;
 
         let mut msg = MSG::default();
         while GetMessageW(&mut msg, HWND::default(), 0, 0).as_bool() {
+            if msg.hwnd.0 == 0 && msg.message == WM_REINSTALL_HOOK {
+                reinstall_hook();
+                continue;
+            }
+            if msg.hwnd.0 == 0 && msg.message == WM_TIMER && msg.wParam.0 == GESTURE_TIMER.get() {
+                on_gesture_timer();
+                continue;
+            }
             TranslateMessage(&msg);
             DispatchMessageA(&msg);
         }
 
-        if !UnhookWindowsHookEx(HOOK).is_ok() {
-            return Err(Error::from_win32());
-        }
-
-        Ok(())
+        HOOK_INSTALLED.store(false, Ordering::Relaxed);
+        UnhookWindowsHookEx(HOOK).map_err(CapsWitchError::HookRemove)
     }
 }
@@ -1,39 +1,47 @@
 use crate::autoload::{is_autoload_enabled, remove_autoload, set_autoload};
+use crate::config::CONFIG;
+use crate::diagnostics;
+use crate::error::{self, CapsWitchError, Result};
+use crate::hooks::{self, HookEvent};
+use crate::icon::render_layout_icon;
+use crate::latency;
+use crate::layout_id::{format_layout, parse_layout};
+use crate::logging::{self, log_dir};
+use crate::mode::SwitchMode;
+use crate::osd;
+use crate::switch::{
+    change_window_layout, get_installed_layouts, get_layout_display_name, get_layout_id,
+    get_layout_short_code, get_window_layout, is_caps_on,
+};
 use crate::APP_STATE;
 use image::ImageReader;
-use std::{env, process, thread};
+use log::{debug, error, info, warn};
+use std::{env, os::windows::process::CommandExt, process, process::Command, thread};
 use tray_icon::{
     menu::{
-        AboutMetadata, AboutMetadataBuilder, Menu, MenuEvent, MenuId, MenuItem, MenuItemBuilder,
-        PredefinedMenuItem,
+        AboutMetadata, AboutMetadataBuilder, CheckMenuItem, Menu, MenuEvent, MenuId, MenuItem,
+        MenuItemBuilder, PredefinedMenuItem, Submenu,
     },
     Icon, TrayIconBuilder,
 };
-use windows::Win32::{Foundation::HWND, UI::WindowsAndMessaging::*};
+use windows::{
+    core::HSTRING,
+    Win32::{
+        Foundation::{HWND, LRESULT},
+        UI::{TextServices::HKL, WindowsAndMessaging::*},
+    },
+};
 
-enum ModeLabel {
-    Circular,
-    Previous,
-}
+/// How often the tray checks the foreground layout and the installed layouts
+/// to refresh the icon and the layouts menu
+const REFRESH_INTERVAL_MS: u32 = 250;
 
-impl ModeLabel {
-    fn as_str(&self) -> String {
-        let prefix = "Previous mode:";
+const LAYOUT_ID_PREFIX: &str = "layout:";
+const ROTATION_ID_PREFIX: &str = "rotation:";
+const MODE_ID_PREFIX: &str = "mode:";
 
-        match self {
-            ModeLabel::Circular => format!("{} disabled", prefix),
-            ModeLabel::Previous => format!("{} enabled", prefix),
-        }
-    }
-
-    fn get_label(is_enabled: &bool) -> String {
-        if *is_enabled {
-            ModeLabel::Previous.as_str()
-        } else {
-            ModeLabel::Circular.as_str()
-        }
-    }
-}
+/// Windows that take the foreground when the tray menu is used
+const SHELL_TRAY_CLASSES: [&str; 2] = ["Shell_TrayWnd", "NotifyIconOverflowWindow"];
 
 enum ToggleLabel {
     Pause,
@@ -41,6 +49,14 @@ enum ToggleLabel {
 }
 
 impl ToggleLabel {
+    fn for_state(is_paused: bool) -> Self {
+        if is_paused {
+            ToggleLabel::Resume
+        } else {
+            ToggleLabel::Pause
+        }
+    }
+
     fn as_str(&self) -> &str {
         match self {
             ToggleLabel::Pause => "Pause",
@@ -49,6 +65,28 @@ impl ToggleLabel {
     }
 }
 
+enum MuteLabel {
+    Mute,
+    Unmute,
+}
+
+impl MuteLabel {
+    fn as_str(&self) -> &str {
+        match self {
+            MuteLabel::Mute => "Mute sounds",
+            MuteLabel::Unmute => "Unmute sounds",
+        }
+    }
+
+    fn get_label(is_muted: bool) -> &'static str {
+        if is_muted {
+            MuteLabel::Unmute.as_str()
+        } else {
+            MuteLabel::Mute.as_str()
+        }
+    }
+}
+
 enum AutoloadLabel {
     Enabled,
     Disabled,
@@ -75,25 +113,153 @@ impl AutoloadLabel {
 
 struct MenuItems {
     toggle: MenuItem,
-    prev_mode: MenuItem,
+    mute: MenuItem,
+    mode: Submenu,
+    mode_items: Vec<CheckMenuItem>,
     autoload: MenuItem,
+    logs: MenuItem,
+    diagnostics: MenuItem,
+    stats: MenuItem,
     separator: PredefinedMenuItem,
     about: PredefinedMenuItem,
     quit: MenuItem,
 }
 
-fn get_icon() -> Icon {
+/// "Layouts" submenu with the installed layouts and their rotation toggles.
+/// Rebuilt whenever layouts are added or removed in the system.
+struct LayoutsMenu {
+    submenu: Submenu,
+    rotation: Submenu,
+    separator: PredefinedMenuItem,
+    layouts: Vec<HKL>,
+    active: HKL,
+    items: Vec<CheckMenuItem>,
+    rotation_items: Vec<CheckMenuItem>,
+}
+
+impl LayoutsMenu {
+    fn new(active: HKL) -> Self {
+        let mut layouts_menu = Self {
+            submenu: Submenu::with_id(MenuId::new("layouts"), "Layouts", true),
+            rotation: Submenu::with_id(MenuId::new("rotation"), "Include in rotation", true),
+            separator: PredefinedMenuItem::separator(),
+            layouts: Vec::new(),
+            active,
+            items: Vec::new(),
+            rotation_items: Vec::new(),
+        };
+        layouts_menu.rebuild(get_installed_layouts());
+
+        layouts_menu
+    }
+
+    fn rebuild(&mut self, layouts: Vec<HKL>) {
+        while self.submenu.remove_at(0).is_some() {}
+        while self.rotation.remove_at(0).is_some() {}
+
+        let excluded = APP_STATE.excluded_layouts();
+
+        self.items = layouts
+            .iter()
+            .map(|layout| {
+                CheckMenuItem::with_id(
+                    MenuId::new(format!("{}{}", LAYOUT_ID_PREFIX, format_layout(layout))),
+                    get_layout_display_name(layout),
+                    true,
+                    *layout == self.active,
+                    None,
+                )
+            })
+            .collect();
+        self.rotation_items = layouts
+            .iter()
+            .map(|layout| {
+                CheckMenuItem::with_id(
+                    MenuId::new(format!("{}{}", ROTATION_ID_PREFIX, format_layout(layout))),
+                    get_layout_display_name(layout),
+                    true,
+                    !excluded.contains(layout),
+                    None,
+                )
+            })
+            .collect();
+        self.layouts = layouts;
+
+        for item in &self.items {
+            if let Err(err) = self.submenu.append(item) {
+                warn!("Failed to add layout to tray menu: {}", err);
+            }
+        }
+        for item in &self.rotation_items {
+            if let Err(err) = self.rotation.append(item) {
+                warn!("Failed to add layout to rotation menu: {}", err);
+            }
+        }
+        if let Err(err) = self
+            .submenu
+            .append_items(&[&self.separator, &self.rotation])
+        {
+            warn!("Failed to add rotation menu: {}", err);
+        }
+    }
+
+    fn set_active(&mut self, active: HKL) {
+        self.active = active;
+        for (layout, item) in self.layouts.iter().zip(&self.items) {
+            item.set_checked(*layout == active);
+        }
+    }
+
+    fn refresh(&mut self, active: HKL) {
+        let layouts = get_installed_layouts();
+        if layouts != self.layouts {
+            self.active = active;
+            self.rebuild(layouts);
+        } else if active != self.active {
+            self.set_active(active);
+        }
+    }
+}
+
+fn get_icon() -> Result<Icon> {
     let icon_bytes = include_bytes!("../assets/icon.png");
     let icon_img = ImageReader::new(std::io::Cursor::new(icon_bytes))
         .with_guessed_format()
-        .expect("Failed to guess image format")
+        .map_err(|err| CapsWitchError::Icon(err.to_string()))?
         .decode()
-        .expect("Failed to decode image");
+        .map_err(|err| CapsWitchError::Icon(err.to_string()))?;
     let rgba_bytes = icon_img.to_rgba8().into_raw();
-    let icon = Icon::from_rgba(rgba_bytes, icon_img.width(), icon_img.height())
-        .expect("Failed to create icon from RGBA bytes");
 
-    return icon;
+    Icon::from_rgba(rgba_bytes, icon_img.width(), icon_img.height())
+        .map_err(|err| CapsWitchError::Icon(err.to_string()))
+}
+
+/// Falls back to the application icon if the layout one can't be created
+fn get_layout_icon(layout: &HKL, is_paused: bool) -> Result<Icon> {
+    let img = render_layout_icon(&get_layout_short_code(layout), is_paused);
+    let (width, height) = img.dimensions();
+
+    Icon::from_rgba(img.into_raw(), width, height).or_else(|err| {
+        warn!("Failed to create layout icon: {}", err);
+        get_icon()
+    })
+}
+
+/// App name with the active layout and the caps state, e.g. "CapsWitch:
+/// English — US, en-US:00000409, Caps Lock on"
+fn get_tooltip(layout: &HKL, is_paused: bool, is_caps_on: bool) -> String {
+    let id = get_layout_id(layout);
+    let paused = if is_paused { " (paused)" } else { "" };
+    let caps = if is_caps_on { ", Caps Lock on" } else { "" };
+
+    format!(
+        "{}{}: {}, {}{}",
+        env!("CARGO_PKG_NAME"),
+        paused,
+        id.name,
+        id,
+        caps
+    )
 }
 
 fn get_metadata() -> AboutMetadata {
@@ -121,17 +287,52 @@ fn get_menu_items() -> MenuItems {
         .text(ToggleLabel::Pause.as_str())
         .enabled(true)
         .build();
-    let menu_i_prev_mode: MenuItem = MenuItemBuilder::new()
-        .id(MenuId::new("mode"))
-        .text(ModeLabel::get_label(&APP_STATE.is_previous_mode().unwrap()))
+    let menu_i_mute: MenuItem = MenuItemBuilder::new()
+        .id(MenuId::new("mute"))
+        .text(MuteLabel::get_label(APP_STATE.is_muted()))
         .enabled(true)
         .build();
 
+    let curr_mode = APP_STATE.mode();
+    let menu_i_modes: Vec<CheckMenuItem> = SwitchMode::ALL
+        .iter()
+        .map(|mode| {
+            CheckMenuItem::with_id(
+                MenuId::new(format!("{}{}", MODE_ID_PREFIX, mode.as_str())),
+                mode.label(),
+                true,
+                *mode == curr_mode,
+                None,
+            )
+        })
+        .collect();
+    let menu_i_mode = Submenu::with_id(MenuId::new("mode"), "Mode", true);
+    for item in &menu_i_modes {
+        if let Err(err) = menu_i_mode.append(item) {
+            warn!("Failed to add mode to tray menu: {}", err);
+        }
+    }
+
     let menu_i_autoload: MenuItem = MenuItemBuilder::new()
         .id(MenuId::new("autoload"))
         .text(AutoloadLabel::get_label())
         .enabled(true)
         .build();
+    let menu_i_logs: MenuItem = MenuItemBuilder::new()
+        .id(MenuId::new("logs"))
+        .text("Open log folder")
+        .enabled(log_dir().is_some())
+        .build();
+    let menu_i_diagnostics: MenuItem = MenuItemBuilder::new()
+        .id(MenuId::new("diagnostics"))
+        .text("Save diagnostics")
+        .enabled(true)
+        .build();
+    let menu_i_stats: MenuItem = MenuItemBuilder::new()
+        .id(MenuId::new("stats"))
+        .text("Latency stats")
+        .enabled(true)
+        .build();
     let menu_i_quit: MenuItem = MenuItemBuilder::new()
         .id(MenuId::new("quit"))
         .text("Quit")
@@ -143,133 +344,298 @@ fn get_menu_items() -> MenuItems {
     let metadata = get_metadata();
     let menu_i_about: PredefinedMenuItem = PredefinedMenuItem::about(Some("About"), Some(metadata));
 
-    let items = MenuItems {
+    MenuItems {
         toggle: menu_i_toggle,
-        prev_mode: menu_i_prev_mode,
+        mute: menu_i_mute,
+        mode: menu_i_mode,
+        mode_items: menu_i_modes,
         autoload: menu_i_autoload,
+        logs: menu_i_logs,
+        diagnostics: menu_i_diagnostics,
+        stats: menu_i_stats,
         separator,
         about: menu_i_about,
         quit: menu_i_quit,
+    }
+}
+
+fn autoload_handler(menu_i: &MenuItem) {
+    let result = if is_autoload_enabled() {
+        remove_autoload()
+    } else {
+        set_autoload(APP_STATE.mode().arg())
     };
 
-    items
+    match result {
+        Ok(_) => menu_i.set_text(AutoloadLabel::get_label()),
+        Err(err) => error::notify(&err),
+    }
 }
 
-fn autoload_handler(menu_i: &MenuItem) {
+fn mode_hander(mode_items: &[CheckMenuItem], value: &str) {
+    let Some(mode) = SwitchMode::parse(value) else {
+        return;
+    };
+
+    APP_STATE.set_mode(mode);
+    // Check items toggle themselves on click, so the marks are always reset
+    for (item_mode, item) in SwitchMode::ALL.iter().zip(mode_items) {
+        item.set_checked(*item_mode == mode);
+    }
+
+    info!("Switched to {} mode", mode.as_str());
+    hooks::dispatch(HookEvent::ModeChange { mode });
+
     if is_autoload_enabled() {
-        let result = remove_autoload();
-        if result {
-            menu_i.set_text(AutoloadLabel::Disabled.as_str());
+        if let Err(err) = set_autoload(mode.arg()) {
+            warn!("Could not update autoload mode: {}", err);
         }
+    }
+}
+
+/// Pauses or resumes switching, from the menu or with CapsLock. The tray
+/// catches up on its next refresh. Safe to call from the keyboard hook.
+pub fn toggle_pause() -> bool {
+    let is_paused = APP_STATE.toggle_pause();
+    osd::show_pause(is_paused);
+    hooks::dispatch(if is_paused {
+        HookEvent::Pause
     } else {
-        let is_prev_mode = APP_STATE.is_previous_mode().unwrap_or_else(|e| {
-            eprintln!("Could not get previous mode for autoload: {}", e);
-            false
-        });
+        HookEvent::Resume
+    });
 
-        let result = set_autoload(if is_prev_mode {
-            Some(String::from("--previous"))
-        } else {
-            None
+    is_paused
+}
+
+fn toggle_handler(menu_i: &MenuItem) {
+    let is_paused = toggle_pause();
+    menu_i.set_text(ToggleLabel::for_state(is_paused).as_str());
+}
+
+fn layout_handler(layouts_menu: &mut LayoutsMenu, target: HWND, value: &str) {
+    let Some(layout) = parse_layout(value) else {
+        return;
+    };
+
+    let prev_layout = layouts_menu.active;
+    // Success is confirmed by the next refresh, which also reverts the check
+    // mark if the window didn't accept the layout
+    layouts_menu.set_active(layout);
+    if change_window_layout(target, &layout) == Some(LRESULT(0)) {
+        hooks::dispatch(HookEvent::LayoutChange {
+            old: prev_layout,
+            new: Some(layout),
         });
+    }
+}
 
-        if result {
-            menu_i.set_text(AutoloadLabel::Enabled.as_str());
-        }
+fn rotation_handler(layouts_menu: &LayoutsMenu, value: &str) {
+    let Some(layout) = parse_layout(value) else {
+        return;
+    };
+
+    let is_excluded = APP_STATE.toggle_excluded_layout(layout);
+    let position = layouts_menu.layouts.iter().position(|&l| l == layout);
+    if let Some(item) = position.and_then(|i| layouts_menu.rotation_items.get(i)) {
+        item.set_checked(!is_excluded);
     }
 }
 
-fn mode_hander(menu_i: &MenuItem) {
-    match APP_STATE.toggle_previous_mode() {
-        Ok(is_prev_mode) => {
-            let text = ModeLabel::get_label(&is_prev_mode);
-            menu_i.set_text(text);
-
-            if is_autoload_enabled() {
-                if is_prev_mode {
-                    set_autoload(Some(String::from("--previous")));
-                } else {
-                    set_autoload(None);
-                }
+/// Whether the window can receive layouts chosen in the tray menu, i.e. it is
+/// neither the taskbar nor one of our own windows
+fn is_layout_target(hwnd: HWND) -> bool {
+    if hwnd.0 == 0 {
+        return false;
+    }
+
+    let mut process_id: u32 = 0;
+    unsafe { GetWindowThreadProcessId(hwnd, Some(&mut process_id)) };
+    if process_id == process::id() {
+        return false;
+    }
+
+    let mut class_name = [0u16; 64];
+    let len = unsafe { GetClassNameW(hwnd, &mut class_name) };
+    let class_name = String::from_utf16_lossy(&class_name[..usize::try_from(len).unwrap_or(0)]);
+
+    !SHELL_TRAY_CLASSES.contains(&class_name.as_str())
+}
+
+fn mute_handler(menu_i: &MenuItem) {
+    menu_i.set_text(MuteLabel::get_label(APP_STATE.toggle_mute()));
+}
+
+fn logs_handler() {
+    let Some(dir) = log_dir() else {
+        return;
+    };
+
+    if let Err(err) = Command::new("explorer").arg(&dir).spawn() {
+        error!("Failed to open log folder {}: {}", dir.display(), err);
+    }
+}
+
+/// Saves the bundle and shows it selected in Explorer, ready to be attached
+fn diagnostics_handler(target: HWND) {
+    match diagnostics::save(target) {
+        Ok(path) => {
+            info!("Saved diagnostics to {}", path.display());
+            // Explorer expects only the path quoted
+            let select = format!("/select,\"{}\"", path.display());
+            if let Err(err) = Command::new("explorer").raw_arg(select).spawn() {
+                error!("Failed to show diagnostics in Explorer: {}", err);
             }
         }
-        Err(err) => {
-            eprintln!("Could not toggle mode: {}", err);
-            return;
-        }
+        Err(err) => error::notify(&err),
     }
 }
 
-fn toggle_handler(menu_i: &MenuItem) {
-    match APP_STATE.toggle_pause() {
-        Ok(is_paused) => {
-            let text = if is_paused {
-                ToggleLabel::Resume.as_str()
-            } else {
-                ToggleLabel::Pause.as_str()
-            };
-            menu_i.set_text(text);
-        }
-        Err(err) => {
-            eprintln!("Couldn't toggle pause. Error: {}", err);
-            return;
+/// Shows the stats on another thread, as the message box blocks until closed
+fn stats_handler() {
+    thread::spawn(|| {
+        let text = HSTRING::from(latency::get_stats());
+        let caption = HSTRING::from(format!("{} latency", env!("CARGO_PKG_NAME")));
+        unsafe {
+            MessageBoxW(None, &text, &caption, MB_OK | MB_ICONINFORMATION);
         }
-    }
+    });
 }
 
 fn quit_hander() {
-    println!("Exiting application...");
+    info!("Exiting application");
+    logging::flush();
     process::exit(0);
 }
 
-pub fn create_tray() {
-    thread::spawn(move || {
-        let tray_menu: Menu = Menu::new();
-        let menu_items: MenuItems = get_menu_items();
-        tray_menu
-            .append_items(&[
-                &menu_items.toggle,
-                &menu_items.prev_mode,
-                &menu_items.autoload,
-                &menu_items.separator,
-                &menu_items.about,
-                &menu_items.quit,
-            ])
-            .expect("Failed to add items to tray menu");
-
-        let icon: Icon = get_icon();
-        let _tray_icon = TrayIconBuilder::new()
-            .with_tooltip(env!("CARGO_PKG_NAME"))
-            .with_icon(icon)
-            .with_menu(Box::new(tray_menu))
-            .build()
-            .unwrap();
-
-        let menu_event_rx = MenuEvent::receiver();
+fn run_tray() -> Result<()> {
+    let mut target = unsafe { GetForegroundWindow() };
+    let mut shown_layout = get_window_layout(target);
+    let mut shown_paused = APP_STATE.is_paused();
+    let mut shown_caps = is_caps_on();
+
+    let tray_menu: Menu = Menu::new();
+    let menu_items: MenuItems = get_menu_items();
+    let mut layouts_menu = LayoutsMenu::new(shown_layout);
+    let layouts_separator = PredefinedMenuItem::separator();
+    tray_menu
+        .append_items(&[
+            &layouts_menu.submenu,
+            &layouts_separator,
+            &menu_items.toggle,
+            &menu_items.mode,
+            &menu_items.autoload,
+            &menu_items.logs,
+            &menu_items.diagnostics,
+            &menu_items.stats,
+            &menu_items.separator,
+            &menu_items.about,
+            &menu_items.quit,
+        ])
+        .map_err(|err| CapsWitchError::Tray(err.to_string()))?;
+    // The mute toggle sits next to Pause and only makes sense with sounds on
+    if CONFIG.sound.enabled {
+        if let Err(err) = tray_menu.insert(&menu_items.mute, 3) {
+            warn!("Failed to add mute item to tray menu: {}", err);
+        }
+    }
 
-        unsafe {
-            let mut msg: MSG = std::mem::zeroed();
-            while GetMessageW(&mut msg, HWND(0), 0, 0).into() {
-                TranslateMessage(&msg);
-                DispatchMessageW(&msg);
-
-                // Check if we received a WM_QUIT message to break the loop
-                if msg.message == WM_QUIT {
-                    break;
+    let icon: Icon = get_layout_icon(&shown_layout, shown_paused)?;
+    let tray_icon = TrayIconBuilder::new()
+        .with_tooltip(get_tooltip(&shown_layout, shown_paused, shown_caps))
+        .with_icon(icon)
+        .with_menu(Box::new(tray_menu))
+        .build()
+        .map_err(|err| CapsWitchError::Tray(err.to_string()))?;
+
+    let menu_event_rx = MenuEvent::receiver();
+
+    unsafe {
+        // A thread timer wakes the loop up to pick layout changes made
+        // with CapsLock or any other way
+        SetTimer(HWND(0), 0, REFRESH_INTERVAL_MS, None);
+
+        let mut msg: MSG = std::mem::zeroed();
+        while GetMessageW(&mut msg, HWND(0), 0, 0).into() {
+            TranslateMessage(&msg);
+            DispatchMessageW(&msg);
+
+            // Check if we received a WM_QUIT message to break the loop
+            if msg.message == WM_QUIT {
+                break;
+            }
+
+            if let Ok(event) = menu_event_rx.try_recv() {
+                match event.id.as_ref() {
+                    "quit" => quit_hander(),
+                    "autoload" => autoload_handler(&menu_items.autoload),
+                    "logs" => logs_handler(),
+                    "diagnostics" => diagnostics_handler(target),
+                    "stats" => stats_handler(),
+                    "toggle" => toggle_handler(&menu_items.toggle),
+                    "mute" => mute_handler(&menu_items.mute),
+                    id if id.starts_with(LAYOUT_ID_PREFIX) => {
+                        layout_handler(&mut layouts_menu, target, &id[LAYOUT_ID_PREFIX.len()..])
+                    }
+                    id if id.starts_with(MODE_ID_PREFIX) => {
+                        mode_hander(&menu_items.mode_items, &id[MODE_ID_PREFIX.len()..])
+                    }
+                    id if id.starts_with(ROTATION_ID_PREFIX) => {
+                        rotation_handler(&layouts_menu, &id[ROTATION_ID_PREFIX.len()..])
+                    }
+                    _ => {
+                        debug!("Menu item clicked: {:?}", event.id);
+                    }
                 }
+            }
 
-                if let Ok(event) = menu_event_rx.try_recv() {
-                    match event.id.as_ref() {
-                        "quit" => quit_hander(),
-                        "autoload" => autoload_handler(&menu_items.autoload),
-                        "mode" => mode_hander(&menu_items.prev_mode),
-                        "toggle" => toggle_handler(&menu_items.toggle),
-                        _ => {
-                            println!("Menu item clicked: {:?}", event.id);
+            let foreground = GetForegroundWindow();
+            if is_layout_target(foreground) {
+                target = foreground;
+            }
+
+            let layout = get_window_layout(target);
+            layouts_menu.refresh(layout);
+
+            let is_paused = APP_STATE.is_paused();
+            let is_caps = is_caps_on();
+            if layout != shown_layout || is_paused != shown_paused {
+                match get_layout_icon(&layout, is_paused) {
+                    Ok(icon) => {
+                        if let Err(err) = tray_icon.set_icon(Some(icon)) {
+                            error!("Failed to update tray icon: {}", err);
                         }
                     }
+                    Err(err) => error!("{}", err),
+                }
+            }
+            if is_paused != shown_paused {
+                menu_items
+                    .toggle
+                    .set_text(ToggleLabel::for_state(is_paused).as_str());
+            }
+            if layout != shown_layout || is_paused != shown_paused || is_caps != shown_caps {
+                shown_layout = layout;
+                shown_paused = is_paused;
+                shown_caps = is_caps;
+                let tooltip = get_tooltip(&layout, is_paused, is_caps);
+                if let Err(err) = tray_icon.set_tooltip(Some(tooltip)) {
+                    error!("Failed to update tray tooltip: {}", err);
                 }
             }
         }
+    }
+
+    Ok(())
+}
+
+/// Runs the tray on its own thread. Without the tray the app can't be paused
+/// or closed, so failing to create it ends the process.
+pub fn create_tray() {
+    thread::spawn(|| {
+        if let Err(err) = run_tray() {
+            error::notify(&err);
+            logging::flush();
+            process::exit(1);
+        }
     });
 }
@@ -1,32 +1,61 @@
-use std::result::Result;
+use crate::error::{CapsWitchError, Result};
+use std::{io::Write, path::PathBuf};
 use windows::{
     core::*,
-    Win32::{Foundation::*, System::Threading::CreateMutexW, UI::WindowsAndMessaging::*},
+    Win32::{
+        Foundation::*,
+        System::Console::{AttachConsole, ATTACH_PARENT_PROCESS},
+        System::Threading::{
+            CreateMutexW, OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+            PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+    },
 };
 
-pub fn check_for_another_instance() -> Result<(), Box<dyn std::error::Error>> {
+pub fn check_for_another_instance() -> Result<()> {
     // Create a named mutex
     let mutex_name = w!("Global\\CapsWitch");
-    let _mutex = unsafe { CreateMutexW(None, true, mutex_name) }?;
+    let _mutex =
+        unsafe { CreateMutexW(None, true, mutex_name) }.map_err(CapsWitchError::InstanceMutex)?;
 
     // Check if the mutex already exists (another instance is running)
     let last_error = unsafe { GetLastError() };
     match last_error {
-        Ok(_) => {}
-        Err(_) => {
-            // Show a message box to the user
-            unsafe {
-                MessageBoxW(
-                    None,
-                    w!("Another instance of the application is already running."),
-                    w!("Application Error"),
-                    MB_OK | MB_ICONWARNING,
-                );
-            }
+        Ok(_) => Ok(()),
+        Err(_) => Err(CapsWitchError::AnotherInstance),
+    }
+}
 
-            std::process::exit(1);
-        }
+/// Full path of the executable of a process
+pub fn get_process_path(process_id: u32) -> Option<PathBuf> {
+    let process =
+        unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, process_id) }.ok()?;
+    let mut buffer = [0u16; MAX_PATH as usize];
+    let mut len = buffer.len() as u32;
+    let result = unsafe {
+        QueryFullProcessImageNameW(
+            process,
+            PROCESS_NAME_WIN32,
+            PWSTR(buffer.as_mut_ptr()),
+            &mut len,
+        )
+    };
+    unsafe {
+        let _ = CloseHandle(process);
     }
 
-    Ok(())
+    result
+        .ok()
+        .map(|_| PathBuf::from(String::from_utf16_lossy(&buffer[..len as usize])))
+}
+
+/// Prints to the console the app was started from. The app has no console of
+/// its own, so without one the text only reaches a redirected output.
+pub fn print_to_console(text: &str) {
+    unsafe {
+        let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+    let mut stdout = std::io::stdout();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
 }
@@ -0,0 +1,515 @@
+#[cfg(windows)]
+use {
+    crate::helper::is_out_of_reach,
+    crate::switch::{get_hook_last_event, request_hook_reinstall, send_heartbeat},
+    log::{error, warn},
+    std::{thread, time::Duration},
+    windows::Win32::{
+        System::SystemInformation::GetTickCount,
+        UI::{
+            Input::KeyboardAndMouse::{GetLastInputInfo, LASTINPUTINFO},
+            WindowsAndMessaging::GetForegroundWindow,
+        },
+    },
+};
+
+#[cfg(windows)]
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Input the hook missed for longer than this triggers a heartbeat. Mouse
+/// input never reaches the keyboard hook, so it only starts a check.
+const MISSED_INPUT_MS: u32 = 1000;
+/// Time the hook has to receive a heartbeat before it's considered removed
+const HEARTBEAT_TIMEOUT_MS: u32 = 2000;
+/// Time after a heartbeat arrived before the next one is sent. While only the
+/// mouse is used, every check finds input the hook missed.
+const PROBE_INTERVAL_MS: u32 = 30_000;
+
+/// Milliseconds of the system tick count, which wraps around every 49.7 days
+pub type Tick = u32;
+
+/// Whether `later` is more than `ms` after `earlier`, also across wrap-arounds
+pub fn is_later_by(later: Tick, earlier: Tick, ms: u32) -> bool {
+    later.wrapping_sub(earlier) as i32 > ms as i32
+}
+
+pub trait Clock {
+    fn now(&self) -> Tick;
+}
+
+/// The keyboard hook as the watchdog sees it
+pub trait HookBackend {
+    /// Time of the last input the system received from any device
+    fn last_input(&self) -> Tick;
+    /// Time of the last event the hook received
+    fn last_hook_event(&self) -> Tick;
+    /// Injects a key event only the hook sees. Returns `false` if the system
+    /// rejected the input.
+    fn send_heartbeat(&self) -> bool;
+    /// Whether the foreground window is elevated while the app isn't. Input
+    /// injected then is dropped before the hook sees it.
+    fn is_foreground_out_of_reach(&self) -> bool;
+    fn reinstall(&self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Healthy,
+    /// A heartbeat was sent and the hook hasn't received it yet
+    Probing,
+    /// The hook missed the heartbeat and is being installed again
+    Reinstalled,
+    ReinstallFailed,
+}
+
+/// Counters of the keyboard hook since the app started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HookHealth {
+    pub is_installed: bool,
+    /// Key events the hook received
+    pub key_events: u64,
+    pub switches: u64,
+    pub errors: u64,
+    /// Times the watchdog found the hook removed by Windows
+    pub reinstalls: u64,
+}
+
+/// Detects a keyboard hook that Windows removed, e.g. for exceeding
+/// `LowLevelHooksTimeout`, which happens without any notification.
+///
+/// Input the hook hasn't seen is only a hint, as it may come from the mouse.
+/// The hook is probed with a heartbeat then, and reinstalled if the
+/// heartbeat doesn't arrive in time.
+pub struct Watchdog<C: Clock, B: HookBackend> {
+    clock: C,
+    backend: B,
+    heartbeat_sent: Option<Tick>,
+    /// When the last heartbeat arrived
+    heartbeat_received: Option<Tick>,
+    incidents: u64,
+}
+
+impl<C: Clock, B: HookBackend> Watchdog<C, B> {
+    pub fn new(clock: C, backend: B) -> Self {
+        Self {
+            clock,
+            backend,
+            heartbeat_sent: None,
+            heartbeat_received: None,
+            incidents: 0,
+        }
+    }
+
+    /// Number of times the hook was found removed
+    pub fn incidents(&self) -> u64 {
+        self.incidents
+    }
+
+    pub fn check(&mut self) -> Verdict {
+        let now = self.clock.now();
+        let last_hook_event = self.backend.last_hook_event();
+
+        let Some(sent) = self.heartbeat_sent else {
+            if self.should_probe(now, last_hook_event) && self.backend.send_heartbeat() {
+                self.heartbeat_sent = Some(now);
+                return Verdict::Probing;
+            }
+            return Verdict::Healthy;
+        };
+
+        if !is_later_by(sent, last_hook_event, 0) {
+            self.heartbeat_sent = None;
+            self.heartbeat_received = Some(now);
+            return Verdict::Healthy;
+        }
+        if !is_later_by(now, sent, HEARTBEAT_TIMEOUT_MS) {
+            return Verdict::Probing;
+        }
+
+        self.heartbeat_sent = None;
+        self.heartbeat_received = None;
+        self.incidents += 1;
+        if self.backend.reinstall() {
+            Verdict::Reinstalled
+        } else {
+            Verdict::ReinstallFailed
+        }
+    }
+
+    fn should_probe(&self, now: Tick, last_hook_event: Tick) -> bool {
+        if !is_later_by(self.backend.last_input(), last_hook_event, MISSED_INPUT_MS) {
+            return false;
+        }
+        if self
+            .heartbeat_received
+            .is_some_and(|received| !is_later_by(now, received, PROBE_INTERVAL_MS))
+        {
+            return false;
+        }
+
+        !self.backend.is_foreground_out_of_reach()
+    }
+}
+
+#[cfg(windows)]
+pub struct SystemClock;
+
+#[cfg(windows)]
+impl Clock for SystemClock {
+    fn now(&self) -> Tick {
+        unsafe { GetTickCount() }
+    }
+}
+
+#[cfg(windows)]
+pub struct SystemHookBackend;
+
+#[cfg(windows)]
+impl HookBackend for SystemHookBackend {
+    fn last_input(&self) -> Tick {
+        let mut info = LASTINPUTINFO {
+            cbSize: std::mem::size_of::<LASTINPUTINFO>() as u32,
+            dwTime: 0,
+        };
+        if unsafe { GetLastInputInfo(&mut info) }.as_bool() {
+            info.dwTime
+        } else {
+            // Nothing to compare, so no input counts as missed
+            self.last_hook_event()
+        }
+    }
+
+    fn last_hook_event(&self) -> Tick {
+        get_hook_last_event()
+    }
+
+    fn send_heartbeat(&self) -> bool {
+        send_heartbeat()
+    }
+
+    fn is_foreground_out_of_reach(&self) -> bool {
+        is_out_of_reach(unsafe { GetForegroundWindow() })
+    }
+
+    fn reinstall(&self) -> bool {
+        request_hook_reinstall()
+    }
+}
+
+#[cfg(windows)]
+pub fn start() {
+    thread::spawn(|| {
+        let mut watchdog = Watchdog::new(SystemClock, SystemHookBackend);
+
+        loop {
+            thread::sleep(CHECK_INTERVAL);
+
+            match watchdog.check() {
+                Verdict::Healthy | Verdict::Probing => {}
+                Verdict::Reinstalled => warn!(
+                    "Keyboard hook stopped receiving events, reinstalling it (incident {})",
+                    watchdog.incidents()
+                ),
+                Verdict::ReinstallFailed => {
+                    error!("Keyboard hook stopped receiving events and can't be reinstalled")
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Default)]
+    struct FakeClock {
+        now: Cell<Tick>,
+    }
+
+    impl FakeClock {
+        fn advance(&self, ms: u32) {
+            self.now.set(self.now.get().wrapping_add(ms));
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Tick {
+            self.now.get()
+        }
+    }
+
+    /// Hook that sees every input while `is_alive`, heartbeats included
+    struct FakeHook {
+        is_alive: Cell<bool>,
+        can_send: bool,
+        is_out_of_reach: Cell<bool>,
+        can_reinstall: bool,
+        last_input: Cell<Tick>,
+        last_hook_event: Cell<Tick>,
+        heartbeats: Cell<u32>,
+        reinstalls: Cell<u32>,
+    }
+
+    impl FakeHook {
+        fn new() -> Self {
+            Self {
+                is_alive: Cell::new(true),
+                can_send: true,
+                is_out_of_reach: Cell::new(false),
+                can_reinstall: true,
+                last_input: Cell::new(0),
+                last_hook_event: Cell::new(0),
+                heartbeats: Cell::new(0),
+                reinstalls: Cell::new(0),
+            }
+        }
+
+        fn input(&self, now: Tick) {
+            self.last_input.set(now);
+            if self.is_alive.get() {
+                self.last_hook_event.set(now);
+            }
+        }
+    }
+
+    impl HookBackend for FakeHook {
+        fn last_input(&self) -> Tick {
+            self.last_input.get()
+        }
+
+        fn last_hook_event(&self) -> Tick {
+            self.last_hook_event.get()
+        }
+
+        fn send_heartbeat(&self) -> bool {
+            self.heartbeats.set(self.heartbeats.get() + 1);
+            self.can_send
+        }
+
+        fn is_foreground_out_of_reach(&self) -> bool {
+            self.is_out_of_reach.get()
+        }
+
+        fn reinstall(&self) -> bool {
+            self.reinstalls.set(self.reinstalls.get() + 1);
+            if self.can_reinstall {
+                self.is_alive.set(true);
+            }
+            self.can_reinstall
+        }
+    }
+
+    fn watchdog_at(start: Tick, hook: FakeHook) -> Watchdog<FakeClock, FakeHook> {
+        hook.last_input.set(start);
+        hook.last_hook_event.set(start);
+        Watchdog::new(
+            FakeClock {
+                now: Cell::new(start),
+            },
+            hook,
+        )
+    }
+
+    /// Input the hook missed, old enough to trigger a heartbeat
+    fn miss_input(watchdog: &Watchdog<FakeClock, FakeHook>) {
+        watchdog.backend.is_alive.set(false);
+        watchdog.clock.advance(MISSED_INPUT_MS + 1);
+        watchdog.backend.input(watchdog.clock.now());
+    }
+
+    /// Mouse input the hook doesn't see, answering a heartbeat if one is sent
+    fn mouse_input(watchdog: &mut Watchdog<FakeClock, FakeHook>) -> Verdict {
+        watchdog.clock.advance(MISSED_INPUT_MS + 1);
+        watchdog.backend.last_input.set(watchdog.clock.now());
+        let verdict = watchdog.check();
+        if verdict == Verdict::Probing {
+            watchdog.backend.last_hook_event.set(watchdog.clock.now());
+            assert_eq!(watchdog.check(), Verdict::Healthy);
+        }
+
+        verdict
+    }
+
+    #[test]
+    fn is_later_by_compares_ticks() {
+        assert!(is_later_by(1001, 0, 1000));
+        assert!(!is_later_by(1000, 0, 1000));
+        assert!(!is_later_by(0, 1000, 0));
+        assert!(!is_later_by(5, 5, 0));
+        assert!(is_later_by(6, 5, 0));
+    }
+
+    #[test]
+    fn is_later_by_handles_wrap_around() {
+        assert!(is_later_by(500, Tick::MAX - 500, 1000));
+        assert!(!is_later_by(499, Tick::MAX - 500, 1000));
+        assert!(!is_later_by(Tick::MAX - 500, 500, 0));
+    }
+
+    #[test]
+    fn healthy_while_hook_sees_input() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        for _ in 0..10 {
+            watchdog.clock.advance(MISSED_INPUT_MS * 2);
+            watchdog.backend.input(watchdog.clock.now());
+            assert_eq!(watchdog.check(), Verdict::Healthy);
+        }
+        assert_eq!(watchdog.backend.heartbeats.get(), 0);
+        assert_eq!(watchdog.incidents(), 0);
+    }
+
+    #[test]
+    fn healthy_while_missed_input_is_recent() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        watchdog.backend.last_input.set(MISSED_INPUT_MS);
+        watchdog.clock.advance(MISSED_INPUT_MS);
+
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+        assert_eq!(watchdog.backend.heartbeats.get(), 0);
+    }
+
+    #[test]
+    fn probes_missed_input() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        miss_input(&watchdog);
+
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        assert_eq!(watchdog.backend.heartbeats.get(), 1);
+        // Only one heartbeat is in flight
+        watchdog.clock.advance(HEARTBEAT_TIMEOUT_MS);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        assert_eq!(watchdog.backend.heartbeats.get(), 1);
+    }
+
+    #[test]
+    fn healthy_when_heartbeat_can_not_be_sent() {
+        let hook = FakeHook {
+            can_send: false,
+            ..FakeHook::new()
+        };
+        let mut watchdog = watchdog_at(0, hook);
+
+        miss_input(&watchdog);
+
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+        assert_eq!(watchdog.backend.heartbeats.get(), 1);
+        assert_eq!(watchdog.backend.reinstalls.get(), 0);
+    }
+
+    #[test]
+    fn healthy_once_heartbeat_is_received() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+
+        // The missed input came from the mouse, the hook gets the heartbeat
+        watchdog.clock.advance(10);
+        watchdog.backend.last_hook_event.set(watchdog.clock.now());
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+        assert_eq!(watchdog.incidents(), 0);
+        assert_eq!(watchdog.backend.reinstalls.get(), 0);
+    }
+
+    #[test]
+    fn reinstalls_after_heartbeat_timeout() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        watchdog.clock.advance(HEARTBEAT_TIMEOUT_MS);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        watchdog.clock.advance(1);
+
+        assert_eq!(watchdog.check(), Verdict::Reinstalled);
+        assert_eq!(watchdog.incidents(), 1);
+        assert_eq!(watchdog.backend.reinstalls.get(), 1);
+
+        // The reinstalled hook sees input again
+        watchdog.clock.advance(MISSED_INPUT_MS * 2);
+        watchdog.backend.input(watchdog.clock.now());
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+    }
+
+    #[test]
+    fn reports_failed_reinstall_and_probes_again() {
+        let hook = FakeHook {
+            can_reinstall: false,
+            ..FakeHook::new()
+        };
+        let mut watchdog = watchdog_at(0, hook);
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        watchdog.clock.advance(HEARTBEAT_TIMEOUT_MS + 1);
+        assert_eq!(watchdog.check(), Verdict::ReinstallFailed);
+        assert_eq!(watchdog.incidents(), 1);
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        assert_eq!(watchdog.backend.heartbeats.get(), 2);
+        watchdog.clock.advance(HEARTBEAT_TIMEOUT_MS + 1);
+        assert_eq!(watchdog.check(), Verdict::ReinstallFailed);
+        assert_eq!(watchdog.incidents(), 2);
+    }
+
+    #[test]
+    fn handles_tick_wrap_around() {
+        let mut watchdog = watchdog_at(Tick::MAX - MISSED_INPUT_MS, FakeHook::new());
+
+        miss_input(&watchdog);
+        assert!(watchdog.clock.now() < MISSED_INPUT_MS);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+
+        watchdog.clock.advance(HEARTBEAT_TIMEOUT_MS);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        watchdog.clock.advance(1);
+        assert_eq!(watchdog.check(), Verdict::Reinstalled);
+    }
+
+    #[test]
+    fn heartbeat_received_across_wrap_around() {
+        let mut watchdog = watchdog_at(Tick::MAX - MISSED_INPUT_MS - 5, FakeHook::new());
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        watchdog.clock.advance(10);
+        watchdog.backend.last_hook_event.set(watchdog.clock.now());
+
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+    }
+
+    #[test]
+    fn skips_probing_while_foreground_is_out_of_reach() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+        watchdog.backend.is_out_of_reach.set(true);
+
+        miss_input(&watchdog);
+        assert_eq!(watchdog.check(), Verdict::Healthy);
+        assert_eq!(watchdog.backend.heartbeats.get(), 0);
+
+        watchdog.backend.is_out_of_reach.set(false);
+        assert_eq!(watchdog.check(), Verdict::Probing);
+        assert_eq!(watchdog.backend.heartbeats.get(), 1);
+    }
+
+    #[test]
+    fn rate_limits_heartbeats_while_mouse_is_used() {
+        let mut watchdog = watchdog_at(0, FakeHook::new());
+
+        assert_eq!(mouse_input(&mut watchdog), Verdict::Probing);
+        let checks = PROBE_INTERVAL_MS / (MISSED_INPUT_MS + 1);
+        for _ in 0..checks {
+            assert_eq!(mouse_input(&mut watchdog), Verdict::Healthy);
+        }
+        assert_eq!(watchdog.backend.heartbeats.get(), 1);
+
+        assert_eq!(mouse_input(&mut watchdog), Verdict::Probing);
+        assert_eq!(watchdog.backend.heartbeats.get(), 2);
+    }
+}
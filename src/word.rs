@@ -0,0 +1,378 @@
+use crate::bindings::Modifiers;
+use windows::Win32::UI::Input::KeyboardAndMouse::{
+    VIRTUAL_KEY, VK_0, VK_9, VK_A, VK_BACK, VK_CAPITAL, VK_CONTROL, VK_LCONTROL, VK_LMENU,
+    VK_LSHIFT, VK_LWIN, VK_MENU, VK_OEM_1, VK_OEM_102, VK_OEM_3, VK_OEM_4, VK_OEM_8, VK_RCONTROL,
+    VK_RMENU, VK_RSHIFT, VK_RWIN, VK_SHIFT, VK_SPACE, VK_Z,
+};
+
+/// Longer words are not kept, typing them again would take too long
+const MAX_WORD_LEN: usize = 64;
+/// Spaces after the word that are typed again with it
+const MAX_TRAILING_SPACES: usize = 4;
+
+/// Key event sent to type the word again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub vk: VIRTUAL_KEY,
+    pub is_up: bool,
+}
+
+impl KeyEvent {
+    fn press(vk: VIRTUAL_KEY) -> [KeyEvent; 2] {
+        [KeyEvent { vk, is_up: false }, KeyEvent { vk, is_up: true }]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TypedKey {
+    vk: VIRTUAL_KEY,
+    is_shifted: bool,
+}
+
+/// Keys that type a character that depends on the layout
+fn is_character_key(vk: VIRTUAL_KEY) -> bool {
+    (VK_0.0..=VK_9.0).contains(&vk.0)
+        || (VK_A.0..=VK_Z.0).contains(&vk.0)
+        || (VK_OEM_1.0..=VK_OEM_3.0).contains(&vk.0)
+        || (VK_OEM_4.0..=VK_OEM_8.0).contains(&vk.0)
+        || vk == VK_OEM_102
+}
+
+fn is_modifier_key(vk: VIRTUAL_KEY) -> bool {
+    [
+        VK_SHIFT,
+        VK_LSHIFT,
+        VK_RSHIFT,
+        VK_CONTROL,
+        VK_LCONTROL,
+        VK_RCONTROL,
+        VK_MENU,
+        VK_LMENU,
+        VK_RMENU,
+        VK_LWIN,
+        VK_RWIN,
+        VK_CAPITAL,
+    ]
+    .contains(&vk)
+}
+
+/// Keys of the word typed last in a window, to type it again in another
+/// layout. The keys are kept rather than the characters, as the same keys
+/// make the word in the other layout.
+///
+/// Anything but typing, Backspace and spaces after the word ends it, and so
+/// does switching to another window.
+#[derive(Debug, Default)]
+pub struct LastWord {
+    window: isize,
+    keys: Vec<TypedKey>,
+    trailing_spaces: usize,
+    is_too_long: bool,
+}
+
+impl LastWord {
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.trailing_spaces = 0;
+        self.is_too_long = false;
+    }
+
+    /// Tracks a key down in `window` with the modifiers held at the time
+    pub fn on_key_down(&mut self, window: isize, vk: VIRTUAL_KEY, modifiers: Modifiers) {
+        if window != self.window {
+            self.clear();
+            self.window = window;
+        }
+        if is_modifier_key(vk) {
+            return;
+        }
+        // Shortcuts may do anything to the text
+        if modifiers != Modifiers::NONE && modifiers != Modifiers::SHIFT {
+            self.clear();
+            return;
+        }
+
+        match vk {
+            VK_BACK if self.trailing_spaces > 0 => self.trailing_spaces -= 1,
+            VK_BACK => {
+                self.keys.pop();
+            }
+            VK_SPACE if self.keys.is_empty() || self.trailing_spaces == MAX_TRAILING_SPACES => {
+                self.clear()
+            }
+            VK_SPACE => self.trailing_spaces += 1,
+            vk if is_character_key(vk) => {
+                if self.trailing_spaces > 0 {
+                    self.clear();
+                }
+                if self.is_too_long {
+                    return;
+                }
+                if self.keys.len() == MAX_WORD_LEN {
+                    self.keys.clear();
+                    self.is_too_long = true;
+                    return;
+                }
+                self.keys.push(TypedKey {
+                    vk,
+                    is_shifted: modifiers == Modifiers::SHIFT,
+                });
+            }
+            _ => self.clear(),
+        }
+    }
+
+    /// Backspaces that erase the word in `window`, none if it was typed
+    /// elsewhere
+    pub fn erase(&self, window: isize) -> Vec<KeyEvent> {
+        if window != self.window {
+            return Vec::new();
+        }
+
+        (0..self.keys.len() + self.trailing_spaces)
+            .flat_map(|_| KeyEvent::press(VK_BACK))
+            .collect()
+    }
+
+    /// Key events that type the word in `window` again, none if it was typed
+    /// elsewhere
+    pub fn retype(&self, window: isize) -> Vec<KeyEvent> {
+        if window != self.window || self.keys.is_empty() {
+            return Vec::new();
+        }
+
+        let mut events = Vec::new();
+        for key in &self.keys {
+            if key.is_shifted {
+                events.push(KeyEvent {
+                    vk: VK_SHIFT,
+                    is_up: false,
+                });
+            }
+            events.extend(KeyEvent::press(key.vk));
+            if key.is_shifted {
+                events.push(KeyEvent {
+                    vk: VK_SHIFT,
+                    is_up: true,
+                });
+            }
+        }
+        for _ in 0..self.trailing_spaces {
+            events.extend(KeyEvent::press(VK_SPACE));
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use windows::Win32::UI::Input::KeyboardAndMouse::{
+        VK_DELETE, VK_ESCAPE, VK_LEFT, VK_NUMPAD1, VK_RETURN, VK_TAB,
+    };
+
+    const WINDOW: isize = 0x1234;
+    // Keys of "ghbdtn", "привет" typed in the US layout
+    const PRIVET: [VIRTUAL_KEY; 6] = [
+        VIRTUAL_KEY(b'G' as u16),
+        VIRTUAL_KEY(b'H' as u16),
+        VIRTUAL_KEY(b'B' as u16),
+        VIRTUAL_KEY(b'D' as u16),
+        VIRTUAL_KEY(b'T' as u16),
+        VIRTUAL_KEY(b'N' as u16),
+    ];
+
+    fn type_keys(word: &mut LastWord, keys: &[VIRTUAL_KEY]) {
+        for &vk in keys {
+            word.on_key_down(WINDOW, vk, Modifiers::NONE);
+        }
+    }
+
+    fn presses(events: &[KeyEvent]) -> Vec<VIRTUAL_KEY> {
+        events
+            .iter()
+            .filter(|event| !event.is_up)
+            .map(|event| event.vk)
+            .collect()
+    }
+
+    #[test]
+    fn retypes_word_keys() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+
+        assert_eq!(presses(&word.erase(WINDOW)), [VK_BACK; 6]);
+        assert_eq!(presses(&word.retype(WINDOW)), PRIVET);
+        // Every key is released right after it's pressed
+        for pair in word.retype(WINDOW).chunks(2) {
+            assert_eq!(pair[0].vk, pair[1].vk);
+            assert!(!pair[0].is_up && pair[1].is_up);
+        }
+    }
+
+    #[test]
+    fn keeps_word_to_convert_again() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+
+        let first = word.retype(WINDOW);
+        assert_eq!(word.retype(WINDOW), first);
+    }
+
+    #[test]
+    fn retypes_shifted_keys_with_shift() {
+        let mut word = LastWord::default();
+        word.on_key_down(WINDOW, VK_LSHIFT, Modifiers::SHIFT);
+        word.on_key_down(WINDOW, PRIVET[0], Modifiers::SHIFT);
+        word.on_key_down(WINDOW, PRIVET[1], Modifiers::NONE);
+
+        assert_eq!(
+            word.retype(WINDOW),
+            [
+                KeyEvent {
+                    vk: VK_SHIFT,
+                    is_up: false
+                },
+                KeyEvent {
+                    vk: PRIVET[0],
+                    is_up: false
+                },
+                KeyEvent {
+                    vk: PRIVET[0],
+                    is_up: true
+                },
+                KeyEvent {
+                    vk: VK_SHIFT,
+                    is_up: true
+                },
+                KeyEvent {
+                    vk: PRIVET[1],
+                    is_up: false
+                },
+                KeyEvent {
+                    vk: PRIVET[1],
+                    is_up: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_punctuation_keys() {
+        let mut word = LastWord::default();
+        // `;` and `[` are letters in many layouts
+        type_keys(&mut word, &[VK_OEM_1, VK_OEM_4, VK_OEM_102, VK_0]);
+
+        assert_eq!(
+            presses(&word.retype(WINDOW)),
+            [VK_OEM_1, VK_OEM_4, VK_OEM_102, VK_0]
+        );
+    }
+
+    #[test]
+    fn backspace_removes_last_key() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        type_keys(&mut word, &[VK_BACK, VK_BACK]);
+
+        assert_eq!(presses(&word.retype(WINDOW)), PRIVET[..4]);
+        assert_eq!(word.erase(WINDOW).len(), 4 * 2);
+
+        type_keys(&mut word, &[VK_BACK; 10]);
+        assert!(word.retype(WINDOW).is_empty());
+        assert!(word.erase(WINDOW).is_empty());
+    }
+
+    #[test]
+    fn retypes_trailing_spaces() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        type_keys(&mut word, &[VK_SPACE, VK_SPACE]);
+
+        assert_eq!(presses(&word.erase(WINDOW)), [VK_BACK; 8]);
+        let retyped = presses(&word.retype(WINDOW));
+        assert_eq!(retyped[..6], PRIVET);
+        assert_eq!(retyped[6..], [VK_SPACE, VK_SPACE]);
+
+        // Backspace takes the spaces back first
+        type_keys(&mut word, &[VK_BACK]);
+        assert_eq!(presses(&word.retype(WINDOW))[6..], [VK_SPACE]);
+    }
+
+    #[test]
+    fn typing_after_space_starts_new_word() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        type_keys(&mut word, &[VK_SPACE, PRIVET[0], PRIVET[1]]);
+
+        assert_eq!(presses(&word.retype(WINDOW)), PRIVET[..2]);
+    }
+
+    #[test]
+    fn too_many_spaces_end_word() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        type_keys(&mut word, &[VK_SPACE; MAX_TRAILING_SPACES + 1]);
+
+        assert!(word.retype(WINDOW).is_empty());
+    }
+
+    #[test]
+    fn other_keys_end_word() {
+        for vk in [VK_RETURN, VK_TAB, VK_LEFT, VK_DELETE, VK_ESCAPE, VK_NUMPAD1] {
+            let mut word = LastWord::default();
+            type_keys(&mut word, &PRIVET);
+            type_keys(&mut word, &[vk]);
+
+            assert!(word.retype(WINDOW).is_empty(), "{:?}", vk);
+        }
+    }
+
+    #[test]
+    fn modifier_keys_keep_word() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        word.on_key_down(WINDOW, VK_LCONTROL, Modifiers::CTRL);
+        word.on_key_down(WINDOW, VK_CAPITAL, Modifiers::NONE);
+
+        assert_eq!(presses(&word.retype(WINDOW)), PRIVET);
+    }
+
+    #[test]
+    fn shortcuts_end_word() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+        word.on_key_down(WINDOW, VIRTUAL_KEY(b'Z' as u16), Modifiers::CTRL);
+
+        assert!(word.retype(WINDOW).is_empty());
+    }
+
+    #[test]
+    fn word_belongs_to_its_window() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &PRIVET);
+
+        assert!(word.erase(WINDOW + 1).is_empty());
+        assert!(word.retype(WINDOW + 1).is_empty());
+
+        word.on_key_down(WINDOW + 1, PRIVET[0], Modifiers::NONE);
+        assert!(word.retype(WINDOW).is_empty());
+        assert_eq!(presses(&word.retype(WINDOW + 1)), [PRIVET[0]]);
+    }
+
+    #[test]
+    fn too_long_word_is_dropped() {
+        let mut word = LastWord::default();
+        type_keys(&mut word, &[PRIVET[0]; MAX_WORD_LEN]);
+        assert_eq!(presses(&word.retype(WINDOW)).len(), MAX_WORD_LEN);
+
+        type_keys(&mut word, &PRIVET);
+        assert!(word.retype(WINDOW).is_empty());
+
+        // The next word is tracked again
+        type_keys(&mut word, &[VK_RETURN]);
+        type_keys(&mut word, &PRIVET);
+        assert_eq!(presses(&word.retype(WINDOW)), PRIVET);
+    }
+}
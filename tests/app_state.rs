@@ -0,0 +1,136 @@
+//! Changes the shared state from several threads at once, the way the hook,
+//! the tray and the workers do
+
+use capswitch::AppState;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use windows::Win32::UI::TextServices::HKL;
+
+const THREADS: usize = 8;
+const ITERATIONS: usize = 10_000;
+
+const INSTALLED: [HKL; 4] = [
+    HKL(0x0409_0409),
+    HKL(0x0419_0419),
+    HKL(0x0407_0407),
+    // Sign-extended like the HKLs of layout variants
+    HKL(0xF002_0409_u32 as i32 as isize),
+];
+
+fn app_state() -> Arc<AppState> {
+    Arc::new(AppState::new(vec![String::from("CapsWitch")]))
+}
+
+/// Runs `f` on `THREADS` threads started at the same time, with the index of
+/// the thread
+fn run_threads<F>(state: &Arc<AppState>, f: F)
+where
+    F: Fn(&AppState, usize) + Send + Sync + 'static,
+{
+    let f = Arc::new(f);
+    let barrier = Arc::new(Barrier::new(THREADS));
+
+    let threads: Vec<_> = (0..THREADS)
+        .map(|i| {
+            let state = Arc::clone(state);
+            let f = Arc::clone(&f);
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                f(&state, i);
+            })
+        })
+        .collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn toggle_pause_loses_no_toggles() {
+    let state = app_state();
+    let was_paused = state.is_paused();
+
+    run_threads(&state, |state, _| {
+        for _ in 0..ITERATIONS {
+            state.toggle_pause();
+        }
+    });
+    assert_eq!(state.is_paused(), was_paused);
+
+    // An odd number of toggles in total
+    run_threads(&state, |state, i| {
+        let toggles = if i == 0 { ITERATIONS + 1 } else { ITERATIONS };
+        for _ in 0..toggles {
+            state.toggle_pause();
+        }
+    });
+    assert_eq!(state.is_paused(), !was_paused);
+}
+
+#[test]
+fn next_recent_layout_stays_installed() {
+    let state = app_state();
+
+    run_threads(&state, |state, i| {
+        for n in 0..ITERATIONS / 10 {
+            let current = INSTALLED[(i + n) % INSTALLED.len()];
+            let next = state.next_recent_layout(current, &INSTALLED);
+            assert!(next.is_some_and(|next| INSTALLED.contains(&next)));
+        }
+    });
+}
+
+// Excluded layouts are saved to the registry on Windows, so the tests only
+// change them where settings aren't persisted
+#[cfg(not(windows))]
+#[test]
+fn toggle_excluded_layout_loses_no_toggles() {
+    let state = app_state();
+
+    run_threads(&state, |state, i| {
+        let layout = HKL(0x0400_0000 + i as isize);
+        // Every layout ends up excluded
+        for _ in 0..ITERATIONS / 10 * 2 + 1 {
+            state.toggle_excluded_layout(layout);
+            assert!(state.excluded_layouts().len() <= THREADS);
+        }
+    });
+
+    let mut excluded = state.excluded_layouts().to_vec();
+    excluded.sort_by_key(|layout| layout.0);
+    let expected: Vec<HKL> = (0..THREADS)
+        .map(|i| HKL(0x0400_0000 + i as isize))
+        .collect();
+    assert_eq!(excluded, expected);
+}
+
+#[cfg(not(windows))]
+#[test]
+fn excluded_layouts_stay_consistent() {
+    let state = app_state();
+
+    run_threads(&state, |state, i| {
+        let layout = HKL(0x0400_0000 + i as isize);
+        for n in 0..ITERATIONS / 10 {
+            if i % 2 == 0 {
+                // Only this thread toggles its layout, so no change is lost
+                let is_excluded = state.toggle_excluded_layout(layout);
+                assert_eq!(is_excluded, n % 2 == 0);
+                assert_eq!(state.excluded_layouts().contains(&layout), is_excluded);
+            } else {
+                let excluded = state.excluded_layouts();
+                let mut unique = excluded.to_vec();
+                unique.sort_by_key(|layout| layout.0);
+                unique.dedup();
+                assert_eq!(unique.len(), excluded.len());
+                assert!(excluded.len() <= THREADS / 2);
+                assert!(!excluded.contains(&layout));
+            }
+        }
+    });
+
+    // Every layout was toggled an even number of times
+    assert!(state.excluded_layouts().is_empty());
+}